@@ -1,33 +1,50 @@
+use crate::config::ATMConfig;
 use common::{
-    io::{errors::ReceiveError, StreamManager, AUTH_SUCCESS, BANK_SERVER_ADDR},
-    message::{constants::*, MessageType, Plaintext, Response},
+    client::{errors::ClientError, BankClient, BankNotice, HeartbeatSchedule},
+    io::errors::{ReceiveError, SendError},
+    message::{Amount, EndReason, TransactionStatus},
 };
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::time::{Duration, Instant};
 
 type Username = String;
 
+#[allow(clippy::upper_case_acronyms)]
 enum ATMState {
+    OFFLINE,
     BASE,
     LOGGED(Username),
 }
 
 /// Maintains ATM state and facilitates communications with the bank
+#[allow(clippy::upper_case_acronyms)]
 pub struct ATM {
+    config: ATMConfig,
     state: ATMState,
-    manager: StreamManager,
-    /// Tracks number of communications. Incremented after SEND
-    comm_count: u8,
+    /// Connection to the bank. Only `None` while offline
+    client: Option<BankClient>,
+    /// Delay to wait after the next failed reconnection attempt
+    reconnect_delay: Duration,
+    /// Earliest time the next reconnection attempt may be made
+    next_reconnect: Instant,
+    /// When to send heartbeats, if they are enabled
+    heartbeat: Option<HeartbeatSchedule>,
 }
 
 impl ATM {
-    /// Create new ATM instance
-    pub fn new() -> Self {
-        Self {
-            state: ATMState::BASE,
-            manager: StreamManager::from_addr(BANK_SERVER_ADDR),
-            comm_count: 0,
-        }
+    /// Create new ATM instance. The ATM starts offline if the bank cannot be reached
+    pub fn new(config: ATMConfig) -> Self {
+        let mut atm = Self {
+            reconnect_delay: config.initial_reconnect_delay,
+            heartbeat: config.heartbeat.map(HeartbeatSchedule::new),
+            config,
+            state: ATMState::OFFLINE,
+            client: None,
+            next_reconnect: Instant::now(),
+        };
+        atm.try_connect();
+        atm
     }
 
     //
@@ -36,6 +53,7 @@ impl ATM {
     /// Returns CLI prompt based on current ATM state
     pub fn get_prompt(&self) -> String {
         match &self.state {
+            ATMState::OFFLINE => "ATM [offline]: ".to_string(),
             ATMState::BASE => "ATM: ".to_string(),
             ATMState::LOGGED(user) => format!("ATM ({}): ", user),
         }
@@ -43,11 +61,13 @@ impl ATM {
     /// Returns CLI help list
     pub fn get_help_display(&self) -> String {
         match self.state {
-            ATMState::BASE => {
+            ATMState::OFFLINE | ATMState::BASE => {
                 "  begin-session <user-name> <PIN>\n".to_string() + "  help\n" + "  exit"
             }
             ATMState::LOGGED(_) => {
                 "  withdraw <amount>\n".to_string()
+                    + "  deposit <amount>\n"
+                    + "  transfer <user-name> <amount>\n"
                     + "  balance\n"
                     + "  end-session\n"
                     + "  help\n"
@@ -58,7 +78,19 @@ impl ATM {
     /// Processes user input based on content.
     /// Limits accessibility of certain commands based on state.
     pub fn process_input(&mut self, input: &str) {
+        // the bank may have come back, or ended the connection while the user was typing
+        if self.maintain_connection() {
+            return;
+        }
+
         match self.state {
+            ATMState::OFFLINE => {
+                if input == "help" {
+                    println!("{}", self.get_help_display());
+                } else {
+                    println!("Bank is offline. Retrying connection shortly.");
+                }
+            }
             ATMState::BASE => {
                 if input.starts_with("begin-session") {
                     if input.trim() == "begin-session" {
@@ -74,7 +106,11 @@ impl ATM {
             }
             ATMState::LOGGED(_) => {
                 if input.starts_with("withdraw") {
-                    // atm.process_withdraw(&user_input, &mut stream);
+                    self.withdraw(input);
+                } else if input.starts_with("deposit") {
+                    self.deposit(input);
+                } else if input.starts_with("transfer") {
+                    self.transfer(input);
                 } else if input == "balance" {
                     self.balance();
                 } else if input == "end-session" {
@@ -87,6 +123,41 @@ impl ATM {
             }
         }
     }
+    /// Reconnects to the bank if the ATM is offline, otherwise handles any
+    /// message the bank sent while the ATM sat idle.
+    /// Returns true if the current session was interrupted
+    pub fn maintain_connection(&mut self) -> bool {
+        match self.client {
+            None => {
+                self.try_connect();
+                false
+            }
+            Some(_) => self.check_for_bank_notice(),
+        }
+    }
+    /// Returns when the ATM next has something to do without user input, if ever
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        self.heartbeat.as_ref().map(HeartbeatSchedule::next)
+    }
+    /// Sends a heartbeat to the bank if one is due, which also checks the
+    /// connection still works. While offline, tries to reconnect instead
+    pub fn tick(&mut self) {
+        let Some(schedule) = self.heartbeat.as_mut() else {
+            return;
+        };
+        if !schedule.is_due() {
+            return;
+        }
+        schedule.reschedule();
+        match self.client.as_mut() {
+            None => self.try_connect(),
+            Some(client) => {
+                if let Err(e) = client.heartbeat() {
+                    self.handle_client_error(e);
+                }
+            }
+        }
+    }
 
     //
     // helpers for managing atm logic
@@ -94,25 +165,137 @@ impl ATM {
     /// Returns bool indicating whether there is an active user
     fn is_active_user(&self) -> bool {
         match self.state {
-            ATMState::BASE => false,
+            ATMState::OFFLINE | ATMState::BASE => false,
             ATMState::LOGGED(_) => true,
         }
     }
-    /// Exits or resets the ATM session depending on error type
+    /// Returns the connection to the bank. Only called from states which
+    /// require a connection
+    fn client(&mut self) -> &mut BankClient {
+        self.client
+            .as_mut()
+            .expect("ERROR: unreachable state achieved. Bank connection used while offline.")
+    }
+    /// Connects to the bank unless a recent attempt failed. Each failure
+    /// doubles the wait before the next attempt, up to a limit
+    fn try_connect(&mut self) {
+        if Instant::now() < self.next_reconnect {
+            return;
+        }
+        let connected = BankClient::connect(
+            &self.config.bank,
+            self.config.timeouts,
+            &self.config.credentials,
+        );
+        match connected {
+            Err(ClientError::Busy) => {
+                // back off rather than immediately adding to the load
+                let retry_after = self.reconnect_delay;
+                self.back_off();
+                self.go_offline("Bank is too busy to serve this ATM.", retry_after);
+            }
+            Err(e @ ClientError::Handshake(_)) => {
+                // the bank was reached, but refused this ATM or its key
+                println!("\nError: {e}");
+                self.next_reconnect = Instant::now() + self.reconnect_delay;
+                self.back_off();
+            }
+            Err(_) => {
+                self.next_reconnect = Instant::now() + self.reconnect_delay;
+                self.back_off();
+            }
+            Ok(client) => {
+                self.client = Some(client);
+                self.state = ATMState::BASE;
+                self.reconnect_delay = self.config.initial_reconnect_delay;
+                println!("\nConnected to the bank.");
+            }
+        }
+    }
+    /// Doubles the delay before the next reconnection attempt, up to the configured limit
+    fn back_off(&mut self) {
+        self.reconnect_delay = (self.reconnect_delay * 2).min(self.config.max_reconnect_delay);
+    }
+    /// Drops the connection to the bank, logging out any active user.
+    /// `retry_after` delays the first reconnection attempt
+    fn go_offline(&mut self, reason: &str, retry_after: Duration) {
+        println!("\n{reason} ATM is offline until the bank can be reached again.\n");
+        if self.is_active_user() {
+            println!("Your session has ended. Please begin a new session once reconnected.\n");
+        }
+        self.client = None;
+        self.state = ATMState::OFFLINE;
+        self.next_reconnect = Instant::now() + retry_after;
+    }
+    /// Goes offline, exits or resets the ATM session depending on error type
     fn handle_receive_error(&mut self, e: ReceiveError) {
         match e {
             ReceiveError::EndOfStream | ReceiveError::StaleStream => {
-                println!("\nConnection to bank has become stale. Shutting down ATM.\n");
-                std::process::exit(1);
+                self.go_offline("Connection to bank was lost.", Duration::ZERO);
             }
-            ReceiveError::InvalidCount => {
+            ReceiveError::InvalidCount | ReceiveError::Forged => {
                 println!("\nConnection to bank may have been tampered with. No personal data has been exposed. Shutting down ATM.\n");
                 std::process::exit(1);
             }
-            ReceiveError::InvalidMessage => {
-                // TODO more elegant solution to this issue perhaps?
-                println!("\nMessage received was invalid. Ending ATM session.\n");
-                self.state = ATMState::BASE;
+            ReceiveError::InvalidMessage(_) => self.discard_invalid_message(),
+            ReceiveError::TimedOut | ReceiveError::Incomplete => {
+                // a late reply would throw off the message count, so the connection is unusable
+                self.go_offline("Bank did not respond in time.", Duration::ZERO);
+            }
+        }
+    }
+    /// Ends the ATM session after the bank sent a message which could not be used
+    fn discard_invalid_message(&mut self) {
+        // TODO more elegant solution to this issue perhaps?
+        println!("\nMessage received was invalid. Ending ATM session.\n");
+        self.state = ATMState::BASE;
+    }
+    /// Goes offline depending on error type
+    fn handle_send_error(&mut self, e: SendError) {
+        match e {
+            SendError::Closed => self.handle_receive_error(ReceiveError::EndOfStream),
+            SendError::TimedOut => self.handle_receive_error(ReceiveError::TimedOut),
+        }
+    }
+    /// Reacts to a message the bank sent without being asked, such as the
+    /// End message it sends to every ATM when shutting down
+    fn handle_notice(&mut self, notice: BankNotice) {
+        match notice {
+            BankNotice::Ended(EndReason::Idle) => {
+                self.go_offline(
+                    "Session ended after a period of inactivity.",
+                    Duration::ZERO,
+                );
+            }
+            BankNotice::Ended(_) => {
+                self.go_offline("Bank has closed for maintenance.", Duration::ZERO);
+            }
+        }
+    }
+    /// Reports a failed request to the user, going offline or resetting the
+    /// ATM session if the connection can no longer be used
+    fn handle_client_error(&mut self, e: ClientError) {
+        match e {
+            ClientError::Send(e) => self.handle_send_error(e),
+            ClientError::Receive(e) => self.handle_receive_error(e),
+            ClientError::UnexpectedReply(_) => self.discard_invalid_message(),
+            ClientError::SessionEnded(reason) => self.handle_notice(BankNotice::Ended(reason)),
+            ClientError::Declined(status) => println!("{}\n", declined_message(status)),
+            e => println!("Error: {e}\n"),
+        }
+    }
+    /// Handles any message the bank sent while the ATM sat idle.
+    /// Returns true if the message interrupted the current session
+    fn check_for_bank_notice(&mut self) -> bool {
+        match self.client().poll_notice() {
+            Err(e) => {
+                self.handle_client_error(e);
+                true
+            }
+            Ok(None) => false,
+            Ok(Some(notice)) => {
+                self.handle_notice(notice);
+                true
             }
         }
     }
@@ -135,42 +318,13 @@ impl ATM {
             return;
         }
 
-        //
-        // extract fields from input
-
-        // extract username and PIN
+        // extract username and PIN. PIN length is fixed
         let caps = BS_RE.captures(user_input).unwrap();
         let username = caps.get(1).unwrap().as_str();
-        let pin = caps.get(2).unwrap().as_str();
-        // PIN length is fixed
-        // validate username length
-        if username.len() > MAX_USERNAME_SIZE {
-            println!(
-                "Error: username must be {} characters or less\n",
-                MAX_USERNAME_SIZE
-            );
-            return;
-        }
-
-        //
-        // construct and send authentication request
+        let pin: u16 = caps.get(2).unwrap().as_str().parse().unwrap();
 
-        let mut plaintext = Plaintext::new(&mut self.comm_count, MessageType::AuthUser);
-        plaintext.set_user_pin(username, pin);
-        self.manager.send_plaintext(plaintext);
-
-        //
-        // receive and validate response
-
-        let response = match self.manager.receive(&mut self.comm_count) {
-            Err(e) => {
-                self.handle_receive_error(e);
-                return;
-            }
-            Ok(response) => response,
-        };
-        match response.get_auth_result() {
-            Err(_) => self.handle_receive_error(ReceiveError::InvalidMessage),
+        match self.client().authenticate(username, pin) {
+            Err(e) => self.handle_client_error(e),
             Ok(false) => {
                 println!("Authorization failed");
             }
@@ -186,111 +340,112 @@ impl ATM {
     /// Handles user request to retreive balance information from bank.
     /// This method can only be reached if a user is logged in.
     fn balance(&mut self) {
-        let mut plaintext = Plaintext::new(&mut self.comm_count, MessageType::Balance);
-        match &self.state {
-            ATMState::BASE => panic!(
-                "ERROR: unreachable state achieved. Balance method called with no user logged in."
-            ),
-            ATMState::LOGGED(username) => plaintext.set_user(username),
+        match self.client().balance() {
+            Err(e) => self.handle_client_error(e),
+            Ok(balance) => println!("$ {balance}"),
         }
-        self.manager.send_plaintext(plaintext);
+    }
 
-        let response = match self.manager.receive(&mut self.comm_count) {
-            Err(e) => {
-                self.handle_receive_error(e);
+    /// Handles user request to take cash out of their account.
+    /// This method can only be reached if a user is logged in.
+    fn withdraw(&mut self, user_input: &str) {
+        lazy_static! {
+            static ref W_RE: Regex = Regex::new(r"^withdraw ([0-9]+\.?[0-9]{0,2})$")
+                .expect("Error while compiling withdraw regular expression");
+        }
+
+        let amount = match parse_amount(&W_RE, user_input, 1) {
+            None => {
+                println!("Usage: withdraw <amount>\n");
                 return;
             }
-            Ok(response) => response,
+            Some(amount) => amount,
         };
-        match response.get_balance() {
-            Err(_) => self.handle_receive_error(ReceiveError::InvalidMessage),
-            Ok(balance) => println!("$ {:.2}", balance),
+        match self.client().withdraw(amount) {
+            Err(e) => self.handle_client_error(e),
+            Ok(balance) => {
+                println!("${amount} dispensed");
+                println!("Remaining balance: ${balance}\n");
+            }
         }
     }
 
-    /// Sends end session request to bank, receives confirmation response and updates ATM state
-    fn end_session(&mut self) {
-        let plaintext = Plaintext::new(&mut self.comm_count, MessageType::End);
-        self.manager.send_plaintext(plaintext);
+    /// Handles user request to put cash into their account.
+    /// This method can only be reached if a user is logged in.
+    fn deposit(&mut self, user_input: &str) {
+        lazy_static! {
+            static ref D_RE: Regex = Regex::new(r"^deposit ([0-9]+\.?[0-9]{0,2})$")
+                .expect("Error while compiling deposit regular expression");
+        }
 
-        match self.manager.receive(&mut self.comm_count) {
-            Err(e) => {
-                self.handle_receive_error(e);
+        let amount = match parse_amount(&D_RE, user_input, 1) {
+            None => {
+                println!("Usage: deposit <amount>\n");
                 return;
             }
-            Ok(_) => {
-                self.state = ATMState::BASE;
-                println!("Session ended");
-                println!("Available commands:\n{}", self.get_help_display());
+            Some(amount) => amount,
+        };
+        match self.client().deposit(amount) {
+            Err(e) => self.handle_client_error(e),
+            Ok(balance) => {
+                println!("${amount} was successfully deposited");
+                println!("New balance: ${balance}\n");
             }
         }
     }
 
-    //     pub fn process_withdraw(&mut self, user_input: &String, stream: &mut TcpStream) {
-    //         /* verify there is a user logged in */
-    //         if !self.is_active_user() {
-    //             println!("No user logged in\n");
-    //             return;
-    //         }
-
-    //         /* create static regular expression (creates it at most once) */
-    //         lazy_static! {
-    //             static ref RE: Regex = Regex::new("^withdraw ([0-9]+)$")
-    //                 .expect("Error while compiling withdraw regular expression");
-    //         }
-
-    //         /* ensure input matches */
-    //         if !RE.is_match(user_input) {
-    //             println!("Usage: withdraw <amt>\n");
-    //             return;
-    //         }
-
-    //         /* extract withdrawal amount from input */
-    //         let caps: Captures = RE.captures(user_input).unwrap();
-    //         let amount_string: &str = caps.get(1).unwrap().as_str();
-
-    //         /* check if valid u64 */
-    //         let amount: u64;
-    //         if let Ok(extracted_value) = amount_string.parse::<u64>() {
-    //             amount = extracted_value;
-    //         } else {
-    //             println!("Error: your requested withdrawal amount is too large for our wee little bank to handle\n");
-    //             return;
-    //         }
-
-    //         /* attempt withdrawal from bank */
-    //  /* TEMP */
-    //         if amount > 1_000_000u64 {
-    //             println!("Insufficient funds\n");
-    //             return;
-    //         }
-    //         println!("${} dispensed\n", amount);
-    //     }
+    /// Handles user request to move money into another user's account.
+    /// This method can only be reached if a user is logged in.
+    fn transfer(&mut self, user_input: &str) {
+        lazy_static! {
+            static ref T_RE: Regex = Regex::new(r"^transfer ([a-zA-Z0-9]+) ([0-9]+\.?[0-9]{0,2})$")
+                .expect("Error while compiling transfer regular expression");
+        }
 
-    //     pub fn process_balance(&mut self, stream: &mut TcpStream) {
-    //         /* verify there is a user logged in */
-    //         if !self.is_active_user() {
-    //             println!("No user logged in\n");
-    //             return;
-    //         }
+        let amount = match parse_amount(&T_RE, user_input, 2) {
+            None => {
+                println!("Usage: transfer <user-name> <amount>\n");
+                return;
+            }
+            Some(amount) => amount,
+        };
+        let recipient = T_RE.captures(user_input).unwrap().get(1).unwrap().as_str();
+        match self.client().transfer(recipient, amount) {
+            Err(e) => self.handle_client_error(e),
+            Ok(balance) => {
+                println!("${amount} was successfully transferred to {recipient}");
+                println!("Remaining balance: ${balance}\n");
+            }
+        }
+    }
 
-    //         /* no regular expression needed */
-    //         /* retreive balance from the bank */
-    //     }
+    /// Sends end session request to bank, receives confirmation response and updates ATM state
+    fn end_session(&mut self) {
+        match self.client().end_session() {
+            Err(e) => self.handle_client_error(e),
+            Ok(()) => {
+                self.state = ATMState::BASE;
+                println!("Session ended");
+                println!("Available commands:\n{}", self.get_help_display());
+            }
+        }
+    }
+}
 
-    //     pub fn process_end_session(&mut self, stream: &mut TcpStream) {
-    //         /* check if there's a user logged in */
-    //         if !self.is_active_user() {
-    //             println!("No user logged in\n");
-    //             return;
-    //         }
+/// Extracts the amount captured by the given group of a command's regular
+/// expression. Returns `None` if the input does not match or the amount is too large
+fn parse_amount(re: &Regex, user_input: &str, group: usize) -> Option<Amount> {
+    re.captures(user_input)?.get(group)?.as_str().parse().ok()
+}
 
-    //         println!(
-    //             "{} was logged out\n",
-    //             self.active_user
-    //                 .as_ref()
-    //                 .expect("Error: active user should never be none here")
-    //         );
-    //         self.logout_user();
-    //     }
+/// Explains to the user why the bank refused a transaction
+fn declined_message(status: TransactionStatus) -> &'static str {
+    match status {
+        TransactionStatus::Approved => "Transaction approved",
+        TransactionStatus::InsufficientFunds => "Insufficient funds",
+        TransactionStatus::UnknownRecipient => "Error: recipient account not recognized",
+        TransactionStatus::InvalidAmount => "Error: invalid amount",
+        TransactionStatus::SameAccount => "Error: cannot transfer to your own account",
+        TransactionStatus::Overflow => "Error: the receiving account cannot hold this much money",
+    }
 }
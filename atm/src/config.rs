@@ -0,0 +1,180 @@
+use clap::Parser;
+use common::{
+    config::{errors::ConfigError, load_toml, nonzero_secs, resolve_endpoint},
+    crypto::{valid_atm_id, AtmCredentials, BankKey, Psk},
+    io::{Timeouts, BANK_SERVER_ADDR, DEFAULT_REQUEST_TIMEOUT},
+    transport::Endpoint,
+};
+use serde::Deserialize;
+use std::{path::PathBuf, time::Duration};
+
+/// Default delay before the first reconnection attempt after the bank becomes unreachable
+const DEFAULT_INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Default longest delay between reconnection attempts
+const DEFAULT_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Command line flags. Flags override settings from the config file
+#[derive(Debug, Parser)]
+#[command(name = "atm", about = "ATM client which connects to the bank")]
+struct Args {
+    /// Path to a TOML config file
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Address of the bank to connect to: `host:port` or `unix:<socket-path>`
+    #[arg(long)]
+    bank: Option<String>,
+    /// Seconds to wait for the bank to answer a request
+    #[arg(long)]
+    request_timeout: Option<u64>,
+    /// Longest wait in seconds between attempts to reach an offline bank
+    #[arg(long)]
+    max_reconnect_delay: Option<u64>,
+    /// Average seconds between heartbeats sent to the bank as cover traffic.
+    /// No heartbeats are sent unless set
+    #[arg(long)]
+    heartbeat: Option<u64>,
+    /// Id this ATM is registered with at the bank
+    #[arg(long)]
+    atm_id: Option<String>,
+    /// File holding the pre-shared key this ATM was provisioned with, as 64
+    /// hex digits. Needs `--atm-id`
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+    /// Bank's public Noise key as 64 hex digits, as printed by the bank at
+    /// startup. When set, only the Noise handshake is used
+    #[arg(long)]
+    bank_key: Option<String>,
+}
+
+/// Layout of the config file. Every setting is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bank: Option<String>,
+    #[serde(default)]
+    timeouts: FileTimeouts,
+    #[serde(default)]
+    channel: FileChannel,
+}
+
+/// `[channel]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileChannel {
+    id: Option<String>,
+    psk_file: Option<PathBuf>,
+    bank_key: Option<String>,
+}
+
+/// `[timeouts]` section of the config file, in seconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTimeouts {
+    request: Option<u64>,
+    max_reconnect_delay: Option<u64>,
+    heartbeat: Option<u64>,
+}
+
+/// Validated ATM settings
+#[derive(Debug, Clone)]
+pub struct ATMConfig {
+    /// Address of the bank to connect to
+    pub bank: Endpoint,
+    /// Read and write deadlines for each request to the bank
+    pub timeouts: Timeouts,
+    /// Delay before the first reconnection attempt after the bank becomes unreachable
+    pub initial_reconnect_delay: Duration,
+    /// Longest delay between reconnection attempts
+    pub max_reconnect_delay: Duration,
+    /// Average delay between heartbeats, if any are sent
+    pub heartbeat: Option<Duration>,
+    /// What this ATM presents to the bank when connecting
+    pub credentials: AtmCredentials,
+}
+
+impl ATMConfig {
+    /// Builds the ATM's settings from command line flags and the config file
+    /// they point to, falling back to defaults for anything left unset
+    pub fn from_args() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+        let file: FileConfig = match &args.config {
+            None => FileConfig::default(),
+            Some(path) => load_toml(path)?,
+        };
+
+        let bank = args
+            .bank
+            .or(file.bank)
+            .unwrap_or(BANK_SERVER_ADDR.to_string());
+        let request = match args.request_timeout.or(file.timeouts.request) {
+            None => DEFAULT_REQUEST_TIMEOUT,
+            Some(secs) => nonzero_secs("request_timeout", secs)?,
+        };
+        let max_reconnect_delay = match args
+            .max_reconnect_delay
+            .or(file.timeouts.max_reconnect_delay)
+        {
+            None => DEFAULT_MAX_RECONNECT_DELAY,
+            Some(secs) => nonzero_secs("max_reconnect_delay", secs)?,
+        };
+        let heartbeat = args
+            .heartbeat
+            .or(file.timeouts.heartbeat)
+            .map(|secs| nonzero_secs("heartbeat", secs))
+            .transpose()?;
+        let credentials = credentials(
+            args.atm_id.or(file.channel.id),
+            args.psk_file.or(file.channel.psk_file),
+            args.bank_key.or(file.channel.bank_key),
+        )?;
+
+        Ok(Self {
+            bank: resolve_endpoint("bank", &bank)?,
+            timeouts: Timeouts {
+                read: Some(request),
+                write: Some(request),
+            },
+            initial_reconnect_delay: DEFAULT_INITIAL_RECONNECT_DELAY.min(max_reconnect_delay),
+            max_reconnect_delay,
+            heartbeat,
+            credentials,
+        })
+    }
+}
+
+/// Checks the ATM's id and loads its key. A key is only of use to the bank
+/// alongside the id it was registered with
+fn credentials(
+    id: Option<String>,
+    psk_file: Option<PathBuf>,
+    bank_key: Option<String>,
+) -> Result<AtmCredentials, ConfigError> {
+    let invalid = |setting: &str, reason: String| ConfigError::Invalid {
+        setting: setting.to_string(),
+        reason,
+    };
+    let id = id.unwrap_or_default();
+    if !valid_atm_id(&id) {
+        return Err(invalid(
+            "atm_id",
+            "must be up to 20 letters, digits, `-` or `_`".to_string(),
+        ));
+    }
+    let psk = match psk_file {
+        None => None,
+        Some(_) if id.is_empty() => {
+            return Err(invalid("psk_file", "needs an `atm_id`".to_string()))
+        }
+        Some(path) => Some(
+            Psk::load(&path)
+                .map_err(|e| invalid("psk_file", format!("cannot load {}: {e}", path.display())))?,
+        ),
+    };
+    let bank_key = bank_key
+        .map(|hex| {
+            BankKey::from_hex(&hex)
+                .ok_or_else(|| invalid("bank_key", "must be 64 hex digits".to_string()))
+        })
+        .transpose()?;
+    Ok(AtmCredentials { id, psk, bank_key })
+}
@@ -1,34 +1,77 @@
 mod atm;
-use crate::atm::ATM;
-use std::io::{self, Write};
+mod config;
+use crate::{atm::ATM, config::ATMConfig};
+use std::{
+    io::{self, Write},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::Instant,
+};
 
 /// ATM entrypoint
 fn main() {
-    let mut atm = ATM::new();
+    let config = ATMConfig::from_args().unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        std::process::exit(2);
+    });
+    let mut atm = ATM::new(config);
 
     // print initial prompt and flush buffer to terminal
     println!("\nAvailable commands:\n{}", atm.get_help_display());
     print!("\n{}", atm.get_prompt());
     io::stdout().flush().unwrap();
 
-    // user input buffer
-    let mut user_input = String::new();
+    let input = read_input();
+    let mut prompt = atm.get_prompt();
 
-    // iteratively read user input
-    while io::stdin().read_line(&mut user_input).unwrap() > 0 {
-        // remove newline from end of user input
-        user_input.pop();
+    // iteratively read user input, waking up whenever the ATM has something to do
+    loop {
+        let received = match atm.next_wakeup() {
+            None => input.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(wakeup) => input.recv_timeout(wakeup.saturating_duration_since(Instant::now())),
+        };
+        let user_input = match received {
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                atm.tick();
+                // reprompt only if the ATM's status changed under the user
+                if atm.get_prompt() != prompt {
+                    prompt = atm.get_prompt();
+                    print!("\n{prompt}");
+                    io::stdout().flush().unwrap();
+                }
+                continue;
+            }
+            Ok(user_input) => user_input,
+        };
 
         if user_input == "exit" {
             break;
         }
 
-        atm.process_input(&user_input.trim());
+        atm.process_input(user_input.trim());
+
+        // reconnect or pick up bank notices so the prompt shows the current status
+        atm.maintain_connection();
 
         // reprompt user
-        print!("\n{}", atm.get_prompt());
+        prompt = atm.get_prompt();
+        print!("\n{prompt}");
         io::stdout().flush().unwrap();
-        // clear user input buffer before next read
-        user_input.clear();
     }
 }
+
+/// Reads lines of user input on a separate thread, so the ATM can keep in
+/// touch with the bank while waiting for the user
+fn read_input() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let line = line.expect("Error reading user input");
+            if sender.send(line).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
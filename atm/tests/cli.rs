@@ -0,0 +1,74 @@
+use bank::test_support::{dollars, TestBank};
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+/// Runs the ATM against a bank, typing the given lines at its prompt
+fn run_atm(test_bank: &TestBank, input: &str) -> String {
+    let endpoint = test_bank
+        .endpoint()
+        .expect("Error: test bank has no address");
+    let mut atm = Command::new(env!("CARGO_BIN_EXE_atm"))
+        .arg("--bank")
+        .arg(endpoint.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Error starting ATM");
+    atm.stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .expect("Error writing to ATM");
+    let Output { status, stdout, .. } = atm.wait_with_output().expect("Error running ATM");
+    assert!(status.success());
+    String::from_utf8(stdout).expect("Error: ATM output is not UTF-8")
+}
+
+#[test]
+fn runs_a_session_from_the_prompt() {
+    let test_bank = TestBank::start();
+    test_bank
+        .bank()
+        .create_account("amy", 1234, dollars(100.0))
+        .unwrap();
+    test_bank
+        .bank()
+        .create_account("bob", 42, dollars(5.0))
+        .unwrap();
+
+    let output = run_atm(
+        &test_bank,
+        "begin-session amy 1234\n\
+         balance\n\
+         withdraw 30\n\
+         deposit 5.50\n\
+         transfer bob 10\n\
+         end-session\n\
+         exit\n",
+    );
+
+    assert!(output.contains("Authorization successful"));
+    assert!(output.contains("$ 100.00"));
+    assert!(output.contains("$30.00 dispensed"));
+    assert!(output.contains("New balance: $75.50"));
+    assert!(output.contains("$10.00 was successfully transferred to bob"));
+    assert!(output.contains("Remaining balance: $65.50"));
+    assert!(output.contains("Session ended"));
+    assert_eq!(test_bank.bank().balance("bob").unwrap(), dollars(15.0));
+}
+
+#[test]
+fn rejects_wrong_pin_from_the_prompt() {
+    let test_bank = TestBank::start();
+    test_bank
+        .bank()
+        .create_account("amy", 1234, dollars(100.0))
+        .unwrap();
+
+    let output = run_atm(&test_bank, "begin-session amy 4321\nbalance\nexit\n");
+
+    assert!(output.contains("Authorization failed"));
+    assert!(!output.contains("$ 100.00"));
+}
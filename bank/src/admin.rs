@@ -0,0 +1,268 @@
+use crate::{
+    audit::{Actor, AuditLog},
+    cli::{self, Console, Next},
+    operators::{Operator, Operators},
+    pool::{ConnectionLimits, ConnectionTracker, WorkerPool},
+    server::{reject_connection, SHUTDOWN_GRACE_PERIOD, SHUTDOWN_POLL_INTERVAL},
+};
+use common::{
+    admin::{errors::AdminError, AdminChannel, AdminReply, AdminRequest},
+    crypto::{AtmKeys, BankSecret},
+    io::{Timeouts, DEFAULT_IDLE_TIMEOUT, DEFAULT_REQUEST_TIMEOUT},
+    transport::Listener,
+};
+use std::{
+    fmt,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Default maximum number of admin consoles served at once
+pub const DEFAULT_MAX_CONSOLES: usize = 4;
+/// Failed logins a console may make before the bank hangs up on it
+pub const MAX_LOGIN_ATTEMPTS: u32 = 3;
+
+/// Settings for serving admin consoles
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    pub limits: ConnectionLimits,
+    /// How long a console may go without a request before the bank ends its session
+    pub idle: Duration,
+    /// How long the bank waits for a reply to be written before dropping the console
+    pub write: Duration,
+    /// Keys of the consoles allowed to connect
+    pub console_keys: Arc<AtmKeys>,
+    /// Bank's static Noise key. The Noise handshake is only offered if set
+    pub noise_key: Option<BankSecret>,
+    /// Log every connection and login is recorded in, alongside the commands
+    /// the console records
+    pub audit: Arc<AuditLog>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            limits: ConnectionLimits {
+                max_connections: DEFAULT_MAX_CONSOLES,
+                max_per_peer: DEFAULT_MAX_CONSOLES,
+            },
+            idle: DEFAULT_IDLE_TIMEOUT,
+            write: DEFAULT_REQUEST_TIMEOUT,
+            console_keys: Arc::default(),
+            noise_key: None,
+            audit: Arc::default(),
+        }
+    }
+}
+
+/// Names a console in the audit log by its id and address
+pub fn console(id: &str, peer: &impl fmt::Display) -> Actor {
+    Actor::Console {
+        id: id.to_string(),
+        peer: peer.to_string(),
+    }
+}
+
+/// Serves admin consoles with a pool of blocking worker threads, one console
+/// per worker, until a shutdown is requested. A supervisor running `exit`
+/// from a console requests one.
+///
+/// Only consoles registered in the console key file are served, and an
+/// operator must then log in with their password exactly as at the bank's
+/// own prompt
+pub fn serve(
+    listener: Listener,
+    console: Arc<Console>,
+    operators: Arc<Operators>,
+    config: &AdminConfig,
+    shutdown: Arc<AtomicBool>,
+) {
+    // poll for connections so shutdown requests are noticed
+    listener
+        .set_nonblocking(true)
+        .expect("Error: could not set admin listener to non-blocking");
+
+    let tracker = ConnectionTracker::new(config.limits);
+    let mut pool = WorkerPool::new(config.limits.max_connections);
+    let handshake_timeouts = Timeouts {
+        read: Some(config.write),
+        write: Some(config.write),
+    };
+    let idle = config.idle;
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let reaped = pool.reap();
+        if reaped > 0 {
+            eprintln!("Warning: replaced {reaped} worker(s) lost to a panicking admin connection");
+        }
+
+        match listener.accept() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(SHUTDOWN_POLL_INTERVAL),
+            Err(e) => eprintln!("Error getting stream from admin listener: {}", e),
+            Ok((stream, peer)) => {
+                let permit = match tracker.try_acquire(peer.host()) {
+                    Err(e) => {
+                        eprintln!("Rejected admin connection from {peer}: {e}");
+                        let outcome = format!("rejected: {e}");
+                        config
+                            .audit
+                            .record(&self::console("", &peer), "connect", "", &outcome);
+                        reject_connection(stream);
+                        continue;
+                    }
+                    Ok(permit) => permit,
+                };
+                let console_clone = console.clone();
+                let operators = operators.clone();
+                let shutdown_clone = shutdown.clone();
+                let console_keys = config.console_keys.clone();
+                let noise_key = config.noise_key.clone();
+                let audit = config.audit.clone();
+                pool.execute(move || {
+                    // the connection counts against the limits until handled
+                    let _permit = permit;
+                    let accepted = AdminChannel::accept(
+                        stream,
+                        handshake_timeouts,
+                        &console_keys,
+                        noise_key.as_ref(),
+                    );
+                    let channel = match accepted {
+                        Err(e) => {
+                            eprintln!("Refused admin connection from {peer}: {e}");
+                            let outcome = format!("refused: {e}");
+                            audit.record(&self::console("", &peer), "connect", "", &outcome);
+                            return;
+                        }
+                        Ok(channel) => channel,
+                    };
+                    let actor = self::console(channel.console_id(), &peer);
+                    audit.record(&actor, "connect", "", "ok");
+                    // wake up periodically to check for shutdown and idle sessions
+                    if let Err(e) = channel.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL)) {
+                        eprintln!("Error setting up admin connection from {peer}: {e}");
+                        return;
+                    }
+                    handle_admin_connection(
+                        &console_clone,
+                        &operators,
+                        &audit,
+                        &actor,
+                        channel,
+                        &shutdown_clone,
+                        idle,
+                    );
+                });
+            }
+        }
+    }
+
+    // stop accepting new connections, and let consoles hear the bank is going
+    drop(listener);
+    let abandoned = pool.shutdown(SHUTDOWN_GRACE_PERIOD);
+    if abandoned > 0 {
+        eprintln!("Warning: {abandoned} admin connection(s) did not close in time");
+    }
+}
+
+/// Handles a remote console's requests on the calling thread until it
+/// disconnects, its operator logs out, its session sits idle for too long,
+/// or the bank shuts down. `serve` runs this for each connection once its
+/// handshake is complete
+pub fn handle_admin_connection(
+    console: &Console,
+    operators: &Operators,
+    audit: &AuditLog,
+    actor: &Actor,
+    mut channel: AdminChannel,
+    shutdown: &AtomicBool,
+    idle: Duration,
+) {
+    // operator logged in on this connection, if any
+    let mut operator: Option<Operator> = None;
+    let mut failed_logins = 0;
+    // tracks when the console last made a request
+    let mut last_request = Instant::now();
+
+    loop {
+        // tell the console why it is being dropped before closing
+        if shutdown.load(Ordering::SeqCst) {
+            let _ = channel.send_reply(&AdminReply::Ended("Bank is shutting down".to_string()));
+            return;
+        }
+        if last_request.elapsed() >= idle {
+            if let Some(operator) = &operator {
+                audit.record(
+                    &Actor::Operator(operator.name.clone()),
+                    "logout",
+                    "",
+                    "idle",
+                );
+            }
+            let _ = channel.send_reply(&AdminReply::Ended("Session was idle".to_string()));
+            return;
+        }
+
+        let request = match channel.receive_request() {
+            Err(AdminError::TimedOut) => continue,
+            Err(_) => return,
+            Ok(request) => request,
+        };
+        last_request = Instant::now();
+
+        let reply = match (request, &operator) {
+            (AdminRequest::Login { name, password }, None) => {
+                let authenticated = operators.authenticate(&name, &password);
+                let outcome = if authenticated.is_some() {
+                    "ok"
+                } else {
+                    "failed"
+                };
+                audit.record(actor, "login", &name, outcome);
+                match authenticated {
+                    Some(authenticated) => {
+                        let greeting = cli::get_greeting(&authenticated);
+                        operator = Some(authenticated);
+                        AdminReply::LoggedIn(greeting)
+                    }
+                    None => {
+                        failed_logins += 1;
+                        if failed_logins >= MAX_LOGIN_ATTEMPTS {
+                            AdminReply::Ended("Login failed".to_string())
+                        } else {
+                            AdminReply::Refused("Login failed".to_string())
+                        }
+                    }
+                }
+            }
+            (AdminRequest::Login { .. }, Some(_)) => {
+                AdminReply::Refused("An operator is already logged in".to_string())
+            }
+            (AdminRequest::Command(_), None) => {
+                AdminReply::Refused("Log in before running commands".to_string())
+            }
+            (AdminRequest::Command(input), Some(operator)) => {
+                let mut out = String::new();
+                match console.process_input(operator, input.trim(), &mut out) {
+                    Next::Prompt => AdminReply::Output(out),
+                    Next::Logout => AdminReply::Ended(out + "Logged out"),
+                    Next::Shutdown => {
+                        shutdown.store(true, Ordering::SeqCst);
+                        AdminReply::Ended(out + "Bank is shutting down")
+                    }
+                }
+            }
+        };
+
+        // a console which stops reading replies is treated as gone
+        let ended = matches!(reply, AdminReply::Ended(_));
+        if channel.send_reply(&reply).is_err() || ended {
+            return;
+        }
+    }
+}
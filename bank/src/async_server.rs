@@ -0,0 +1,182 @@
+use crate::{
+    audit::{Actor, AuditLog},
+    bank::Bank,
+    pool::ConnectionTracker,
+    server::{atm, ServerConfig, SessionTimeouts, SHUTDOWN_GRACE_PERIOD, SHUTDOWN_POLL_INTERVAL},
+    session::{Connection, Step},
+};
+use common::{
+    async_io::{AsyncListener, AsyncStreamManager},
+    crypto::busy_hello,
+    io::Timeouts,
+    transport::Listener,
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::{io::AsyncWriteExt, runtime, task::JoinSet, time};
+
+/// Serves ATM connections as tasks on a tokio runtime, so thousands of
+/// connections can share a small pool of threads. Uses the same protocol and
+/// request handling as the threaded server
+pub fn serve(
+    listener: Listener,
+    bank: Arc<Bank>,
+    config: &ServerConfig,
+    worker_threads: Option<usize>,
+    shutdown: Arc<AtomicBool>,
+) {
+    let mut builder = runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    let runtime = builder
+        .enable_io()
+        .enable_time()
+        .build()
+        .expect("Error: could not start async runtime");
+
+    // tasks record to the audit log without waiting on the disk
+    let _background = config.audit.sync_in_background();
+    runtime.block_on(accept_connections(listener, bank, config, shutdown));
+}
+
+/// Accepts ATM connections and spawns a task for each until a shutdown is requested
+async fn accept_connections(
+    listener: Listener,
+    bank: Arc<Bank>,
+    config: &ServerConfig,
+    shutdown: Arc<AtomicBool>,
+) {
+    let listener =
+        AsyncListener::from_std(listener).expect("Error: could not register listener with runtime");
+
+    let tracker = ConnectionTracker::new(config.limits);
+    let session_timeouts = config.timeouts;
+    // ATMs are given as long to complete the handshake as to read a reply
+    let handshake_timeouts = Timeouts {
+        read: Some(config.timeouts.write),
+        write: Some(config.timeouts.write),
+    };
+    let mut sessions = JoinSet::new();
+
+    while !shutdown.load(Ordering::SeqCst) {
+        // reap finished sessions
+        while sessions.try_join_next().is_some() {}
+
+        // wake up periodically to check for shutdown
+        let (mut stream, peer) =
+            match time::timeout(SHUTDOWN_POLL_INTERVAL, listener.accept()).await {
+                Err(_) => continue,
+                Ok(Err(e)) => {
+                    eprintln!("Error getting stream from listener: {}", e);
+                    continue;
+                }
+                Ok(Ok(accepted)) => accepted,
+            };
+        let permit = match tracker.try_acquire(peer.host()) {
+            Err(e) => {
+                eprintln!("Rejected connection from {peer}: {e}");
+                config
+                    .audit
+                    .record(&atm("", &peer), "connect", "", &format!("rejected: {e}"));
+                // the ATM may already have hung up, in which case there is no one to tell
+                let _ = stream.write_all(&busy_hello()).await;
+                continue;
+            }
+            Ok(permit) => permit,
+        };
+
+        let bank_clone = bank.clone();
+        let shutdown_clone = shutdown.clone();
+        let atm_keys = config.atm_keys.clone();
+        let noise_key = config.noise_key.clone();
+        let audit = config.audit.clone();
+        sessions.spawn(async move {
+            // the connection counts against the limits until handled
+            let _permit = permit;
+            let accepted = AsyncStreamManager::accept(
+                stream,
+                handshake_timeouts,
+                &atm_keys,
+                noise_key.as_ref(),
+            );
+            let mut manager = match accepted.await {
+                Err(e) => {
+                    eprintln!("Refused connection from {peer}: {e}");
+                    audit.record(&atm("", &peer), "connect", "", &format!("refused: {e}"));
+                    return;
+                }
+                Ok(manager) => manager,
+            };
+            let atm = atm(manager.atm_id(), &peer);
+            audit.record(&atm, "connect", "", "ok");
+            // wake up periodically to check for shutdown and idle sessions
+            manager.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL));
+            handle_remote_connection(
+                bank_clone,
+                audit,
+                atm,
+                manager,
+                shutdown_clone,
+                session_timeouts,
+            )
+            .await
+        });
+    }
+
+    // stop accepting new connections
+    drop(listener);
+    println!("\nShutting down bank. Waiting for ATM sessions to close...");
+
+    // give sessions a chance to finish in-flight transactions
+    let drain = async { while sessions.join_next().await.is_some() {} };
+    if time::timeout(SHUTDOWN_GRACE_PERIOD, drain).await.is_err() {
+        eprintln!(
+            "Warning: {} ATM connection(s) did not close in time",
+            sessions.len()
+        );
+        sessions.abort_all();
+    }
+}
+
+/// Handles a remote ATM's requests until the ATM disconnects, its session
+/// sits idle for too long, or the bank shuts down
+async fn handle_remote_connection(
+    bank: Arc<Bank>,
+    audit: Arc<AuditLog>,
+    atm: Actor,
+    mut manager: AsyncStreamManager,
+    shutdown: Arc<AtomicBool>,
+    timeouts: SessionTimeouts,
+) {
+    let mut connection = Connection::new(atm, timeouts);
+    loop {
+        if let Some(notice) = connection.end_notice(&shutdown) {
+            let _ = manager
+                .send_message(notice, &mut connection.comm_count)
+                .await;
+            return;
+        }
+        let received = manager.receive(&mut connection.comm_count).await;
+        let request = match connection.receive(received) {
+            Step::Wait => continue,
+            Step::Close => return,
+            Step::Handle(request) => request,
+        };
+
+        let Some(reply) = connection.handle(&bank, &audit, &request) else {
+            return;
+        };
+        time::sleep_until(connection.reply_due(&request).into()).await;
+        // an ATM which stops reading replies is treated as gone
+        if manager
+            .send_message(reply, &mut connection.comm_count)
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
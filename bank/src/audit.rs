@@ -0,0 +1,536 @@
+use self::errors::AuditError;
+use blake2::{
+    digest::{KeyInit, Mac},
+    Blake2sMac256,
+};
+use common::hex::{from_hex, to_hex};
+use rand_core::{OsRng, RngCore};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    iter,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Size of an entry's hash
+const HASH_SIZE: usize = 32;
+/// Hash the first entry follows from
+const GENESIS_HASH: [u8; HASH_SIZE] = [0; HASH_SIZE];
+/// Number of fields in an entry
+const ENTRY_FIELDS: usize = 8;
+
+/// Who performed an audited action
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Actor {
+    /// The bank itself, for starting up and shutting down
+    Bank,
+    /// An operator at the bank's command line, by name
+    Operator(String),
+    /// An ATM, by the id it presented and where it connected from. The id is
+    /// empty for an anonymous ATM
+    Atm { id: String, peer: String },
+    /// A remote admin console, by the id it presented and where it connected
+    /// from. The id is empty until the console's handshake is accepted
+    Console { id: String, peer: String },
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bank => write!(f, "bank"),
+            Self::Operator(name) => write!(f, "operator:{name}"),
+            Self::Atm { id, peer } if id.is_empty() => write!(f, "atm@{peer}"),
+            Self::Atm { id, peer } => write!(f, "atm:{id}@{peer}"),
+            Self::Console { id, peer } if id.is_empty() => write!(f, "console@{peer}"),
+            Self::Console { id, peer } => write!(f, "console:{id}@{peer}"),
+        }
+    }
+}
+
+/// Secret key the audit log's chain of hashes is keyed with
+#[derive(Clone)]
+pub struct AuditKey([u8; HASH_SIZE]);
+
+impl AuditKey {
+    /// Reads the key file at the given path, holding a key written as 64 hex
+    /// digits. A missing file is created holding a new random key, readable
+    /// only by its owner
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+            Ok(contents) => {
+                return parse_hash(contents.trim()).map(Self).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "key must be 64 hex digits")
+                })
+            }
+        }
+        let mut key = [0u8; HASH_SIZE];
+        OsRng.fill_bytes(&mut key);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        writeln!(file, "{}", to_hex(&key))?;
+        file.sync_all()?;
+        Ok(Self(key))
+    }
+}
+
+impl From<[u8; HASH_SIZE]> for AuditKey {
+    fn from(key: [u8; HASH_SIZE]) -> Self {
+        Self(key)
+    }
+}
+
+// keys are kept out of logs and error messages
+impl fmt::Debug for AuditKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AuditKey(..)")
+    }
+}
+
+/// Summary of a log which passed verification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditHead {
+    /// Number of entries in the log
+    pub entries: u64,
+    /// Hash of the newest entry
+    pub hash: [u8; HASH_SIZE],
+}
+
+impl AuditHead {
+    const EMPTY: Self = Self {
+        entries: 0,
+        hash: GENESIS_HASH,
+    };
+}
+
+impl fmt::Display for AuditHead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} entries, head {}", self.entries, to_hex(&self.hash))
+    }
+}
+
+/// The bank's audit log: an append-only trail of everything done to the bank,
+/// by its operators, consoles or ATMs. Entries are written through to disk as
+/// they are recorded. A disabled log records nothing.
+///
+/// Each entry is one line of tab separated fields:
+/// `<seq> <time> <actor> <action> <target> <outcome> <prev-hash> <hash>`.
+/// An entry's hash covers every field before it, including the hash of the
+/// entry before it, so changing any entry breaks the chain from there on.
+/// The number and hash of the newest entry are also kept in a head file
+/// beside the log, so the log cannot be cut short unnoticed either.
+///
+/// Hashes are keyed BLAKE2s with a key only the bank holds. Someone who can
+/// write the log and head file but not read the key cannot compute the hashes
+/// for entries they change, add or drop, so cannot hide doing so. Anyone
+/// holding the key can rewrite the log unnoticed, so the key file needs
+/// tighter permissions than the log
+#[derive(Default)]
+pub struct AuditLog {
+    writer: Option<Mutex<Writer>>,
+    /// Flushes appended entries to disk. Kept apart from the writer, so
+    /// entries can be appended while earlier ones are being flushed, and one
+    /// flush covers every entry appended while it waited
+    syncer: Option<Arc<Mutex<Syncer>>>,
+    /// Hands appended entries to the thread flushing them, while one runs
+    background: Mutex<Option<Sender<AuditHead>>>,
+}
+
+/// Open log file and the newest entry written to it
+struct Writer {
+    path: PathBuf,
+    key: AuditKey,
+    file: File,
+    /// Length of the log up to the end of the newest entry
+    len: u64,
+    head: AuditHead,
+}
+
+/// Handle on the open log file for flushing it, and the newest entry flushed.
+/// The head file names that entry
+struct Syncer {
+    path: PathBuf,
+    file: File,
+    synced: u64,
+}
+
+impl AuditLog {
+    /// A log which records nothing, for banks embedded without one
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Opens the log at the given path, creating it if needed, to be chained
+    /// with the given key. The existing entries are verified first, so a log
+    /// which has been tampered with is never appended to
+    pub fn open(path: &Path, key: AuditKey) -> Result<Self, AuditError> {
+        let head = verify(path, &key)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            syncer: Some(Arc::new(Mutex::new(Syncer {
+                path: path.to_path_buf(),
+                file: file.try_clone()?,
+                synced: head.entries,
+            }))),
+            writer: Some(Mutex::new(Writer {
+                path: path.to_path_buf(),
+                key,
+                file,
+                len,
+                head,
+            })),
+            background: Mutex::new(None),
+        })
+    }
+
+    /// Returns where the log is kept, or `None` if it is disabled
+    pub fn path(&self) -> Option<PathBuf> {
+        self.writer
+            .as_ref()
+            .map(|writer| writer.lock().unwrap().path.clone())
+    }
+
+    /// Appends an entry for an action, and returns once it is on disk, or
+    /// once it is handed to the thread flushing entries while
+    /// `sync_in_background` has one running. A failure to write is reported
+    /// but does not stop the action, which has already happened
+    pub fn record(&self, actor: &Actor, action: &str, target: &str, outcome: &str) {
+        let (Some(writer), Some(syncer)) = (&self.writer, &self.syncer) else {
+            return;
+        };
+        // only the append holds the writer, so requests do not queue behind
+        // each other's flushes
+        let head = {
+            let mut writer = writer.lock().unwrap();
+            if let Err(e) = writer.append(actor, action, target, outcome) {
+                eprintln!("Error writing audit log: {e}");
+                return;
+            }
+            writer.head
+        };
+        if let Some(background) = &*self.background.lock().unwrap() {
+            // the thread outlives the sender, so the send cannot fail
+            let _ = background.send(head);
+            return;
+        }
+
+        let seq = head.entries;
+        let mut syncer = syncer.lock().unwrap();
+        // a flush which ran while this one waited may already have covered it
+        if syncer.synced >= seq {
+            return;
+        }
+        let head = writer.lock().unwrap().head;
+        if let Err(e) = syncer.sync(&head) {
+            eprintln!("Error writing audit log: {e}");
+        }
+    }
+
+    /// Flushes entries on a thread of its own until the returned guard is
+    /// dropped, so `record` never waits on the disk. Meant for callers which
+    /// must not block, such as tasks on an async runtime. Entries handed to
+    /// the thread are all flushed by the time the guard is dropped
+    pub fn sync_in_background(&self) -> BackgroundSync<'_> {
+        let Some(syncer) = self.syncer.clone() else {
+            return BackgroundSync {
+                audit: self,
+                thread: None,
+            };
+        };
+        let (sender, heads) = mpsc::channel::<AuditHead>();
+        let thread = thread::spawn(move || {
+            while let Ok(head) = heads.recv() {
+                // one flush covers every entry handed over while the last ran
+                let head = iter::once(head)
+                    .chain(heads.try_iter())
+                    .max_by_key(|head| head.entries)
+                    .unwrap();
+                let mut syncer = syncer.lock().unwrap();
+                if syncer.synced >= head.entries {
+                    continue;
+                }
+                if let Err(e) = syncer.sync(&head) {
+                    eprintln!("Error writing audit log: {e}");
+                }
+            }
+        });
+        *self.background.lock().unwrap() = Some(sender);
+        BackgroundSync {
+            audit: self,
+            thread: Some(thread),
+        }
+    }
+
+    /// Checks the whole log on disk is intact and ends with the newest entry
+    /// this bank wrote
+    pub fn verify(&self) -> Result<AuditHead, AuditError> {
+        let Some(writer) = &self.writer else {
+            return Err(AuditError::Disabled);
+        };
+        let writer = writer.lock().unwrap();
+        let head = verify(&writer.path, &writer.key)?;
+        let (expected, found) = (writer.head.entries, head.entries);
+        if found < expected {
+            return Err(AuditError::Truncated { expected, found });
+        }
+        if found > expected {
+            return Err(AuditError::Extended { expected, found });
+        }
+        if head.hash != writer.head.hash {
+            return Err(AuditError::Broken(found));
+        }
+        Ok(head)
+    }
+}
+
+/// Keeps a log flushing its entries on a background thread. Dropping it waits
+/// for the thread to flush what it was handed, and `record` flushes entries
+/// itself again
+pub struct BackgroundSync<'a> {
+    audit: &'a AuditLog,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for BackgroundSync<'_> {
+    fn drop(&mut self) {
+        // the thread stops once it has flushed everything sent before this
+        self.audit.background.lock().unwrap().take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("path", &self.path())
+            .finish()
+    }
+}
+
+impl Writer {
+    /// Writes an entry following the newest one. The entry is not on disk
+    /// until it has been synced. A failed write is cut off the log, so the
+    /// next entry never starts partway through a line
+    fn append(
+        &mut self,
+        actor: &Actor,
+        action: &str,
+        target: &str,
+        outcome: &str,
+    ) -> io::Result<()> {
+        let seq = self.head.entries + 1;
+        let target = if target.is_empty() { "-" } else { target };
+        let fields = [
+            seq.to_string(),
+            format_time(SystemTime::now()),
+            actor.to_string(),
+            action.to_string(),
+            target.to_string(),
+            outcome.to_string(),
+            to_hex(&self.head.hash),
+        ]
+        .map(|field| sanitize(&field))
+        .join("\t");
+        let hash = entry_hash(&self.key, &fields);
+        let line = format!("{fields}\t{}\n", to_hex(&hash));
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            // entries before this one are whole, and may still be being synced
+            self.file.set_len(self.len)?;
+            return Err(e);
+        }
+        self.len += line.len() as u64;
+        self.head = AuditHead { entries: seq, hash };
+        Ok(())
+    }
+}
+
+impl Syncer {
+    /// Flushes every entry up to the given head to disk, then moves the head
+    /// file on to it
+    fn sync(&mut self, head: &AuditHead) -> io::Result<()> {
+        self.file.sync_data()?;
+        write_head(&self.path, head)?;
+        self.synced = head.entries;
+        Ok(())
+    }
+}
+
+/// Describes how an operation turned out, for an entry's outcome
+pub fn outcome<T, E: fmt::Display>(result: &Result<T, E>) -> String {
+    match result {
+        Ok(_) => "ok".to_string(),
+        Err(e) => format!("refused: {e}"),
+    }
+}
+
+/// Checks the log at the given path is intact: every entry follows from the
+/// one before it under the given key, and the log reaches the entry the head
+/// file names. A missing log is empty
+pub fn verify(path: &Path, key: &AuditKey) -> Result<AuditHead, AuditError> {
+    let contents = match fs::read_to_string(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+        Ok(contents) => contents,
+    };
+
+    let mut head = AuditHead::EMPTY;
+    // hashes of every entry, to check the head file against
+    let mut hashes = Vec::new();
+    for (line_num, line) in (1..).zip(contents.lines()) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != ENTRY_FIELDS {
+            return Err(AuditError::Malformed(line_num));
+        }
+        let (body, hash) = line.rsplit_once('\t').unwrap();
+        let follows = fields[0] == (head.entries + 1).to_string()
+            && parse_hash(fields[6]) == Some(head.hash)
+            && parse_hash(hash) == Some(entry_hash(key, body));
+        if !follows {
+            return Err(AuditError::Broken(line_num));
+        }
+        head = AuditHead {
+            entries: head.entries + 1,
+            hash: entry_hash(key, body),
+        };
+        hashes.push(head.hash);
+    }
+
+    // the head file is only missing if nothing has been written yet
+    let recorded = match fs::read_to_string(head_path(path)) {
+        Err(e) if e.kind() == ErrorKind::NotFound && head.entries == 0 => AuditHead::EMPTY,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(AuditError::BadHead),
+        Err(e) => return Err(e.into()),
+        Ok(recorded) => parse_head(&recorded).ok_or(AuditError::BadHead)?,
+    };
+    if recorded.entries > head.entries {
+        return Err(AuditError::Truncated {
+            expected: recorded.entries,
+            found: head.entries,
+        });
+    }
+    // a crash between writing an entry and the head file leaves the head behind
+    if recorded.entries > 0 && hashes[recorded.entries as usize - 1] != recorded.hash {
+        return Err(AuditError::Broken(recorded.entries));
+    }
+    Ok(head)
+}
+
+//
+// helpers
+
+/// Returns the path of the head file kept beside the given log
+fn head_path(path: &Path) -> PathBuf {
+    path.with_extension("head")
+}
+
+/// Writes the head file beside the given log. The file is replaced
+/// atomically, so a crash mid-write leaves the previous head rather than a
+/// torn one
+fn write_head(path: &Path, head: &AuditHead) -> io::Result<()> {
+    let head_path = head_path(path);
+    let tmp_path = head_path.with_extension("head.tmp");
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{} {}", head.entries, to_hex(&head.hash))?;
+    file.sync_all()?;
+    fs::rename(tmp_path, head_path)
+}
+
+/// Parses a head file: `<entries> <hash>`
+fn parse_head(contents: &str) -> Option<AuditHead> {
+    let mut fields = contents.split_whitespace();
+    let entries = fields.next()?.parse().ok()?;
+    let hash = parse_hash(fields.next()?)?;
+    Some(AuditHead { entries, hash })
+}
+
+/// Hashes an entry's fields with the log's key
+fn entry_hash(key: &AuditKey, body: &str) -> [u8; HASH_SIZE] {
+    let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(&key.0)
+        .expect("Error: audit key has an invalid length");
+    mac.update(body.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// Keeps a field to a single line without tabs, so it cannot forge others
+fn sanitize(field: &str) -> String {
+    field
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// Formats a time as an ISO 8601 UTC timestamp, e.g. `2024-01-31T09:05:00Z`
+fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+/// Reads an entry's hash written as hex digits
+fn parse_hash(hex: &str) -> Option<[u8; HASH_SIZE]> {
+    from_hex(hex)?.try_into().ok()
+}
+
+pub mod errors {
+    use std::io;
+    use thiserror::Error;
+
+    #[derive(Debug, Error)]
+    pub enum AuditError {
+        #[error("There is no audit log to verify.")]
+        Disabled,
+        #[error("Cannot read the audit log: {0}")]
+        Io(#[from] io::Error),
+        #[error("Audit entry on line {0} is malformed.")]
+        Malformed(u64),
+        #[error("Audit entry on line {0} has been changed, or does not follow from the entry before it.")]
+        Broken(u64),
+        #[error("Audit head file is missing or malformed.")]
+        BadHead,
+        #[error("Audit log ends after entry {found}, but entry {expected} was written.")]
+        Truncated { expected: u64, found: u64 },
+        #[error("Audit log holds {found} entries, but only {expected} were written.")]
+        Extended { expected: u64, found: u64 },
+    }
+}
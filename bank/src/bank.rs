@@ -1,228 +1,277 @@
-use common::message::constants::MAX_USERNAME_SIZE;
-use lazy_static::lazy_static;
-use regex::{Captures, Regex};
-use std::collections::HashMap;
-
-/// Internally represents a user's bank data
-#[derive(Debug)]
-struct User {
-    name: String,
-    pin: u16,
-    balance: f64,
+use self::errors::BankError;
+use crate::storage::DataFile;
+use common::message::{errors::FieldError, Amount, Pin, Username};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex, RwLock},
+};
+use subtle::{Choice, ConstantTimeEq};
+
+/// A user's bank data
+#[derive(Clone)]
+pub struct User {
+    pub name: String,
+    pub pin: u16,
+    pub balance: Amount,
+}
+
+/// Leaves out the PIN, so it cannot end up in logs or on a console
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("name", &self.name)
+            .field("balance", &self.balance)
+            .finish_non_exhaustive()
+    }
 }
 
 impl User {
-    fn new(name: String, pin: u16, balance: f64) -> Self {
+    fn new(name: String, pin: u16, balance: Amount) -> Self {
         Self { name, pin, balance }
     }
 }
 
+/// A user's account, locked independently of every other account
+type Account = Arc<Mutex<User>>;
+
 /// Defines a Bank instance which stores bank information about users.
 ///
-/// Stores users' names, pins, and balances
+/// Stores users' names, pins, and balances. The bank is safe to share between
+/// threads: the user map is only locked long enough to find or add an account,
+/// and each account has its own lock so requests for different users never wait
+/// on each other
 pub struct Bank {
-    users: HashMap<String, User>,
+    users: RwLock<HashMap<String, Account>>,
+}
+
+impl Default for Bank {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bank {
     /// Creates new bank instance
     pub fn new() -> Self {
         Self {
-            users: HashMap::new(),
+            users: RwLock::new(HashMap::new()),
         }
     }
 
     //
-    // helpers
+    // persistence
 
-    /// Returns CLI prompt for bank
-    pub fn get_prompt() -> String {
-        "BANK: ".to_string()
-    }
-    /// Returns CLI help list
-    pub fn get_help_display() -> String {
-        "  create-user <user-name> <pin> <balance>\n".to_string()
-            + "  deposit <user-name> <amt>\n"
-            + "  balance <user-name>\n"
-            + "  users\n"
-            + "  exit"
-    }
+    /// Loads a bank from the given data file. A missing file yields an empty bank
+    pub fn load(data: &DataFile) -> io::Result<Self> {
+        let bank = Self::new();
 
-    /// Adds new User account to bank hashmap
-    fn create_new_account(&mut self, username: &String, pin: u16, balance: f64) {
-        self.users
-            .insert(username.clone(), User::new(username.clone(), pin, balance));
-    }
-    /// Check if given username exists in bank database
-    pub fn is_existing_user(&self, username: &str) -> bool {
-        self.users.contains_key(username)
-    }
-    /// Attempts to authenticate the given user with the given pin
-    pub fn attempt_authentication(&self, username: &str, pin: u16) -> bool {
-        match self.users.get(username) {
-            Some(user) => user.pin == pin,
-            None => false,
-        }
-    }
-    /// Retrieves a user's balance
-    pub fn get_balance(&self, username: &str) -> Result<f64, ()> {
-        match self.users.get(username) {
-            None => Err(()),
-            Some(user) => Ok(user.balance),
+        // each record is a single user: `<user-name> <pin> <balance>`
+        for (line_num, line) in data.read()?.iter().enumerate() {
+            let invalid_record = |reason: String| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid user record on line {}: {reason}", line_num + 1),
+                )
+            };
+            let malformed = || invalid_record("malformed".to_string());
+            let mut fields = line.split_whitespace();
+            let username = fields.next().ok_or_else(malformed)?;
+            let pin: u16 = fields
+                .next()
+                .and_then(|pin| pin.parse().ok())
+                .ok_or_else(malformed)?;
+            let balance = fields
+                .next()
+                .and_then(|balance| balance.parse().ok())
+                .ok_or_else(malformed)?;
+            bank.create_account(username, pin, balance)
+                .map_err(|e| invalid_record(e.to_string()))?;
         }
+        Ok(bank)
     }
 
-    /// Prints the state of the bank information to stdin
-    fn display_users(&self) {
-        println!("Bank user information:");
-        for (k, v) in &self.users {
-            println!("{k} -> {:?}", v);
-        }
-        println!();
-    }
-
-    /// Processes user input based on content
-    pub fn process_input(&mut self, input: &str) {
-        if input.starts_with("create-user") {
-            self.process_create_user(input);
-        } else if input.starts_with("deposit") {
-            self.process_deposit(input);
-        } else if input.starts_with("balance") {
-            self.process_balance(input);
-        } else if input == "users" {
-            self.display_users();
-        } else if input == "help" {
-            println!("{}", Bank::get_help_display());
-        } else {
-            println!("Invalid command. Use `help` to see options.");
-        }
+    /// Writes every user record to the given data file, replacing what it held
+    pub fn save(&self, data: &DataFile) -> io::Result<()> {
+        let records: Vec<String> = self
+            .accounts()
+            .iter()
+            .map(|account| {
+                let user = account.lock().unwrap();
+                format!("{} {:04} {}", user.name, user.pin, user.balance)
+            })
+            .collect();
+        data.write(&records)
     }
 
-    /// Processes a request to create a new user. The given request must include
-    /// a username, pin for the user, and an initial balance.
-    fn process_create_user(&mut self, user_input: &str) {
-        lazy_static! {
-            static ref CU_RE: Regex =
-                Regex::new(r"^create-user ([a-zA-Z]+) ([0-9]{4}) ([0-9]+\.?[0-9]{0,2})$")
-                    .expect("Error while compiling create-user regular expression");
-        }
+    //
+    // helpers
 
-        // ensure input matches
-        if !CU_RE.is_match(user_input) {
-            println!("Usage: create-user <user-name> <4-digit-PIN> <balance>\n");
-            return;
-        }
+    /// Returns the account for the given username.
+    /// The user map is only locked while looking the account up
+    fn account(&self, username: &str) -> Result<Account, BankError> {
+        self.users
+            .read()
+            .unwrap()
+            .get(username)
+            .cloned()
+            .ok_or_else(|| BankError::UnknownUser(username.to_string()))
+    }
+    /// Returns every account. The user map is only locked while collecting them
+    fn accounts(&self) -> Vec<Account> {
+        self.users.read().unwrap().values().cloned().collect()
+    }
 
-        // validate username, pin, and balance from matched string
-        let caps: Captures = CU_RE.captures(user_input).unwrap();
+    //
+    // operations
 
-        let username: String = caps.get(1).unwrap().as_str().to_string();
-        if username.len() > MAX_USERNAME_SIZE {
-            println!(
-                "Error: username must be {} characters or less\n",
-                MAX_USERNAME_SIZE
-            );
-            return;
+    /// Opens a new account for the given user. Fails without changing anything
+    /// if the username is taken or any of the details are invalid
+    pub fn create_account(
+        &self,
+        username: &str,
+        pin: u16,
+        balance: Amount,
+    ) -> Result<(), BankError> {
+        Username::new(username).map_err(|e| match e {
+            FieldError::UsernameTooLong(max) => BankError::UsernameTooLong(max),
+            _ => BankError::InvalidUsername,
+        })?;
+        if pin > Pin::MAX {
+            return Err(BankError::InvalidPin);
         }
 
-        // pin is guaranteed to be 4 digits
-        let pin: u16 = caps.get(2).unwrap().as_str().parse::<u16>().unwrap();
-
-        // validate initial balance
-        let balance: f64 = match caps.get(3).unwrap().as_str().parse::<f64>() {
-            Ok(v) => v,
-            Err(_) => {
-                println!("Error: we don't have a big enough vault to store a balance this large\n");
-                return;
-            }
-        };
-
-        // ensure this isn't a duplicate username
-        if self.is_existing_user(&username) {
-            println!("Error: user {} already exists\n", username);
-            return;
+        let mut users = self.users.write().unwrap();
+        if users.contains_key(username) {
+            return Err(BankError::UserExists(username.to_string()));
         }
-
-        self.create_new_account(&username, pin, balance);
-        println!("Created account for {}\n", username);
+        users.insert(
+            username.to_string(),
+            Arc::new(Mutex::new(User::new(username.to_string(), pin, balance))),
+        );
+        Ok(())
     }
-
-    /// Processes a request to make a deposit into a user's account. The given
-    /// request must include a username and an amount to deposit.
-    fn process_deposit(&mut self, user_input: &str) {
-        lazy_static! {
-            static ref D_RE: Regex = Regex::new(r"^deposit ([a-zA-Z]+) ([0-9]+\.?[0-9]{0,2})$")
-                .expect("Error while compiling deposit regular expression");
-        }
-
-        // ensure input matches
-        if !D_RE.is_match(user_input) {
-            println!("Usage: deposit <user-name> <amount>\n");
-            return;
+    /// Attempts to authenticate the given user with the given pin. Unknown
+    /// users and wrong pins are turned away by the same steps, so how long
+    /// this takes does not reveal whether an account exists. Unknown users are
+    /// checked against an account of their own, so they never wait on each other
+    pub fn attempt_authentication(&self, username: &str, pin: u16) -> bool {
+        let account = self.account(username).ok();
+        let exists = Choice::from(u8::from(account.is_some()));
+        let account = account.unwrap_or_else(|| {
+            Arc::new(Mutex::new(User::new(String::new(), 0, Amount::default())))
+        });
+        let matches = account.lock().unwrap().pin.ct_eq(&pin);
+        (exists & matches).into()
+    }
+    /// Changes a user's pin
+    pub fn set_pin(&self, username: &str, pin: u16) -> Result<(), BankError> {
+        if pin > Pin::MAX {
+            return Err(BankError::InvalidPin);
         }
-
-        let caps: Captures = D_RE.captures(user_input).unwrap();
-
-        // validate username
-        let username: String = caps.get(1).unwrap().as_str().to_string();
-        if !self.is_existing_user(&username) {
-            println!("Error: account name not recognized\n");
-            return;
+        self.account(username)?.lock().unwrap().pin = pin;
+        Ok(())
+    }
+    /// Retrieves a user's balance
+    pub fn balance(&self, username: &str) -> Result<Amount, BankError> {
+        Ok(self.account(username)?.lock().unwrap().balance)
+    }
+    /// Adds the given amount to a user's account. Returns the new balance
+    pub fn deposit(&self, username: &str, amount: Amount) -> Result<Amount, BankError> {
+        let account = self.account(username)?;
+        let mut user = account.lock().unwrap();
+        user.balance = user
+            .balance
+            .checked_add(amount)
+            .ok_or(BankError::Overflow)?;
+        Ok(user.balance)
+    }
+    /// Takes the given amount out of a user's account. Returns the new balance
+    pub fn withdraw(&self, username: &str, amount: Amount) -> Result<Amount, BankError> {
+        let account = self.account(username)?;
+        let mut user = account.lock().unwrap();
+        user.balance = user
+            .balance
+            .checked_sub(amount)
+            .ok_or(BankError::InsufficientFunds)?;
+        Ok(user.balance)
+    }
+    /// Moves the given amount from one user's account to another's.
+    /// Both accounts are locked in username order, so transfers running in
+    /// opposite directions at the same time cannot deadlock
+    pub fn transfer(&self, from: &str, to: &str, amount: Amount) -> Result<(), BankError> {
+        if from == to {
+            return Err(BankError::SameAccount);
         }
+        let from_account = self.account(from)?;
+        let to_account = self.account(to)?;
 
-        // validate deposit amount
-        let amount: f64 = match caps.get(2).unwrap().as_str().parse::<f64>() {
-            Ok(v) => v,
-            Err(_) => {
-                println!(
-                    "Error: we don't have a big enough vault to store wealth of this magnitute\n"
-                );
-                return;
-            }
+        let (mut from_user, mut to_user) = if from < to {
+            let from_user = from_account.lock().unwrap();
+            (from_user, to_account.lock().unwrap())
+        } else {
+            let to_user = to_account.lock().unwrap();
+            (from_account.lock().unwrap(), to_user)
         };
 
-        // retreive user
-        let user_data = self.users.get_mut(&username).unwrap();
-
-        // check for deposit overflow
-        if f64::MAX - amount < user_data.balance {
-            println!("Error: we would drown in money trying to process this request, which is no good for anybody\n");
-            return;
-        }
-
-        user_data.balance += amount;
-
-        println!("${:.2} was successfully deposited into the account", amount);
-        println!("Balance for {} is: ${:.2}\n", username, user_data.balance);
+        let from_balance = from_user
+            .balance
+            .checked_sub(amount)
+            .ok_or(BankError::InsufficientFunds)?;
+        let to_balance = to_user
+            .balance
+            .checked_add(amount)
+            .ok_or(BankError::Overflow)?;
+        from_user.balance = from_balance;
+        to_user.balance = to_balance;
+        Ok(())
     }
+    /// Returns a snapshot of every user, ordered by username
+    pub fn users(&self) -> Vec<User> {
+        let mut users: Vec<User> = self
+            .accounts()
+            .iter()
+            .map(|account| account.lock().unwrap().clone())
+            .collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+}
 
-    /// Processes a request to view a user's balance
-    fn process_balance(&mut self, user_input: &str) {
-        lazy_static! {
-            static ref B_RE: Regex = Regex::new("^balance ([a-zA-Z]+)$")
-                .expect("Error while compiling balance regular expression");
-        }
-
-        // ensure input matches
-        if !B_RE.is_match(user_input) {
-            println!("Usage: balance <user-name>\n");
-            return;
-        }
-
-        let caps: Captures = B_RE.captures(user_input).unwrap();
-
-        // validate username
-        let username: String = caps.get(1).unwrap().as_str().to_string();
-        if !self.is_existing_user(&username) {
-            println!("Error: account name not recognized\n");
-            return;
-        }
+/// Error types related to bank operations
+pub mod errors {
+    use thiserror::Error;
 
-        // display user balance
-        println!(
-            "Balance for {} is: ${:.2}\n",
-            username,
-            self.users.get(&username).unwrap().balance
-        );
+    /// Reasons a bank operation is refused. Nothing is changed when an
+    /// operation fails
+    #[derive(Debug, Error, PartialEq)]
+    pub enum BankError {
+        /// No account exists with the given username
+        #[error("account name {0} not recognized")]
+        UnknownUser(String),
+        /// An account already exists with the given username
+        #[error("user {0} already exists")]
+        UserExists(String),
+        /// Usernames may only contain letters
+        #[error("username must only contain letters")]
+        InvalidUsername,
+        /// The username does not fit in a message
+        #[error("username must be {0} characters or less")]
+        UsernameTooLong(usize),
+        /// Pins are 4 digits
+        #[error("pin must be 4 digits")]
+        InvalidPin,
+        /// Both usernames name the same account
+        #[error("cannot transfer from an account to itself")]
+        SameAccount,
+        /// The account holds less than the amount
+        #[error("insufficient funds")]
+        InsufficientFunds,
+        /// The receiving account cannot hold the amount
+        #[error(
+            "we would drown in money trying to process this request, which is no good for anybody"
+        )]
+        Overflow,
     }
 }
@@ -0,0 +1,158 @@
+use clap::Parser;
+use common::{
+    admin::ADMIN_SERVER_ADDR,
+    config::{errors::ConfigError, load_toml, nonzero_secs, resolve_endpoint},
+    crypto::{valid_atm_id, AtmCredentials, BankKey, Psk},
+    io::{Timeouts, DEFAULT_REQUEST_TIMEOUT},
+    transport::Endpoint,
+};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Command line flags. Flags override settings from the config file
+#[derive(Debug, Parser)]
+#[command(
+    name = "bank-admin",
+    about = "Admin console which runs the bank's command line remotely"
+)]
+struct Args {
+    /// Path to a TOML config file
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Address the bank serves admin consoles on: `host:port` or `unix:<socket-path>`
+    #[arg(long)]
+    bank: Option<String>,
+    /// Name of the operator to log in as. Prompted for unless set
+    #[arg(long)]
+    operator: Option<String>,
+    /// Seconds to wait for the bank to answer a command
+    #[arg(long)]
+    request_timeout: Option<u64>,
+    /// Id this console is registered with at the bank
+    #[arg(long)]
+    console_id: Option<String>,
+    /// File holding the pre-shared key this console was provisioned with, as
+    /// 64 hex digits
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+    /// Bank's public Noise key as 64 hex digits, as printed by the bank at
+    /// startup. When set, only the Noise handshake is used
+    #[arg(long)]
+    bank_key: Option<String>,
+}
+
+/// Layout of the config file. Every setting is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bank: Option<String>,
+    operator: Option<String>,
+    #[serde(default)]
+    timeouts: FileTimeouts,
+    #[serde(default)]
+    channel: FileChannel,
+}
+
+/// `[channel]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileChannel {
+    id: Option<String>,
+    psk_file: Option<PathBuf>,
+    bank_key: Option<String>,
+}
+
+/// `[timeouts]` section of the config file, in seconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTimeouts {
+    request: Option<u64>,
+}
+
+/// Validated console settings
+#[derive(Debug, Clone)]
+pub struct AdminClientConfig {
+    /// Address of the bank's admin listener
+    pub bank: Endpoint,
+    /// Operator to log in as, if given up front
+    pub operator: Option<String>,
+    /// Read and write deadlines for each command sent to the bank
+    pub timeouts: Timeouts,
+    /// What this console presents to the bank when connecting
+    pub credentials: AtmCredentials,
+}
+
+impl AdminClientConfig {
+    /// Builds the console's settings from command line flags and the config
+    /// file they point to, falling back to defaults for anything left unset
+    pub fn from_args() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+        let file: FileConfig = match &args.config {
+            None => FileConfig::default(),
+            Some(path) => load_toml(path)?,
+        };
+
+        let bank = args
+            .bank
+            .or(file.bank)
+            .unwrap_or(ADMIN_SERVER_ADDR.to_string());
+        let request = match args.request_timeout.or(file.timeouts.request) {
+            None => DEFAULT_REQUEST_TIMEOUT,
+            Some(secs) => nonzero_secs("request_timeout", secs)?,
+        };
+        let credentials = credentials(
+            args.console_id.or(file.channel.id),
+            args.psk_file.or(file.channel.psk_file),
+            args.bank_key.or(file.channel.bank_key),
+        )?;
+
+        Ok(Self {
+            bank: resolve_endpoint("bank", &bank)?,
+            operator: args.operator.or(file.operator),
+            timeouts: Timeouts {
+                read: Some(request),
+                write: Some(request),
+            },
+            credentials,
+        })
+    }
+}
+
+/// Checks the console's id and loads its key. The bank only serves consoles
+/// registered with a key, so both are needed
+fn credentials(
+    id: Option<String>,
+    psk_file: Option<PathBuf>,
+    bank_key: Option<String>,
+) -> Result<AtmCredentials, ConfigError> {
+    let invalid = |setting: &str, reason: String| ConfigError::Invalid {
+        setting: setting.to_string(),
+        reason,
+    };
+    let id = id.unwrap_or_default();
+    if id.is_empty() || !valid_atm_id(&id) {
+        return Err(invalid(
+            "console_id",
+            "must be 1 to 20 letters, digits, `-` or `_`".to_string(),
+        ));
+    }
+    let Some(path) = psk_file else {
+        return Err(invalid(
+            "psk_file",
+            "must be set, as the bank only serves consoles with a key".to_string(),
+        ));
+    };
+    let psk = Psk::load(&path)
+        .map_err(|e| invalid("psk_file", format!("cannot load {}: {e}", path.display())))?;
+    let bank_key = bank_key
+        .map(|hex| {
+            BankKey::from_hex(&hex)
+                .ok_or_else(|| invalid("bank_key", "must be 64 hex digits".to_string()))
+        })
+        .transpose()?;
+    Ok(AtmCredentials {
+        id,
+        psk: Some(psk),
+        bank_key,
+    })
+}
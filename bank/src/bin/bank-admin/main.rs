@@ -0,0 +1,121 @@
+mod config;
+use crate::config::AdminClientConfig;
+use bank::cli;
+use common::admin::{AdminChannel, AdminReply, AdminRequest};
+use std::io::{self, Write};
+
+/// Admin console entrypoint
+fn main() {
+    let config = AdminClientConfig::from_args().unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        std::process::exit(2);
+    });
+
+    let stream = config.bank.connect().unwrap_or_else(|e| {
+        eprintln!("Error: could not reach the bank at {}: {e}", config.bank);
+        std::process::exit(1);
+    });
+    let mut channel = AdminChannel::open(stream, config.timeouts, &config.credentials)
+        .unwrap_or_else(|e| {
+            eprintln!("Error opening a channel to the bank: {e}");
+            std::process::exit(1);
+        });
+
+    if !login(&mut channel, config.operator) {
+        std::process::exit(1);
+    }
+    if !run_commands(&mut channel) {
+        std::process::exit(1);
+    }
+}
+
+/// Logs an operator in, asking for their password until the bank accepts it
+/// or hangs up. Returns false if no operator logged in
+fn login(channel: &mut AdminChannel, operator: Option<String>) -> bool {
+    let name = match operator {
+        Some(name) => name,
+        None => {
+            print!("Operator: ");
+            io::stdout().flush().unwrap();
+            let mut name = String::new();
+            if io::stdin()
+                .read_line(&mut name)
+                .expect("Failed to read line from stdin")
+                == 0
+            {
+                return false;
+            }
+            name.trim().to_string()
+        }
+    };
+
+    loop {
+        let Some(password) = cli::read_password("Password: ") else {
+            return false;
+        };
+        let request = AdminRequest::Login {
+            name: name.clone(),
+            password,
+        };
+        match exchange(channel, &request) {
+            None => return false,
+            Some(AdminReply::LoggedIn(greeting)) => {
+                println!("\n{greeting}");
+                return true;
+            }
+            Some(AdminReply::Refused(reason)) => println!("{reason}"),
+            Some(AdminReply::Output(text) | AdminReply::Ended(text)) => {
+                println!("{text}");
+                return false;
+            }
+        }
+    }
+}
+
+/// Sends the operator's commands from stdin to the bank and prints what they
+/// had to say, until the operator logs out or the bank ends the session. The
+/// operator is logged out once stdin closes. Returns false if the connection
+/// failed
+fn run_commands(channel: &mut AdminChannel) -> bool {
+    // user input buffer
+    let mut user_input = String::new();
+
+    loop {
+        print!("\n{}", cli::get_prompt());
+        io::stdout().flush().unwrap();
+        // clear input buffer before next read
+        user_input.clear();
+        let line = match io::stdin()
+            .read_line(&mut user_input)
+            .expect("Failed to read line from stdin")
+        {
+            0 => "logout",
+            _ => user_input.trim(),
+        };
+
+        match exchange(channel, &AdminRequest::Command(line.to_string())) {
+            None => return false,
+            Some(AdminReply::Output(output)) => print!("{output}"),
+            Some(AdminReply::LoggedIn(text) | AdminReply::Refused(text)) => println!("{text}"),
+            Some(AdminReply::Ended(text)) => {
+                println!("{text}");
+                return true;
+            }
+        }
+    }
+}
+
+/// Sends a request to the bank and waits for its reply. Returns `None`,
+/// reporting why, if the connection failed
+fn exchange(channel: &mut AdminChannel, request: &AdminRequest) -> Option<AdminReply> {
+    let reply = channel
+        .send_request(request)
+        .and_then(|()| channel.receive_reply());
+    match reply {
+        Err(e) => {
+            eprintln!("Error talking to the bank: {e}");
+            None
+        }
+        Ok(reply) => Some(reply),
+    }
+}
@@ -0,0 +1,688 @@
+use crate::{
+    audit::{outcome, Actor, AuditLog},
+    bank::Bank,
+    operators::{Operator, Role},
+};
+use common::message::Amount;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+/// Appends a line to a command's output, as `println!` would print it
+macro_rules! outln {
+    ($out:expr) => {
+        $out.push('\n')
+    };
+    ($out:expr, $($arg:tt)*) => {{
+        $out.push_str(&format!($($arg)*));
+        $out.push('\n');
+    }};
+}
+
+/// Largest deposit, transfer or opening balance an operator may make without
+/// a second operator's approval: $10,000
+pub const DEFAULT_DUAL_CONTROL_LIMIT: Amount = match Amount::from_cents(1_000_000) {
+    Ok(limit) => limit,
+    Err(_) => panic!("default dual control limit is larger than Amount::MAX"),
+};
+
+/// Every role
+const ANYONE: &[Role] = &[Role::Teller, Role::Supervisor, Role::Auditor];
+/// Roles which move money and open accounts
+const STAFF: &[Role] = &[Role::Teller, Role::Supervisor];
+/// Roles which review the bank
+const REVIEWERS: &[Role] = &[Role::Supervisor, Role::Auditor];
+/// Roles which run the bank
+const SUPERVISORS: &[Role] = &[Role::Supervisor];
+
+/// A command the console accepts, and the roles which may run it
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    roles: &'static [Role],
+}
+
+/// Permission table: every command, and who may run it
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "create-user",
+        usage: "create-user <user-name> <pin> <balance>",
+        roles: STAFF,
+    },
+    Command {
+        name: "deposit",
+        usage: "deposit <user-name> <amt>",
+        roles: STAFF,
+    },
+    Command {
+        name: "balance",
+        usage: "balance <user-name>",
+        roles: ANYONE,
+    },
+    Command {
+        name: "transfer",
+        usage: "transfer <from-user> <to-user> <amt>",
+        roles: STAFF,
+    },
+    Command {
+        name: "reset-pin",
+        usage: "reset-pin <user-name> <pin>",
+        roles: STAFF,
+    },
+    Command {
+        name: "users",
+        usage: "users",
+        roles: SUPERVISORS,
+    },
+    Command {
+        name: "pending",
+        usage: "pending",
+        roles: REVIEWERS,
+    },
+    Command {
+        name: "approve",
+        usage: "approve <request-id>",
+        roles: SUPERVISORS,
+    },
+    Command {
+        name: "reject",
+        usage: "reject <request-id>",
+        roles: SUPERVISORS,
+    },
+    Command {
+        name: "verify-audit",
+        usage: "verify-audit",
+        roles: REVIEWERS,
+    },
+    Command {
+        name: "help",
+        usage: "help",
+        roles: ANYONE,
+    },
+    Command {
+        name: "logout",
+        usage: "logout",
+        roles: ANYONE,
+    },
+    Command {
+        name: "exit",
+        usage: "exit",
+        roles: SUPERVISORS,
+    },
+];
+
+/// Returns CLI prompt for bank
+pub fn get_prompt() -> String {
+    "BANK: ".to_string()
+}
+/// Returns CLI help list of the commands the given role may run
+pub fn get_help_display(role: Role) -> String {
+    COMMANDS
+        .iter()
+        .filter(|command| command.roles.contains(&role))
+        .map(|command| format!("  {}", command.usage))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Returns the greeting shown to an operator once they log in
+pub fn get_greeting(operator: &Operator) -> String {
+    format!(
+        "Logged in as {} ({})\nAvailable commands:\n{}",
+        operator.name,
+        operator.role,
+        get_help_display(operator.role)
+    )
+}
+
+/// Reads a password from the terminal without echoing it. Falls back to a
+/// line from stdin when there is no terminal, e.g. when input is piped in.
+/// Returns `None` once stdin closes
+pub fn read_password(prompt: &str) -> Option<String> {
+    if let Ok(password) = rpassword::prompt_password(prompt) {
+        return Some(password);
+    }
+    print!("{prompt}");
+    io::stdout().flush().unwrap();
+    let mut password = String::new();
+    match io::stdin().read_line(&mut password) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(password.trim_end_matches(['\r', '\n']).to_string()),
+    }
+}
+
+/// What the terminal does once a command has run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    /// Prompt the same operator for another command
+    Prompt,
+    /// Log the operator out and wait for the next one
+    Logout,
+    /// Shut the bank down
+    Shutdown,
+}
+
+/// An action held until a second operator approves it
+#[derive(Debug, Clone, PartialEq)]
+enum Held {
+    CreateUser {
+        username: String,
+        pin: u16,
+        balance: Amount,
+    },
+    Deposit {
+        username: String,
+        amount: Amount,
+    },
+    Transfer {
+        from: String,
+        to: String,
+        amount: Amount,
+    },
+    ResetPin {
+        username: String,
+        pin: u16,
+    },
+}
+
+impl Held {
+    /// Describes the action for the audit log and the pending list. New pins
+    /// are left out
+    fn action(&self) -> String {
+        match self {
+            Self::CreateUser { balance, .. } => format!("create-user {balance}"),
+            Self::Deposit { amount, .. } => format!("deposit {amount}"),
+            Self::Transfer { to, amount, .. } => format!("transfer {amount} to {to}"),
+            Self::ResetPin { .. } => "reset-pin".to_string(),
+        }
+    }
+    /// Returns the account the action is carried out on. Transfers are
+    /// carried out on the account money is taken from
+    fn username(&self) -> &str {
+        match self {
+            Self::CreateUser { username, .. }
+            | Self::Deposit { username, .. }
+            | Self::ResetPin { username, .. } => username,
+            Self::Transfer { from, .. } => from,
+        }
+    }
+    /// Returns the money the action brings into or moves around the bank,
+    /// which is checked against the dual control limit
+    fn amount(&self) -> Option<Amount> {
+        match self {
+            Self::CreateUser { balance, .. } => Some(*balance),
+            Self::Deposit { amount, .. } | Self::Transfer { amount, .. } => Some(*amount),
+            Self::ResetPin { .. } => None,
+        }
+    }
+}
+
+/// A held action and who asked for it
+#[derive(Debug)]
+struct Pending {
+    id: u64,
+    requester: Actor,
+    held: Held,
+}
+
+/// Actions awaiting approval
+#[derive(Debug)]
+struct Approvals {
+    pending: Vec<Pending>,
+    /// Id of the next held action
+    next_id: u64,
+}
+
+/// The bank's command line. Runs operators' commands against the bank once
+/// the permission table allows them, holding sensitive actions until a second
+/// operator approves them, and records everything in the audit log
+pub struct Console {
+    bank: Arc<Bank>,
+    audit: Arc<AuditLog>,
+    /// Deposits, transfers and opening balances larger than this need a
+    /// second operator's approval
+    dual_control_limit: Amount,
+    approvals: Mutex<Approvals>,
+}
+
+impl Console {
+    pub fn new(bank: Arc<Bank>, audit: Arc<AuditLog>, dual_control_limit: Amount) -> Self {
+        Self {
+            bank,
+            audit,
+            dual_control_limit,
+            approvals: Mutex::new(Approvals {
+                pending: Vec::new(),
+                next_id: 1,
+            }),
+        }
+    }
+
+    /// Processes user input based on content, if the operator's role may run
+    /// the command, writing what the command has to say to `out`. Every
+    /// command which gets as far as the bank is recorded in the audit log
+    pub fn process_input(&self, operator: &Operator, input: &str, out: &mut String) -> Next {
+        let actor = Actor::Operator(operator.name.clone());
+        let name = input.split_whitespace().next().unwrap_or_default();
+        let Some(command) = COMMANDS.iter().find(|command| command.name == name) else {
+            outln!(out, "Invalid command. Use `help` to see options.");
+            return Next::Prompt;
+        };
+        if !command.roles.contains(&operator.role) {
+            outln!(out, "Error: a {} may not run `{name}`\n", operator.role);
+            self.audit.record(&actor, name, "", "denied");
+            return Next::Prompt;
+        }
+
+        match name {
+            "create-user" => self.process_create_user(&actor, input, out),
+            "deposit" => self.process_deposit(&actor, input, out),
+            "balance" => self.process_balance(&actor, input, out),
+            "transfer" => self.process_transfer(&actor, input, out),
+            "reset-pin" => self.process_reset_pin(&actor, input, out),
+            "users" => self.display_users(&actor, input, out),
+            "pending" => self.display_pending(input, out),
+            "approve" => self.process_decision(&actor, input, true, out),
+            "reject" => self.process_decision(&actor, input, false, out),
+            "verify-audit" => self.verify_audit(&actor, input, out),
+            "help" => outln!(out, "{}", get_help_display(operator.role)),
+            "logout" => {
+                self.audit.record(&actor, "logout", "", "ok");
+                return Next::Logout;
+            }
+            "exit" => {
+                self.audit.record(&actor, "exit", "", "ok");
+                return Next::Shutdown;
+            }
+            _ => unreachable!("every command in the table is handled"),
+        }
+        Next::Prompt
+    }
+
+    //
+    // commands
+
+    /// Lists every user and their balance. PINs are never shown
+    fn display_users(&self, actor: &Actor, user_input: &str, out: &mut String) {
+        if user_input != "users" {
+            outln!(out, "Usage: users\n");
+            return;
+        }
+        self.audit.record(actor, "users", "", "ok");
+        outln!(out, "Bank user information:");
+        for user in self.bank.users() {
+            outln!(out, "{} -> ${}", user.name, user.balance);
+        }
+        outln!(out);
+    }
+
+    /// Checks the audit log has not been modified or cut short
+    fn verify_audit(&self, actor: &Actor, user_input: &str, out: &mut String) {
+        if user_input != "verify-audit" {
+            outln!(out, "Usage: verify-audit\n");
+            return;
+        }
+        let verified = self.audit.verify();
+        match &verified {
+            Err(e) => outln!(out, "Audit log verification failed: {e}\n"),
+            Ok(head) => outln!(out, "Audit log intact: {head}\n"),
+        }
+        self.audit
+            .record(actor, "verify-audit", "", &outcome(&verified));
+    }
+
+    /// Processes a request to create a new user. The given request must include
+    /// a username, pin for the user, and an initial balance. Opening balances
+    /// over the dual control limit are held for approval
+    fn process_create_user(&self, actor: &Actor, user_input: &str, out: &mut String) {
+        lazy_static! {
+            static ref CU_RE: Regex =
+                Regex::new(r"^create-user ([a-zA-Z]+) ([0-9]{4}) ([0-9]+\.?[0-9]{0,2})$")
+                    .expect("Error while compiling create-user regular expression");
+        }
+
+        // ensure input matches
+        if !CU_RE.is_match(user_input) {
+            outln!(
+                out,
+                "Usage: create-user <user-name> <4-digit-PIN> <balance>\n"
+            );
+            return;
+        }
+
+        let caps: Captures = CU_RE.captures(user_input).unwrap();
+        let username = caps.get(1).unwrap().as_str();
+        // pin is guaranteed to be 4 digits
+        let pin: u16 = caps.get(2).unwrap().as_str().parse::<u16>().unwrap();
+
+        // validate initial balance
+        let balance: Amount = match caps.get(3).unwrap().as_str().parse() {
+            Ok(v) => v,
+            Err(_) => {
+                outln!(
+                    out,
+                    "Error: we don't have a big enough vault to store a balance this large\n"
+                );
+                return;
+            }
+        };
+
+        self.submit(
+            actor,
+            Held::CreateUser {
+                username: username.to_string(),
+                pin,
+                balance,
+            },
+            out,
+        );
+    }
+
+    /// Processes a request to make a deposit into a user's account. The given
+    /// request must include a username and an amount to deposit. Deposits over
+    /// the dual control limit are held for approval
+    fn process_deposit(&self, actor: &Actor, user_input: &str, out: &mut String) {
+        lazy_static! {
+            static ref D_RE: Regex = Regex::new(r"^deposit ([a-zA-Z]+) ([0-9]+\.?[0-9]{0,2})$")
+                .expect("Error while compiling deposit regular expression");
+        }
+
+        // ensure input matches
+        if !D_RE.is_match(user_input) {
+            outln!(out, "Usage: deposit <user-name> <amount>\n");
+            return;
+        }
+
+        let caps: Captures = D_RE.captures(user_input).unwrap();
+        let username = caps.get(1).unwrap().as_str();
+
+        // validate deposit amount
+        let amount: Amount = match caps.get(2).unwrap().as_str().parse() {
+            Ok(v) => v,
+            Err(_) => {
+                outln!(
+                    out,
+                    "Error: we don't have a big enough vault to store wealth of this magnitute\n"
+                );
+                return;
+            }
+        };
+
+        self.submit(
+            actor,
+            Held::Deposit {
+                username: username.to_string(),
+                amount,
+            },
+            out,
+        );
+    }
+
+    /// Processes a request to view a user's balance
+    fn process_balance(&self, actor: &Actor, user_input: &str, out: &mut String) {
+        lazy_static! {
+            static ref B_RE: Regex = Regex::new("^balance ([a-zA-Z]+)$")
+                .expect("Error while compiling balance regular expression");
+        }
+
+        // ensure input matches
+        if !B_RE.is_match(user_input) {
+            outln!(out, "Usage: balance <user-name>\n");
+            return;
+        }
+
+        let caps: Captures = B_RE.captures(user_input).unwrap();
+        let username = caps.get(1).unwrap().as_str();
+
+        let result = self.bank.balance(username);
+        self.audit
+            .record(actor, "balance", username, &outcome(&result));
+        match result {
+            Err(e) => outln!(out, "Error: {e}\n"),
+            Ok(balance) => outln!(out, "Balance for {} is: ${}\n", username, balance),
+        }
+    }
+
+    /// Processes a request to move money between two users' accounts. The
+    /// given request must include both usernames and an amount to transfer.
+    /// Transfers over the dual control limit are held for approval
+    fn process_transfer(&self, actor: &Actor, user_input: &str, out: &mut String) {
+        lazy_static! {
+            static ref T_RE: Regex =
+                Regex::new(r"^transfer ([a-zA-Z]+) ([a-zA-Z]+) ([0-9]+\.?[0-9]{0,2})$")
+                    .expect("Error while compiling transfer regular expression");
+        }
+
+        // ensure input matches
+        if !T_RE.is_match(user_input) {
+            outln!(out, "Usage: transfer <from-user> <to-user> <amount>\n");
+            return;
+        }
+
+        let caps: Captures = T_RE.captures(user_input).unwrap();
+        let from = caps.get(1).unwrap().as_str();
+        let to = caps.get(2).unwrap().as_str();
+
+        // validate transfer amount
+        let amount: Amount = match caps.get(3).unwrap().as_str().parse() {
+            Ok(v) => v,
+            Err(_) => {
+                outln!(
+                    out,
+                    "Error: we don't have a big enough vault to move wealth of this magnitute\n"
+                );
+                return;
+            }
+        };
+
+        self.submit(
+            actor,
+            Held::Transfer {
+                from: from.to_string(),
+                to: to.to_string(),
+                amount,
+            },
+            out,
+        );
+    }
+
+    /// Processes a request to give a user a new pin, which is always held for
+    /// approval
+    fn process_reset_pin(&self, actor: &Actor, user_input: &str, out: &mut String) {
+        lazy_static! {
+            static ref R_RE: Regex = Regex::new("^reset-pin ([a-zA-Z]+) ([0-9]{4})$")
+                .expect("Error while compiling reset-pin regular expression");
+        }
+
+        // ensure input matches
+        if !R_RE.is_match(user_input) {
+            outln!(out, "Usage: reset-pin <user-name> <4-digit-PIN>\n");
+            return;
+        }
+
+        let caps: Captures = R_RE.captures(user_input).unwrap();
+        let username = caps.get(1).unwrap().as_str();
+        // pin is guaranteed to be 4 digits
+        let pin: u16 = caps.get(2).unwrap().as_str().parse::<u16>().unwrap();
+
+        self.hold(
+            actor,
+            Held::ResetPin {
+                username: username.to_string(),
+                pin,
+            },
+            out,
+        );
+    }
+
+    /// Lists the actions awaiting approval
+    fn display_pending(&self, user_input: &str, out: &mut String) {
+        if user_input != "pending" {
+            outln!(out, "Usage: pending\n");
+            return;
+        }
+        let approvals = self.approvals.lock().unwrap();
+        if approvals.pending.is_empty() {
+            outln!(out, "No requests are awaiting approval\n");
+            return;
+        }
+        for pending in &approvals.pending {
+            outln!(
+                out,
+                "  #{} {} for {}, requested by {}",
+                pending.id,
+                pending.held.action(),
+                pending.held.username(),
+                pending.requester
+            );
+        }
+        outln!(out);
+    }
+
+    /// Processes a supervisor's decision on a held action. The operator who
+    /// asked for an action may not decide on it
+    fn process_decision(&self, actor: &Actor, user_input: &str, approve: bool, out: &mut String) {
+        lazy_static! {
+            static ref A_RE: Regex = Regex::new("^(approve|reject) ([0-9]+)$")
+                .expect("Error while compiling approval regular expression");
+        }
+
+        // ensure input matches
+        let verb = if approve { "approve" } else { "reject" };
+        let id = match A_RE.captures(user_input) {
+            Some(caps) => caps.get(2).unwrap().as_str().parse::<u64>().ok(),
+            None => None,
+        };
+        let Some(id) = id else {
+            outln!(out, "Usage: {verb} <request-id>\n");
+            return;
+        };
+
+        let pending = {
+            let mut approvals = self.approvals.lock().unwrap();
+            let Some(index) = approvals.pending.iter().position(|p| p.id == id) else {
+                outln!(out, "Error: no request #{id} is awaiting approval\n");
+                return;
+            };
+            if actor == &approvals.pending[index].requester {
+                outln!(
+                    out,
+                    "Error: request #{id} must be decided by another operator\n"
+                );
+                self.audit
+                    .record(actor, &format!("{verb} #{id}"), "", "denied: own request");
+                return;
+            }
+            approvals.pending.remove(index)
+        };
+
+        let action = format!("{verb} #{id} {}", pending.held.action());
+        if approve {
+            self.carry_out(actor, &action, &pending.held, out);
+        } else {
+            self.audit
+                .record(actor, &action, pending.held.username(), "ok");
+            outln!(out, "Rejected request #{id}\n");
+        }
+    }
+
+    //
+    // helpers
+
+    /// Carries out an action, or holds it for approval if it involves more
+    /// money than the dual control limit
+    fn submit(&self, actor: &Actor, held: Held, out: &mut String) {
+        if held
+            .amount()
+            .is_some_and(|amount| amount > self.dual_control_limit)
+        {
+            self.hold(actor, held, out);
+            return;
+        }
+        self.carry_out(actor, &held.action(), &held, out);
+    }
+
+    /// Holds an action until another operator approves it. The hold is
+    /// audited before the action can be approved, but without holding up
+    /// other operators' approvals while the log is written
+    fn hold(&self, actor: &Actor, held: Held, out: &mut String) {
+        let id = {
+            let mut approvals = self.approvals.lock().unwrap();
+            approvals.next_id += 1;
+            approvals.next_id - 1
+        };
+        self.audit.record(
+            actor,
+            &held.action(),
+            held.username(),
+            &format!("held for approval as #{id}"),
+        );
+        outln!(out, "Request #{id} needs a second operator's approval\n");
+        self.approvals.lock().unwrap().pending.push(Pending {
+            id,
+            requester: actor.clone(),
+            held,
+        });
+    }
+
+    /// Carries out an action which needs no more approval
+    fn carry_out(&self, actor: &Actor, action: &str, held: &Held, out: &mut String) {
+        match held {
+            Held::CreateUser {
+                username,
+                pin,
+                balance,
+            } => {
+                let result = self.bank.create_account(username, *pin, *balance);
+                self.audit
+                    .record(actor, action, username, &outcome(&result));
+                match result {
+                    Err(e) => outln!(out, "Error: {e}\n"),
+                    Ok(()) => outln!(out, "Created account for {}\n", username),
+                }
+            }
+            Held::Deposit { username, amount } => {
+                let result = self.bank.deposit(username, *amount);
+                self.audit
+                    .record(actor, action, username, &outcome(&result));
+                match result {
+                    Err(e) => outln!(out, "Error: {e}\n"),
+                    Ok(balance) => {
+                        outln!(
+                            out,
+                            "${} was successfully deposited into the account",
+                            amount
+                        );
+                        outln!(out, "Balance for {} is: ${}\n", username, balance);
+                    }
+                }
+            }
+            Held::Transfer { from, to, amount } => {
+                let result = self.bank.transfer(from, to, *amount);
+                self.audit.record(actor, action, from, &outcome(&result));
+                match result {
+                    Err(e) => outln!(out, "Error: {e}\n"),
+                    Ok(()) => outln!(
+                        out,
+                        "${} was successfully transferred from {} to {}\n",
+                        amount,
+                        from,
+                        to
+                    ),
+                }
+            }
+            Held::ResetPin { username, pin } => {
+                let result = self.bank.set_pin(username, *pin);
+                self.audit
+                    .record(actor, action, username, &outcome(&result));
+                match result {
+                    Err(e) => outln!(out, "Error: {e}\n"),
+                    Ok(()) => outln!(out, "Pin for {} has been reset\n", username),
+                }
+            }
+        }
+    }
+}
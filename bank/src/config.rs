@@ -0,0 +1,631 @@
+use bank::{
+    admin::AdminConfig,
+    audit::{AuditKey, AuditLog},
+    cli::DEFAULT_DUAL_CONTROL_LIMIT,
+    operators::{Operators, Role},
+    pool::{ConnectionLimits, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_CONNECTIONS_PER_PEER},
+    server::{ResponseTimes, ServerConfig, SessionTimeouts},
+    storage::{DataFile, DataKey},
+};
+use clap::{Parser, ValueEnum};
+use common::{
+    config::{
+        errors::ConfigError, load_toml, nonzero_count, nonzero_secs, parse_socket_mode,
+        resolve_endpoint,
+    },
+    crypto::{AtmKeys, BankSecret},
+    io::{BANK_SERVER_ADDR, DEFAULT_IDLE_TIMEOUT, DEFAULT_REQUEST_TIMEOUT},
+    message::Amount,
+    transport::{Endpoint, DEFAULT_SOCKET_MODE},
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Name of the file user records are stored in, within the data directory
+const BANK_DATA_FILE: &str = "bank.db";
+/// Name of the audit log, within the data directory, unless set elsewhere
+const AUDIT_LOG_FILE: &str = "audit.log";
+/// Name of the file the audit log's key is kept in, within the data
+/// directory, unless set elsewhere
+const AUDIT_KEY_FILE: &str = "audit.key";
+/// Name of the operator accounts file, within the data directory, unless set elsewhere
+const OPERATORS_FILE: &str = "operators";
+
+/// Command line flags. Flags override settings from the config file
+#[derive(Debug, Parser)]
+#[command(name = "bank", about = "Bank server which ATMs connect to")]
+struct Args {
+    /// Path to a TOML config file
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Address to listen for ATM connections on: `host:port` or `unix:<socket-path>`
+    #[arg(long)]
+    listen: Option<String>,
+    /// Permissions given to the bank's Unix socket files, in octal. Defaults
+    /// to 660, so only their owner and group may connect
+    #[arg(long)]
+    socket_mode: Option<String>,
+    /// Directory the bank's data files are kept in
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// File every operator command and ATM request is recorded in. Defaults
+    /// to `audit.log` in the data directory
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+    /// File holding the key the audit log is chained with, as 64 hex digits.
+    /// Defaults to `audit.key` in the data directory. Created with a new key
+    /// if missing
+    #[arg(long)]
+    audit_key: Option<PathBuf>,
+    /// Maximum number of ATM connections served at once
+    #[arg(long)]
+    max_connections: Option<usize>,
+    /// Maximum number of ATM connections served at once from a single address
+    #[arg(long)]
+    max_connections_per_peer: Option<usize>,
+    /// Seconds an ATM session may sit idle before the bank ends it
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// Seconds the bank waits for a reply to be written to an ATM
+    #[arg(long)]
+    request_timeout: Option<u64>,
+    /// Milliseconds the bank takes at least to answer any request, so reply
+    /// timing does not reveal what was asked
+    #[arg(long)]
+    min_response: Option<u64>,
+    /// Address to listen for remote admin consoles on: `host:port` or
+    /// `unix:<socket-path>`. Consoles are not served unless set
+    #[arg(long)]
+    admin_listen: Option<String>,
+    /// File of admin console ids and the pre-shared keys they must hold to
+    /// connect. Needed to serve admin consoles
+    #[arg(long)]
+    console_keys: Option<PathBuf>,
+    /// File of ATM ids and the pre-shared keys they must hold to connect.
+    /// Needed to serve ATMs unless `--allow-anonymous-atms` is set
+    #[arg(long)]
+    atm_keys: Option<PathBuf>,
+    /// Serve any ATM without a key when no ATM keys are registered, leaving
+    /// the channel open to anyone in the middle
+    #[arg(long)]
+    allow_anonymous_atms: bool,
+    /// File holding the bank's private Noise key as 64 hex digits. ATMs may
+    /// only use the Noise handshake if set
+    #[arg(long)]
+    noise_key: Option<PathBuf>,
+    /// File holding the key bank data is encrypted with, as 64 hex digits.
+    /// Bank data is kept in plaintext unless this or `--data-passphrase` is set
+    #[arg(long)]
+    data_key_file: Option<PathBuf>,
+    /// Prompt for the passphrase bank data is encrypted with
+    #[arg(long)]
+    data_passphrase: bool,
+    /// Re-encrypt bank data at startup with the key in this file, which the
+    /// bank then keeps using
+    #[arg(long)]
+    rotate_key_file: Option<PathBuf>,
+    /// Re-encrypt bank data at startup with a newly prompted passphrase, which
+    /// the bank then keeps using
+    #[arg(long)]
+    rotate_passphrase: bool,
+    /// File of the operators who may log in to the bank's command line.
+    /// Defaults to `operators` in the data directory
+    #[arg(long)]
+    operators: Option<PathBuf>,
+    /// Largest deposit, transfer or opening balance, in dollars, an operator
+    /// may make without a second operator's approval
+    #[arg(long)]
+    dual_control_limit: Option<Amount>,
+    /// Register an operator with a newly prompted password, then exit
+    #[arg(long, requires = "role")]
+    add_operator: Option<String>,
+    /// Role of the operator registered with `--add-operator`: `teller`,
+    /// `supervisor` or `auditor`
+    #[arg(long, requires = "add_operator")]
+    role: Option<Role>,
+    /// How ATM connections are served: `threads` or `async`
+    #[arg(long)]
+    runtime: Option<RuntimeMode>,
+    /// Threads the async runtime runs connections on. Defaults to one per CPU
+    #[arg(long)]
+    worker_threads: Option<usize>,
+    /// Run without the command line on stdin, as under a service manager.
+    /// Operators use admin consoles instead
+    #[arg(long)]
+    headless: bool,
+    /// File to write the bank's process id to while it runs
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+    /// File to append everything the bank prints to, instead of stdout and
+    /// stderr. Reopened on SIGHUP
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+}
+
+/// Names of the available server runtimes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum RuntimeMode {
+    /// One blocking worker thread per connection
+    Threads,
+    /// Connections as tasks on a tokio runtime. Requires the `async` feature
+    Async,
+}
+
+/// Layout of the config file. Every setting is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<String>,
+    socket_mode: Option<String>,
+    data_dir: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+    audit_key: Option<PathBuf>,
+    #[serde(default)]
+    limits: FileLimits,
+    #[serde(default)]
+    timeouts: FileTimeouts,
+    #[serde(default)]
+    channel: FileChannel,
+    #[serde(default)]
+    storage: FileStorage,
+    #[serde(default)]
+    console: FileConsole,
+    #[serde(default)]
+    admin: FileAdmin,
+    #[serde(default)]
+    runtime: FileRuntime,
+    #[serde(default)]
+    daemon: FileDaemon,
+}
+
+/// `[channel]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileChannel {
+    atm_keys: Option<PathBuf>,
+    allow_anonymous: Option<bool>,
+    noise_key: Option<PathBuf>,
+}
+
+/// `[storage]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileStorage {
+    key_file: Option<PathBuf>,
+    passphrase: Option<bool>,
+}
+
+/// `[console]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConsole {
+    operators: Option<PathBuf>,
+    dual_control_limit: Option<FileAmount>,
+}
+
+/// An amount of dollars in the config file: a whole number, or a string such
+/// as `"10000.50"` for an amount with cents
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FileAmount {
+    Dollars(u64),
+    Exact(String),
+}
+
+impl FileAmount {
+    /// Returns the amount, if it is whole cents no larger than `Amount::MAX`
+    fn amount(&self) -> Option<Amount> {
+        match self {
+            Self::Dollars(dollars) => Amount::from_cents(dollars.checked_mul(100)?).ok(),
+            Self::Exact(dollars) => dollars.parse().ok(),
+        }
+    }
+}
+
+/// `[admin]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAdmin {
+    listen: Option<String>,
+    console_keys: Option<PathBuf>,
+}
+
+/// `[daemon]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileDaemon {
+    headless: Option<bool>,
+    pid_file: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+}
+
+/// `[runtime]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRuntime {
+    mode: Option<RuntimeMode>,
+    worker_threads: Option<usize>,
+}
+
+/// `[limits]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileLimits {
+    max_connections: Option<usize>,
+    max_connections_per_peer: Option<usize>,
+}
+
+/// `[timeouts]` section of the config file, in seconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTimeouts {
+    idle: Option<u64>,
+    request: Option<u64>,
+    #[serde(default)]
+    min_response: FileResponseTimes,
+}
+
+/// `[timeouts.min_response]` section of the config file, in milliseconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileResponseTimes {
+    auth: Option<u64>,
+    query: Option<u64>,
+    transaction: Option<u64>,
+}
+
+/// How the bank serves ATM connections
+#[derive(Debug, Clone, Copy)]
+pub enum Runtime {
+    /// A pool of blocking worker threads, one connection per worker
+    Threads,
+    /// Tasks on a tokio runtime with the given number of threads, or one per CPU
+    #[cfg(feature = "async")]
+    Async { worker_threads: Option<usize> },
+}
+
+/// Validated bank settings
+#[derive(Debug)]
+pub struct BankConfig {
+    /// Address to listen for ATM connections on
+    pub listen: Endpoint,
+    /// Permissions given to the files of Unix sockets the bank listens on
+    pub socket_mode: u32,
+    /// File user records are loaded from and persisted to
+    pub data: DataFile,
+    /// Key to re-encrypt the data file with before serving
+    pub new_data_key: Option<DataKey>,
+    /// File operator accounts are kept in
+    pub operators_path: PathBuf,
+    /// File ATM keys are kept in, if ATMs need keys
+    pub atm_keys_path: Option<PathBuf>,
+    /// File admin console keys are kept in, if any
+    pub console_keys_path: Option<PathBuf>,
+    /// Operators who may log in to the command line
+    pub operators: Operators,
+    /// Deposits, transfers and opening balances larger than this need a
+    /// second operator's approval
+    pub dual_control_limit: Amount,
+    /// Operator to register instead of running the bank
+    pub add_operator: Option<(String, Role)>,
+    /// Address to listen for admin consoles on, if they are served
+    pub admin_listen: Option<Endpoint>,
+    pub server: ServerConfig,
+    pub admin: AdminConfig,
+    pub runtime: Runtime,
+    /// Whether to run without the command line on stdin
+    pub headless: bool,
+    /// File the bank's process id is written to while it runs
+    pub pid_file: Option<PathBuf>,
+    /// File the bank's output is appended to instead of stdout and stderr
+    pub log_file: Option<PathBuf>,
+}
+
+impl BankConfig {
+    /// Builds the bank's settings from command line flags and the config file
+    /// they point to, falling back to defaults for anything left unset
+    pub fn from_args() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+        let file: FileConfig = match &args.config {
+            None => FileConfig::default(),
+            Some(path) => load_toml(path)?,
+        };
+
+        let listen = args
+            .listen
+            .or(file.listen)
+            .unwrap_or(BANK_SERVER_ADDR.to_string());
+        let socket_mode = match args.socket_mode.or(file.socket_mode) {
+            None => DEFAULT_SOCKET_MODE,
+            Some(mode) => parse_socket_mode("socket_mode", &mode)?,
+        };
+        let data_dir = args
+            .data_dir
+            .or(file.data_dir)
+            .unwrap_or(PathBuf::from("."));
+        let max_connections = args
+            .max_connections
+            .or(file.limits.max_connections)
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        let max_per_peer = args
+            .max_connections_per_peer
+            .or(file.limits.max_connections_per_peer)
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_PEER);
+        let idle = args.idle_timeout.or(file.timeouts.idle);
+        let request = args.request_timeout.or(file.timeouts.request);
+        // the flag sets every class of request, overriding the file
+        let min_response =
+            |class: Option<u64>| Duration::from_millis(args.min_response.or(class).unwrap_or(0));
+        let min_response = ResponseTimes {
+            auth: min_response(file.timeouts.min_response.auth),
+            query: min_response(file.timeouts.min_response.query),
+            transaction: min_response(file.timeouts.min_response.transaction),
+        };
+        let atm_keys_path = args.atm_keys.or(file.channel.atm_keys);
+        let atm_keys = match &atm_keys_path {
+            None => AtmKeys::default(),
+            Some(path) => AtmKeys::load(path).map_err(|e| ConfigError::Invalid {
+                setting: "atm_keys".to_string(),
+                reason: format!("cannot load {}: {e}", path.display()),
+            })?,
+        };
+        let noise_key = args
+            .noise_key
+            .or(file.channel.noise_key)
+            .map(|path| {
+                BankSecret::load(&path).map_err(|e| ConfigError::Invalid {
+                    setting: "noise_key".to_string(),
+                    reason: format!("cannot load {}: {e}", path.display()),
+                })
+            })
+            .transpose()?;
+        // an ATM without a key cannot tell the bank from anyone in the middle.
+        // Registering an operator serves nobody
+        let allow_anonymous = args.allow_anonymous_atms
+            || file.channel.allow_anonymous.unwrap_or(false)
+            || args.add_operator.is_some();
+        if atm_keys.is_open() && !allow_anonymous {
+            return Err(ConfigError::Invalid {
+                setting: "atm_keys".to_string(),
+                reason: "must register at least one ATM, or allow anonymous ATMs to serve any ATM without a key".to_string(),
+            });
+        }
+        let headless = args.headless || file.daemon.headless.unwrap_or(false);
+        let data_passphrase = args.data_passphrase || file.storage.passphrase.unwrap_or(false);
+        // nobody is at the terminal to type a passphrase
+        for (setting, passphrase) in [
+            ("data_passphrase", data_passphrase),
+            ("rotate_passphrase", args.rotate_passphrase),
+        ] {
+            if headless && passphrase {
+                return Err(ConfigError::Invalid {
+                    setting: setting.to_string(),
+                    reason: "cannot be prompted for when headless: use a key file".to_string(),
+                });
+            }
+        }
+        let log_file = args.log_file.or(file.daemon.log_file);
+        #[cfg(not(unix))]
+        if log_file.is_some() {
+            return Err(ConfigError::Invalid {
+                setting: "log_file".to_string(),
+                reason: "log files are not supported on this platform".to_string(),
+            });
+        }
+        let data_key = read_data_key(
+            "data_key_file",
+            args.data_key_file.or(file.storage.key_file),
+            data_passphrase,
+            false,
+        )?;
+        let new_data_key = read_data_key(
+            "rotate_key_file",
+            args.rotate_key_file,
+            args.rotate_passphrase,
+            true,
+        )?;
+        let data_dir = prepare_data_dir(&data_dir)?;
+        let audit_log = args
+            .audit_log
+            .or(file.audit_log)
+            .unwrap_or(data_dir.join(AUDIT_LOG_FILE));
+        let audit_key_path = args
+            .audit_key
+            .or(file.audit_key)
+            .unwrap_or(data_dir.join(AUDIT_KEY_FILE));
+        let audit_key =
+            AuditKey::load_or_create(&audit_key_path).map_err(|e| ConfigError::Invalid {
+                setting: "audit_key".to_string(),
+                reason: format!("cannot load {}: {e}", audit_key_path.display()),
+            })?;
+        let audit = AuditLog::open(&audit_log, audit_key).map_err(|e| ConfigError::Invalid {
+            setting: "audit_log".to_string(),
+            reason: format!("cannot open {}: {e}", audit_log.display()),
+        })?;
+        let operators_path = args
+            .operators
+            .or(file.console.operators)
+            .unwrap_or(data_dir.join(OPERATORS_FILE));
+        let operators = Operators::load(&operators_path).map_err(|e| ConfigError::Invalid {
+            setting: "operators".to_string(),
+            reason: format!("cannot load {}: {e}", operators_path.display()),
+        })?;
+        let file_limit = file
+            .console
+            .dual_control_limit
+            .map(|limit| {
+                limit.amount().ok_or_else(|| ConfigError::Invalid {
+                    setting: "dual_control_limit".to_string(),
+                    reason: "must be a non-negative number of dollars and whole cents".to_string(),
+                })
+            })
+            .transpose()?;
+        let dual_control_limit = args
+            .dual_control_limit
+            .or(file_limit)
+            .unwrap_or(DEFAULT_DUAL_CONTROL_LIMIT);
+        let admin_listen = args
+            .admin_listen
+            .or(file.admin.listen)
+            .map(|addr| resolve_endpoint("admin_listen", &addr))
+            .transpose()?;
+        let console_keys_path = args.console_keys.or(file.admin.console_keys);
+        let console_keys = match &console_keys_path {
+            None => AtmKeys::default(),
+            Some(path) => AtmKeys::load(path).map_err(|e| ConfigError::Invalid {
+                setting: "console_keys".to_string(),
+                reason: format!("cannot load {}: {e}", path.display()),
+            })?,
+        };
+        // an open admin channel would let anyone in the middle read passwords
+        if admin_listen.is_some() && console_keys.is_open() {
+            return Err(ConfigError::Invalid {
+                setting: "admin_listen".to_string(),
+                reason: "needs `console_keys` registering at least one console".to_string(),
+            });
+        }
+        let runtime = args
+            .runtime
+            .or(file.runtime.mode)
+            .unwrap_or(RuntimeMode::Threads);
+        let worker_threads = args.worker_threads.or(file.runtime.worker_threads);
+
+        let audit = Arc::new(audit);
+        let timeouts = SessionTimeouts {
+            idle: match idle {
+                None => DEFAULT_IDLE_TIMEOUT,
+                Some(secs) => nonzero_secs("idle_timeout", secs)?,
+            },
+            write: match request {
+                None => DEFAULT_REQUEST_TIMEOUT,
+                Some(secs) => nonzero_secs("request_timeout", secs)?,
+            },
+            min_response,
+        };
+
+        Ok(Self {
+            listen: resolve_endpoint("listen", &listen)?,
+            socket_mode,
+            data: DataFile::new(data_dir.join(BANK_DATA_FILE), data_key),
+            new_data_key,
+            operators_path,
+            atm_keys_path,
+            console_keys_path,
+            operators,
+            dual_control_limit,
+            add_operator: args.add_operator.zip(args.role),
+            server: ServerConfig {
+                limits: ConnectionLimits {
+                    max_connections: nonzero_count("max_connections", max_connections)?,
+                    max_per_peer: nonzero_count("max_connections_per_peer", max_per_peer)?,
+                },
+                timeouts,
+                atm_keys: Arc::new(atm_keys),
+                noise_key: noise_key.clone(),
+                audit: audit.clone(),
+            },
+            admin_listen,
+            admin: AdminConfig {
+                idle: timeouts.idle,
+                write: timeouts.write,
+                console_keys: Arc::new(console_keys),
+                noise_key,
+                audit,
+                ..AdminConfig::default()
+            },
+            runtime: select_runtime(runtime, worker_threads)?,
+            headless,
+            pid_file: args.pid_file.or(file.daemon.pid_file),
+            log_file,
+        })
+    }
+}
+
+/// Checks the requested runtime is available in this build
+fn select_runtime(
+    mode: RuntimeMode,
+    worker_threads: Option<usize>,
+) -> Result<Runtime, ConfigError> {
+    let worker_threads = worker_threads
+        .map(|count| nonzero_count("worker_threads", count))
+        .transpose()?;
+    match mode {
+        RuntimeMode::Threads if worker_threads.is_some() => Err(ConfigError::Invalid {
+            setting: "worker_threads".to_string(),
+            reason: "only applies to the async runtime".to_string(),
+        }),
+        RuntimeMode::Threads => Ok(Runtime::Threads),
+        #[cfg(feature = "async")]
+        RuntimeMode::Async => Ok(Runtime::Async { worker_threads }),
+        #[cfg(not(feature = "async"))]
+        RuntimeMode::Async => Err(ConfigError::Invalid {
+            setting: "runtime".to_string(),
+            reason: "this bank was built without the `async` feature".to_string(),
+        }),
+    }
+}
+
+/// Reads the data key from its key file, or prompts the operator for a
+/// passphrase. A new passphrase is asked for twice so a typo cannot lock the
+/// bank out of its data
+fn read_data_key(
+    setting: &str,
+    key_file: Option<PathBuf>,
+    passphrase: bool,
+    new: bool,
+) -> Result<Option<DataKey>, ConfigError> {
+    let invalid = |reason: String| ConfigError::Invalid {
+        setting: setting.to_string(),
+        reason,
+    };
+    match (key_file, passphrase) {
+        (None, false) => Ok(None),
+        (Some(_), true) => Err(invalid(
+            "a key file and a passphrase cannot both be used".to_string(),
+        )),
+        (Some(path), false) => DataKey::load(&path)
+            .map(Some)
+            .map_err(|e| invalid(format!("cannot load {}: {e}", path.display()))),
+        (None, true) => {
+            let prompt = |prompt: &str| {
+                rpassword::prompt_password(prompt)
+                    .map_err(|e| invalid(format!("cannot read passphrase: {e}")))
+            };
+            let passphrase = prompt(if new {
+                "New bank data passphrase: "
+            } else {
+                "Bank data passphrase: "
+            })?;
+            if passphrase.is_empty() {
+                return Err(invalid("passphrase must not be empty".to_string()));
+            }
+            if new && prompt("Repeat new bank data passphrase: ")? != passphrase {
+                return Err(invalid("passphrases do not match".to_string()));
+            }
+            Ok(Some(DataKey::Passphrase(passphrase)))
+        }
+    }
+}
+
+/// Creates the data directory if it does not exist yet
+fn prepare_data_dir(data_dir: &Path) -> Result<&Path, ConfigError> {
+    let invalid = |reason: String| ConfigError::Invalid {
+        setting: "data_dir".to_string(),
+        reason,
+    };
+    fs::create_dir_all(data_dir)
+        .map_err(|e| invalid(format!("cannot create {}: {e}", data_dir.display())))?;
+    if !data_dir.is_dir() {
+        return Err(invalid(format!(
+            "{} is not a directory",
+            data_dir.display()
+        )));
+    }
+    Ok(data_dir)
+}
@@ -0,0 +1,126 @@
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::{
+    fs,
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// File holding the bank's process id while it runs, removed once dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes this process's id to the given file. Fails if the file names a
+    /// bank which is still running, but replaces one left behind by a bank
+    /// which did not shut down cleanly
+    pub fn create(path: &Path) -> io::Result<Self> {
+        match write_pid(path) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            result => return result.map(|()| Self { path: path.into() }),
+        }
+        let contents = fs::read_to_string(path)?;
+        if let Some(pid) = contents.trim().parse().ok().filter(|&pid| is_running(pid)) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("the bank is already running as process {pid}"),
+            ));
+        }
+        fs::remove_file(path)?;
+        write_pid(path)?;
+        Ok(Self { path: path.into() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Writes this process's id to a file which must not exist yet
+fn write_pid(path: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    writeln!(file, "{}", process::id())?;
+    file.sync_all()
+}
+
+/// Checks whether a process with the given id exists
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal 0 only checks the process could be signalled
+    pid > 0
+        && (unsafe { libc::kill(pid, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+/// Checks whether a process with the given id exists. Other platforms cannot
+/// tell, so the process is assumed to still run
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+/// Sends everything the bank prints, to stdout and stderr alike, to the end of
+/// the given file. Run again to reopen the file once it has been rotated
+#[cfg(unix)]
+pub fn redirect_output(path: &Path) -> io::Result<()> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    // hold stdout so nothing is printed while it is being switched over
+    let mut stdout = io::stdout().lock();
+    stdout.flush()?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// What the bank tells its service manager it is doing
+#[derive(Debug, Clone, Copy)]
+pub enum State<'a> {
+    /// Serving, with a line describing what
+    Ready(&'a str),
+    /// Reloading its files, and not to be sent another reload until ready
+    Reloading,
+    /// Shutting down
+    Stopping,
+}
+
+/// Tells systemd what the bank is doing, if it started the bank as a
+/// `Type=notify` service. Does nothing otherwise
+#[cfg(unix)]
+pub fn notify(state: State) {
+    use sd_notify::NotifyState;
+
+    let notified = match state {
+        State::Ready(status) => {
+            sd_notify::notify(false, &[NotifyState::Ready, NotifyState::Status(status)])
+        }
+        // systemd needs to know when the reload began to tell it apart from
+        // the readiness which follows
+        State::Reloading => NotifyState::monotonic_usec_now()
+            .and_then(|now| sd_notify::notify(false, &[NotifyState::Reloading, now])),
+        State::Stopping => sd_notify::notify(false, &[NotifyState::Stopping]),
+    };
+    if let Err(e) = notified {
+        eprintln!("Warning: could not notify the service manager: {e}");
+    }
+}
+
+/// Tells the service manager what the bank is doing. Only systemd is told, so
+/// this does nothing on other platforms
+#[cfg(not(unix))]
+pub fn notify(_state: State) {}
@@ -0,0 +1,14 @@
+pub mod admin;
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod audit;
+mod bank;
+pub mod cli;
+pub mod operators;
+pub mod pool;
+pub mod server;
+mod session;
+pub mod storage;
+pub mod test_support;
+
+pub use crate::bank::{errors, Bank, User};
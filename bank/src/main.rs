@@ -1,13 +1,31 @@
-mod bank;
-use crate::bank::Bank;
-use common::{
-    io::{errors::ReceiveError, StreamManager, BANK_SERVER_ADDR},
-    message::{MessageType, Plaintext},
+mod config;
+mod daemon;
+use crate::{
+    config::{BankConfig, Runtime},
+    daemon::{PidFile, State},
 };
+#[cfg(feature = "async")]
+use bank::async_server;
+use bank::{
+    admin,
+    audit::{outcome, Actor, AuditLog},
+    cli::{self, Console, Next},
+    operators::{Operator, Operators, Role},
+    server,
+    storage::DataFile,
+    Bank,
+};
+use common::{crypto::AtmKeys, transport::Listener};
+use signal_hook::consts::{SIGINT, SIGTERM};
+#[cfg(unix)]
+use signal_hook::{consts::SIGHUP, iterator::Signals};
 use std::{
-    io::{self, Write},
-    net::TcpListener,
-    sync::{Arc, Mutex},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread,
 };
 
@@ -15,141 +33,359 @@ use std::{
 fn main() {
     /*
      * this program needs to handle local commands and remote commands
-     * this is done by spawning a thread to handle local connections
-     * and leaving main to listen for remote TCP connections and spawn threads
-     * to handle those connections
+     * this is done by spawning a thread to handle local connections unless
+     * running headless, another to serve remote admin consoles if they are
+     * enabled, another to reload files on SIGHUP, and leaving main to listen
+     * for remote TCP connections and spawn threads to handle those connections
      */
 
-    let bank: Arc<Mutex<Bank>> = Arc::new(Mutex::new(Bank::new()));
+    let config = BankConfig::from_args().unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        std::process::exit(2);
+    });
+    if let Some((name, role)) = &config.add_operator {
+        add_operator(&config, name, *role);
+        return;
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.log_file {
+        daemon::redirect_output(path).unwrap_or_else(|e| {
+            eprintln!("Error opening log file {}: {e}", path.display());
+            std::process::exit(1);
+        });
+    }
+    // run returns before exiting, so the PID file is removed on failure too
+    if let Err(e) = run(config) {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+}
 
-    // spawn thread to process local commands
-    let bank_clone: Arc<Mutex<Bank>> = bank.clone();
-    let local_thread = thread::spawn(|| process_local_commands(bank_clone));
-
-    // bind to port 32001 to listen for atm requests
-    let listener: TcpListener =
-        TcpListener::bind(BANK_SERVER_ADDR).expect("Error: bank could not bind");
-
-    let mut remote_threads = Vec::new();
-
-    for stream in listener.incoming() {
-        match stream {
-            Err(e) => eprintln!("Error getting stream from listener: {}", e),
-            Ok(stream) => {
-                // spawn thread to handle this connection
-                let bank_clone = bank.clone();
-                remote_threads.push(thread::spawn(|| {
-                    handle_remote_connection(bank_clone, StreamManager::from_stream(stream))
-                }));
+/// Serves the bank until it is shut down, then saves its data. Returns a
+/// description of anything which stopped the bank from starting
+fn run(config: BankConfig) -> Result<(), String> {
+    // claimed before touching the data, which another bank may be serving
+    let _pid_file = config
+        .pid_file
+        .as_deref()
+        .map(|path| {
+            PidFile::create(path)
+                .map_err(|e| format!("cannot write PID file {}: {e}", path.display()))
+        })
+        .transpose()?;
+
+    let audit = config.server.audit.clone();
+    let loaded = Bank::load(&config.data);
+    audit.record(&Actor::Bank, "start", "", &outcome(&loaded));
+    let bank = loaded.map_err(|e| {
+        format!(
+            "cannot load bank data from {}: {e}",
+            config.data.path().display()
+        )
+    })?;
+    // the new key takes over once the data is safely written with it
+    let data = match config.new_data_key {
+        None => config.data,
+        Some(key) => {
+            let data = DataFile::new(config.data.path().to_path_buf(), Some(key));
+            let saved = bank.save(&data);
+            audit.record(&Actor::Bank, "rotate-data-key", "", &outcome(&saved));
+            saved.map_err(|e| format!("cannot re-encrypt bank data: {e}"))?;
+            println!("Bank data re-encrypted with the new key");
+            data
+        }
+    };
+    let bank: Arc<Bank> = Arc::new(bank);
+
+    // set by the `exit` command or a termination signal
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, shutdown.clone())
+            .map_err(|e| format!("cannot register signal handler: {e}"))?;
+    }
+
+    let console = Arc::new(Console::new(
+        bank.clone(),
+        audit.clone(),
+        config.dual_control_limit,
+    ));
+    let operators = Arc::new(config.operators);
+    let status = format!("Bank ready, serving ATMs on {}", config.listen);
+    let reload = Reload {
+        operators_path: config.operators_path,
+        operators: operators.clone(),
+        atm_keys: config
+            .atm_keys_path
+            .map(|path| (path, config.server.atm_keys.clone())),
+        console_keys: config
+            .console_keys_path
+            .map(|path| (path, config.admin.console_keys.clone())),
+        log_file: config.log_file,
+        audit: audit.clone(),
+        status: status.clone(),
+    };
+    // this thread is never joined: it waits for signals for as long as the bank runs
+    #[cfg(unix)]
+    {
+        let mut hangups =
+            Signals::new([SIGHUP]).map_err(|e| format!("cannot register signal handler: {e}"))?;
+        thread::spawn(move || {
+            for _ in hangups.forever() {
+                reload.run();
             }
+        });
+    }
+    #[cfg(not(unix))]
+    drop(reload);
+
+    // spawn thread to process local commands
+    // this thread is never joined: it may be blocked reading stdin when a signal arrives
+    if !config.headless {
+        let console_clone = console.clone();
+        let operators_clone = operators.clone();
+        let audit_clone = audit.clone();
+        let shutdown_clone = shutdown.clone();
+        thread::spawn(|| {
+            process_local_commands(console_clone, operators_clone, audit_clone, shutdown_clone)
+        });
+    }
+
+    // bind to the configured addresses to listen for admin consoles and atm requests
+    let admin_listener = config
+        .admin_listen
+        .map(|endpoint| {
+            Listener::bind_with_mode(&endpoint, config.socket_mode)
+                .map_err(|e| format!("bank could not bind to {endpoint} for admin consoles: {e}"))
+        })
+        .transpose()?;
+    let listener = Listener::bind_with_mode(&config.listen, config.socket_mode)
+        .map_err(|e| format!("bank could not bind to {}: {e}", config.listen))?;
+
+    // serve remote admin consoles alongside the local command line
+    let admin_server = admin_listener.map(|listener| {
+        let admin_config = config.admin;
+        let shutdown = shutdown.clone();
+        thread::spawn(move || admin::serve(listener, console, operators, &admin_config, shutdown))
+    });
+    if let Some(noise_key) = &config.server.noise_key {
+        // ATMs using the Noise handshake are configured with this key
+        println!("Bank public key: {}", noise_key.public());
+    }
+    // both listeners are bound, so connections are queued from here on
+    println!("{status}");
+    daemon::notify(State::Ready(&status));
+    match config.runtime {
+        Runtime::Threads => server::serve(listener, bank.clone(), &config.server, shutdown),
+        #[cfg(feature = "async")]
+        Runtime::Async { worker_threads } => async_server::serve(
+            listener,
+            bank.clone(),
+            &config.server,
+            worker_threads,
+            shutdown,
+        ),
+    }
+
+    daemon::notify(State::Stopping);
+    if let Some(admin_server) = admin_server {
+        let _ = admin_server.join();
+    }
+
+    // persist state
+    let saved = bank.save(&data);
+    audit.record(&Actor::Bank, "shutdown", "", &outcome(&saved));
+    match saved {
+        Err(e) => eprintln!("Error saving bank data: {e}"),
+        Ok(()) => println!("Bank data saved to {}", data.path().display()),
+    }
+    Ok(())
+}
+
+/// Files the bank reloads on SIGHUP, and the settings loaded from them which
+/// the bank is serving with
+struct Reload {
+    operators_path: PathBuf,
+    operators: Arc<Operators>,
+    /// ATM keys and the file they came from, if ATMs need keys
+    atm_keys: Option<(PathBuf, Arc<AtmKeys>)>,
+    /// Console keys and the file they came from, if any
+    console_keys: Option<(PathBuf, Arc<AtmKeys>)>,
+    log_file: Option<PathBuf>,
+    audit: Arc<AuditLog>,
+    /// What the bank tells its service manager once ready again
+    status: String,
+}
+
+impl Reload {
+    /// Reloads the operators and keys from their files and reopens the log
+    /// file, so they can be changed or rotated without restarting the bank
+    fn run(&self) {
+        daemon::notify(State::Reloading);
+        let reloaded = self.load();
+        self.audit
+            .record(&Actor::Bank, "reload", "", &outcome(&reloaded));
+        match reloaded {
+            Err(e) => eprintln!("Error reloading, kept the previous settings: {e}"),
+            Ok(()) => println!("Reloaded operators and keys"),
         }
+        daemon::notify(State::Ready(&self.status));
     }
 
-    // join threads -> local then remotes
-    local_thread
-        .join()
-        .expect("Join failed: local command thread has panicked");
-    for t in remote_threads {
-        t.join().expect("Join failed: a remote thread has panicked");
+    /// Replaces the operators and keys only once every file has loaded, so a
+    /// mistake in one leaves the bank serving as before
+    fn load(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        if let Some(path) = &self.log_file {
+            daemon::redirect_output(path).map_err(|e| cannot_load(path, e))?;
+        }
+        let operators = Operators::load(&self.operators_path)
+            .map_err(|e| cannot_load(&self.operators_path, e))?;
+        let atm_keys = self.atm_keys.as_ref().map(reload_keys).transpose()?;
+        let console_keys = self.console_keys.as_ref().map(reload_keys).transpose()?;
+
+        self.operators.replace(operators);
+        for ((_, current), keys) in self.atm_keys.iter().zip(atm_keys) {
+            current.replace(keys);
+        }
+        for ((_, current), keys) in self.console_keys.iter().zip(console_keys) {
+            current.replace(keys);
+        }
+        Ok(())
     }
 }
 
-/// Processes commands from stdin. Expected to be run in a thread and provided
-/// a safe copy of an Arc reference to the Bank instance. This function
-/// retreives a lock on the bank after receiving input and utilizes the Bank's
-/// methods for processing requests.
-fn process_local_commands(bank: Arc<Mutex<Bank>>) {
-    // initial prompt
-    println!("\nAvailable commands:\n{}", Bank::get_help_display());
-    print!("\n{}", Bank::get_prompt());
-    io::stdout().flush().unwrap();
+/// Loads keys from their file again. A file which registers none is refused,
+/// since it would let anyone connect without a key
+fn reload_keys((path, current): &(PathBuf, Arc<AtmKeys>)) -> io::Result<AtmKeys> {
+    let keys = AtmKeys::load(path).map_err(|e| cannot_load(path, e))?;
+    if keys.is_open() && !current.is_open() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} registers no keys", path.display()),
+        ));
+    }
+    Ok(keys)
+}
 
-    // user input buffer
-    let mut user_input = String::new();
+/// Names the file an error came from
+fn cannot_load(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("cannot load {}: {e}", path.display()))
+}
 
-    // iteratively read user input
-    while io::stdin()
-        .read_line(&mut user_input)
-        .expect("Failed to read line from stdin")
-        > 0
-    {
-        // remove newline from user input
-        user_input.pop();
+/// Runs the bank's command line on stdin. Expected to be run in a thread.
+/// Each operator logs in, runs the commands their role allows until they log
+/// out, and then hands over to the next operator
+fn process_local_commands(
+    console: Arc<Console>,
+    operators: Arc<Operators>,
+    audit: Arc<AuditLog>,
+    shutdown: Arc<AtomicBool>,
+) {
+    if operators.is_empty() {
+        println!(
+            "\nNo operator accounts exist, so the command line is disabled. Add one \
+             with `bank --add-operator <name> --role supervisor`, then send the bank \
+             SIGHUP to log in from an admin console or restart it to log in here"
+        );
+        return;
+    }
+
+    while let Some(operator) = login(&operators, &audit) {
+        println!("\n{}", cli::get_greeting(&operator));
+        match run_commands(&console, &operator) {
+            // stdin has closed
+            None => return,
+            Some(Next::Shutdown) => {
+                shutdown.store(true, Ordering::SeqCst);
+                return;
+            }
+            Some(_) => println!("Logged out"),
+        }
+    }
+}
 
-        if user_input == "exit" {
-            break;
+/// Asks for an operator's name and password until someone logs in. Returns
+/// `None` once stdin closes
+fn login(operators: &Operators, audit: &AuditLog) -> Option<Operator> {
+    loop {
+        print!("\nOperator: ");
+        io::stdout().flush().unwrap();
+        let mut name = String::new();
+        if io::stdin()
+            .read_line(&mut name)
+            .expect("Failed to read line from stdin")
+            == 0
+        {
+            return None;
         }
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+        let password = cli::read_password("Password: ")?;
 
-        // retreive lock on the bank
-        let mut bank = bank.lock().unwrap();
+        let operator = operators.authenticate(name, &password);
+        let outcome = if operator.is_some() { "ok" } else { "failed" };
+        audit.record(&Actor::Operator(name.to_string()), "login", "", outcome);
+        match operator {
+            None => println!("Login failed"),
+            Some(operator) => return Some(operator),
+        }
+    }
+}
 
-        bank.process_input(&user_input.trim());
+/// Processes an operator's commands from stdin until they log out or shut the
+/// bank down. Returns `None` once stdin closes
+fn run_commands(console: &Console, operator: &Operator) -> Option<Next> {
+    // user input buffer
+    let mut user_input = String::new();
 
-        // reprompt user
-        print!("\n{}", Bank::get_prompt());
+    loop {
+        print!("\n{}", cli::get_prompt());
         io::stdout().flush().unwrap();
         // clear input buffer before next read
         user_input.clear();
+        if io::stdin()
+            .read_line(&mut user_input)
+            .expect("Failed to read line from stdin")
+            == 0
+        {
+            return None;
+        }
+
+        let mut output = String::new();
+        let next = console.process_input(operator, user_input.trim(), &mut output);
+        print!("{output}");
+        match next {
+            Next::Prompt => continue,
+            next => return Some(next),
+        }
     }
 }
 
-/// Handles a remote ATM's requests
-fn handle_remote_connection(bank: Arc<Mutex<Bank>>, mut manager: StreamManager) {
-    // tracks number of communications
-    let mut comm_count: u8 = 0;
+/// Registers an operator with a password typed twice, instead of running the bank
+fn add_operator(config: &BankConfig, name: &str, role: Role) {
+    let password = cli::read_password(&format!("Password for {name}: ")).unwrap_or_default();
+    if password.is_empty() {
+        eprintln!("Error: the password must not be empty");
+        std::process::exit(2);
+    }
+    if cli::read_password(&format!("Repeat password for {name}: ")).as_ref() != Some(&password) {
+        eprintln!("Error: the passwords do not match");
+        std::process::exit(2);
+    }
 
-    loop {
-        // receive response and handle possible errors
-        let response = match manager.receive(&mut comm_count) {
-            Err(ReceiveError::EndOfStream) => return,
-            Err(_) => {
-                // // send stale response and exit
-                // let response = Plaintext::new(&mut comm_count, MessageType::Stale);
-                // manager.send_plaintext(response);
-                return;
-            }
-            Ok(response) => response,
-        };
-
-        let bank = bank.lock().unwrap();
-
-        match response.get_type() {
-            MessageType::AuthUser => {
-                let username = match response.get_user() {
-                    Err(_) => return,
-                    Ok(username) => username,
-                };
-                let pin = match response.get_pin() {
-                    Err(_) => return,
-                    Ok(pin) => pin,
-                };
-                // send auth response indicating success
-                let mut plaintext = Plaintext::new(&mut comm_count, MessageType::AuthResult);
-                plaintext.set_auth_result(bank.attempt_authentication(&username, pin));
-                manager.send_plaintext(plaintext);
-            }
-            MessageType::Balance => {
-                dbg!("balance");
-                let username = match response.get_user() {
-                    Err(_) => return,
-                    Ok(username) => username,
-                };
-                dbg!(&username);
-                let balance = match bank.get_balance(&username) {
-                    Err(_) => return,
-                    Ok(balance) => balance,
-                };
-                dbg!(balance);
-                // send balance back
-                let mut plaintext = Plaintext::new(&mut comm_count, MessageType::Balance);
-                plaintext.set_balance(balance);
-                manager.send_plaintext(plaintext);
-            }
-            MessageType::End => {
-                let plaintext = Plaintext::new(&mut comm_count, MessageType::End);
-                manager.send_plaintext(plaintext);
-            }
-            _ => todo!(),
+    let added = Operators::add(&config.operators_path, name, role, &password);
+    let action = format!("add-operator {role}");
+    config
+        .server
+        .audit
+        .record(&Actor::Bank, &action, name, &outcome(&added));
+    match added {
+        Err(e) => {
+            eprintln!("Error adding operator: {e}");
+            std::process::exit(1);
         }
+        Ok(()) => println!("Added {role} {name} to {}", config.operators_path.display()),
     }
 }
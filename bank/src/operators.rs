@@ -0,0 +1,204 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, ErrorKind, Write},
+    path::Path,
+    str::FromStr,
+    sync::RwLock,
+};
+
+/// Longest operator name
+pub const MAX_OPERATOR_NAME_SIZE: usize = 20;
+
+/// What an operator is employed to do. Each command can be run by some roles
+/// and not others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Serves customers: opens accounts, takes deposits, moves money
+    Teller,
+    /// Oversees tellers: everything a teller can do, plus approving sensitive
+    /// actions and running the bank
+    Supervisor,
+    /// Reviews the books: may look but not touch
+    Auditor,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "teller" => Ok(Self::Teller),
+            "supervisor" => Ok(Self::Supervisor),
+            "auditor" => Ok(Self::Auditor),
+            _ => Err(format!(
+                "`{s}` is not a role: expected teller, supervisor or auditor"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Teller => write!(f, "teller"),
+            Self::Supervisor => write!(f, "supervisor"),
+            Self::Auditor => write!(f, "auditor"),
+        }
+    }
+}
+
+/// An operator who has logged in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operator {
+    pub name: String,
+    pub role: Role,
+}
+
+/// A registered operator and the hash of their password
+#[derive(Debug, Clone)]
+struct Account {
+    role: Role,
+    /// Argon2id hash of the password, as a PHC string
+    password_hash: String,
+}
+
+/// Every operator allowed to log in to the bank's command line. The accounts
+/// can be replaced while the bank is running, and apply from the next login
+#[derive(Debug, Default)]
+pub struct Operators {
+    accounts: RwLock<HashMap<String, Account>>,
+    /// Hash unknown names are checked against, so they take the same work to
+    /// turn away as a wrong password
+    decoy_hash: String,
+}
+
+impl Operators {
+    /// Loads the operators from the given file. A missing file has none
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut accounts = HashMap::new();
+        let operators = |accounts| Self {
+            accounts: RwLock::new(accounts),
+            decoy_hash: hash_password(""),
+        };
+        let contents = match fs::read_to_string(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(operators(accounts)),
+            Err(e) => return Err(e),
+            Ok(contents) => contents,
+        };
+
+        // each line registers a single operator: `<name> <role> <password-hash>`
+        for (line_num, line) in contents.lines().enumerate() {
+            let invalid_record = |reason: &str| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid operator record on line {}: {reason}", line_num + 1),
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(role), Some(password_hash), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid_record("malformed"));
+            };
+            if !valid_operator_name(name) {
+                return Err(invalid_record("invalid name"));
+            }
+            let role = role.parse().map_err(|e: String| invalid_record(&e))?;
+            if PasswordHash::new(password_hash).is_err() {
+                return Err(invalid_record("invalid password hash"));
+            }
+            let account = Account {
+                role,
+                password_hash: password_hash.to_string(),
+            };
+            if accounts.insert(name.to_string(), account).is_some() {
+                return Err(invalid_record("duplicate operator"));
+            }
+        }
+        Ok(operators(accounts))
+    }
+
+    /// Registers a new operator in the given file, hashing their password.
+    /// Fails if the name is invalid or already taken
+    pub fn add(path: &Path, name: &str, role: Role, password: &str) -> io::Result<()> {
+        if !valid_operator_name(name) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "operator names are up to {MAX_OPERATOR_NAME_SIZE} letters, digits, `-` or `_`"
+                ),
+            ));
+        }
+        if Self::load(path)?
+            .accounts
+            .into_inner()
+            .unwrap()
+            .contains_key(name)
+        {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("operator {name} already exists"),
+            ));
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{name} {role} {}", hash_password(password))?;
+        file.sync_all()
+    }
+
+    /// Replaces every account with those of `other`, such as accounts loaded
+    /// afresh from the operators file. Operators already logged in stay so
+    pub fn replace(&self, other: Operators) {
+        *self.accounts.write().unwrap() = other.accounts.into_inner().unwrap();
+    }
+
+    /// Returns true if no operator can log in
+    pub fn is_empty(&self) -> bool {
+        self.accounts.read().unwrap().is_empty()
+    }
+
+    /// Returns the operator with the given name if the password is theirs
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<Operator> {
+        // the lock is not held while hashing, so a reload is not held up
+        let account = self.accounts.read().unwrap().get(name).cloned();
+        // an unknown name is checked all the same, so it takes as long
+        let password_hash = account
+            .as_ref()
+            .map_or(&self.decoy_hash, |account| &account.password_hash);
+        let matches = PasswordHash::new(password_hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        });
+        let account = account.filter(|_| matches)?;
+        Some(Operator {
+            name: name.to_string(),
+            role: account.role,
+        })
+    }
+}
+
+/// Hashes a password with Argon2id and a random salt
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Error hashing operator password")
+        .to_string()
+}
+
+/// Checks an operator name is up to 20 letters, digits, `-` or `_`
+fn valid_operator_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_OPERATOR_NAME_SIZE
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -0,0 +1,194 @@
+use self::errors::ConnectionError;
+use common::transport::PeerHost;
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Default maximum number of ATM connections served at once
+pub const DEFAULT_MAX_CONNECTIONS: usize = 128;
+/// Default maximum number of ATM connections served at once from a single address
+pub const DEFAULT_MAX_CONNECTIONS_PER_PEER: usize = 64;
+
+/// How often `WorkerPool::shutdown` checks whether workers have finished
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Limits on the number of ATM connections the bank serves at once
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    /// Maximum connections in total. Also the number of pool workers
+    pub max_connections: usize,
+    /// Maximum connections from any one IP address, or from local peers combined
+    pub max_per_peer: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_per_peer: DEFAULT_MAX_CONNECTIONS_PER_PEER,
+        }
+    }
+}
+
+/// Fixed set of threads which run jobs handed to the pool
+pub struct WorkerPool {
+    sender: Sender<Job>,
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Creates a pool and spawns `size` worker threads
+    pub fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size).map(|_| spawn_worker(receiver.clone())).collect();
+        Self {
+            sender,
+            receiver,
+            workers,
+        }
+    }
+
+    /// Queues a job to be run by the next idle worker
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.sender
+            .send(Box::new(job))
+            .expect("Error: all pool workers have exited");
+    }
+
+    /// Replaces workers which have exited. A job that panics takes its worker
+    /// down with it, so without reaping the pool would slowly shrink.
+    /// Returns the number of workers replaced
+    pub fn reap(&mut self) -> usize {
+        let mut reaped = 0;
+        for worker in self.workers.iter_mut() {
+            if worker.is_finished() {
+                *worker = spawn_worker(self.receiver.clone());
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+
+    /// Stops handing out jobs and waits up to `timeout` for workers to finish
+    /// their current job. Returns the number of workers still busy
+    pub fn shutdown(self, timeout: Duration) -> usize {
+        // workers exit once the channel is closed and drained
+        drop(self.sender);
+
+        let deadline = Instant::now() + timeout;
+        while self.workers.iter().any(|w| !w.is_finished()) && Instant::now() < deadline {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        self.workers.iter().filter(|w| !w.is_finished()).count()
+    }
+}
+
+/// Spawns a thread which runs jobs until the pool's channel closes
+fn spawn_worker(receiver: Arc<Mutex<Receiver<Job>>>) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        // the lock is released before the job runs
+        let job = match receiver.lock() {
+            Err(_) => return,
+            Ok(receiver) => receiver.recv(),
+        };
+        match job {
+            Err(_) => return,
+            Ok(job) => job(),
+        }
+    })
+}
+
+/// Counts open connections so new ones can be checked against `ConnectionLimits`
+pub struct ConnectionTracker {
+    limits: ConnectionLimits,
+    open: Arc<Mutex<OpenConnections>>,
+}
+
+/// Open connection counts shared between the tracker and its permits
+#[derive(Default)]
+struct OpenConnections {
+    total: usize,
+    per_peer: HashMap<PeerHost, usize>,
+}
+
+impl ConnectionTracker {
+    /// Creates a tracker enforcing the given limits
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            open: Arc::new(Mutex::new(OpenConnections::default())),
+        }
+    }
+
+    /// Registers a new connection from `peer` if the limits allow it.
+    /// The connection counts as open until the returned permit is dropped
+    pub fn try_acquire(&self, peer: PeerHost) -> Result<ConnectionPermit, ConnectionError> {
+        let mut open = self.open.lock().unwrap();
+        if open.total >= self.limits.max_connections {
+            return Err(ConnectionError::TooManyConnections(
+                self.limits.max_connections,
+            ));
+        }
+        let peer_count = open.per_peer.entry(peer).or_insert(0);
+        if *peer_count >= self.limits.max_per_peer {
+            return Err(ConnectionError::TooManyFromPeer(
+                peer,
+                self.limits.max_per_peer,
+            ));
+        }
+        *peer_count += 1;
+        open.total += 1;
+
+        Ok(ConnectionPermit {
+            peer,
+            open: self.open.clone(),
+        })
+    }
+}
+
+/// Marks a connection as open. Dropping the permit closes it
+pub struct ConnectionPermit {
+    peer: PeerHost,
+    open: Arc<Mutex<OpenConnections>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        // a poisoned lock still holds valid counts
+        let mut open = self.open.lock().unwrap_or_else(|p| p.into_inner());
+        open.total -= 1;
+        if let Some(count) = open.per_peer.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                open.per_peer.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// Error types related to the connection pool
+pub mod errors {
+    use common::transport::PeerHost;
+    use thiserror::Error;
+
+    /// Reasons a new connection is turned away
+    #[derive(Debug, Error)]
+    pub enum ConnectionError {
+        /// The bank is serving its maximum number of connections
+        #[error("The bank is already serving the maximum of {0} connections.")]
+        TooManyConnections(usize),
+        /// The connecting address already has its maximum number of connections
+        #[error("{0} already has the maximum of {1} connections.")]
+        TooManyFromPeer(PeerHost, usize),
+    }
+}
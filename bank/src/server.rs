@@ -0,0 +1,243 @@
+use crate::{
+    audit::{Actor, AuditLog},
+    bank::Bank,
+    pool::{ConnectionLimits, ConnectionTracker, WorkerPool},
+    session::{Connection, Step},
+};
+use common::{
+    crypto::{busy_hello, AtmKeys, BankSecret},
+    io::{StreamManager, Timeouts, DEFAULT_IDLE_TIMEOUT, DEFAULT_REQUEST_TIMEOUT},
+    message::Message,
+    transport::{Listener, Transport},
+};
+use std::{
+    fmt,
+    io::{ErrorKind, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often idle loops check whether a shutdown has been requested
+pub const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long in-flight ATM transactions are given to complete during shutdown
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Least time the bank takes to answer each class of request. A reply ready
+/// sooner is held back, so how long a reply takes reveals neither which
+/// request it answers nor how it turned out. Zero sends replies as soon as
+/// they are ready
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResponseTimes {
+    /// Logins, whether they succeed or not
+    pub auth: Duration,
+    /// Balance requests, and heartbeats which stand in for them
+    pub query: Duration,
+    /// Withdrawals, deposits and transfers
+    pub transaction: Duration,
+}
+
+impl ResponseTimes {
+    /// Returns the least time the bank takes to answer the given request
+    pub fn minimum(&self, request: &Message) -> Duration {
+        match request {
+            Message::AuthRequest(_) => self.auth,
+            Message::BalanceRequest(_) | Message::Heartbeat(_) => self.query,
+            Message::WithdrawRequest(_)
+            | Message::DepositRequest(_)
+            | Message::TransferRequest(_) => self.transaction,
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// Timeouts applied to every ATM session
+#[derive(Debug, Clone, Copy)]
+pub struct SessionTimeouts {
+    /// How long a session may go without a request before the bank ends it
+    pub idle: Duration,
+    /// How long the bank waits for a reply to be written before dropping the ATM
+    pub write: Duration,
+    pub min_response: ResponseTimes,
+}
+
+impl Default for SessionTimeouts {
+    fn default() -> Self {
+        Self {
+            idle: DEFAULT_IDLE_TIMEOUT,
+            write: DEFAULT_REQUEST_TIMEOUT,
+            min_response: ResponseTimes::default(),
+        }
+    }
+}
+
+/// Settings for serving ATM connections, whichever runtime serves them
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    pub limits: ConnectionLimits,
+    pub timeouts: SessionTimeouts,
+    /// Keys of the ATMs allowed to connect. Any ATM may connect without a
+    /// key if none are registered
+    pub atm_keys: Arc<AtmKeys>,
+    /// Bank's static Noise key. The Noise handshake is only offered if set
+    pub noise_key: Option<BankSecret>,
+    /// Log every connection and request is recorded in
+    pub audit: Arc<AuditLog>,
+}
+
+/// Names an ATM in the audit log by its id and address
+pub fn atm(id: &str, peer: &impl fmt::Display) -> Actor {
+    Actor::Atm {
+        id: id.to_string(),
+        peer: peer.to_string(),
+    }
+}
+
+/// Serves ATM connections with a pool of blocking worker threads, one
+/// connection per worker, until a shutdown is requested
+pub fn serve(
+    listener: Listener,
+    bank: Arc<Bank>,
+    config: &ServerConfig,
+    shutdown: Arc<AtomicBool>,
+) {
+    // poll for connections so shutdown requests are noticed
+    listener
+        .set_nonblocking(true)
+        .expect("Error: could not set listener to non-blocking");
+
+    // one worker per allowed connection: accepted connections never wait for a worker
+    let tracker = ConnectionTracker::new(config.limits);
+    let mut pool = WorkerPool::new(config.limits.max_connections);
+    let session_timeouts = config.timeouts;
+    // ATMs are given as long to complete the handshake as to read a reply
+    let handshake_timeouts = Timeouts {
+        read: Some(config.timeouts.write),
+        write: Some(config.timeouts.write),
+    };
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let reaped = pool.reap();
+        if reaped > 0 {
+            eprintln!("Warning: replaced {reaped} worker(s) lost to a panicking connection");
+        }
+
+        match listener.accept() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(SHUTDOWN_POLL_INTERVAL),
+            Err(e) => eprintln!("Error getting stream from listener: {}", e),
+            Ok((stream, peer)) => {
+                let permit = match tracker.try_acquire(peer.host()) {
+                    Err(e) => {
+                        eprintln!("Rejected connection from {peer}: {e}");
+                        let outcome = format!("rejected: {e}");
+                        config
+                            .audit
+                            .record(&atm("", &peer), "connect", "", &outcome);
+                        reject_connection(stream);
+                        continue;
+                    }
+                    Ok(permit) => permit,
+                };
+                // hand this connection to a worker, which completes the handshake
+                let bank_clone = bank.clone();
+                let shutdown_clone = shutdown.clone();
+                let atm_keys = config.atm_keys.clone();
+                let noise_key = config.noise_key.clone();
+                let audit = config.audit.clone();
+                pool.execute(move || {
+                    // the connection counts against the limits until handled
+                    let _permit = permit;
+                    let accepted = StreamManager::accept(
+                        stream,
+                        handshake_timeouts,
+                        &atm_keys,
+                        noise_key.as_ref(),
+                    );
+                    let manager = match accepted {
+                        Err(e) => {
+                            eprintln!("Refused connection from {peer}: {e}");
+                            audit.record(&atm("", &peer), "connect", "", &format!("refused: {e}"));
+                            return;
+                        }
+                        Ok(manager) => manager,
+                    };
+                    let atm = atm(manager.atm_id(), &peer);
+                    audit.record(&atm, "connect", "", "ok");
+                    // wake up periodically to check for shutdown and idle sessions
+                    if let Err(e) = manager.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL)) {
+                        eprintln!("Error setting up connection from {peer}: {e}");
+                        return;
+                    }
+                    handle_remote_connection(
+                        bank_clone,
+                        audit,
+                        atm,
+                        manager,
+                        shutdown_clone,
+                        session_timeouts,
+                    )
+                });
+            }
+        }
+    }
+
+    // stop accepting new connections
+    drop(listener);
+    println!("\nShutting down bank. Waiting for ATM sessions to close...");
+
+    // give workers a chance to finish in-flight transactions
+    let abandoned = pool.shutdown(SHUTDOWN_GRACE_PERIOD);
+    if abandoned > 0 {
+        eprintln!("Warning: {abandoned} ATM connection(s) did not close in time");
+    }
+}
+
+/// Tells an ATM the bank is too busy to serve it, then closes the connection
+pub(crate) fn reject_connection(mut stream: Box<dyn Transport>) {
+    // the ATM may already have hung up, in which case there is no one to tell
+    let _ = stream.write_all(&busy_hello());
+}
+
+/// Handles a remote ATM's requests on the calling thread until the ATM
+/// disconnects, its session sits idle for too long, or the bank shuts down.
+/// `serve` runs this for each connection once its handshake is complete
+pub fn handle_remote_connection(
+    bank: Arc<Bank>,
+    audit: Arc<AuditLog>,
+    atm: Actor,
+    mut manager: StreamManager,
+    shutdown: Arc<AtomicBool>,
+    timeouts: SessionTimeouts,
+) {
+    let mut connection = Connection::new(atm, timeouts);
+    loop {
+        if let Some(notice) = connection.end_notice(&shutdown) {
+            let _ = manager.send_message(notice, &mut connection.comm_count);
+            return;
+        }
+        let received = manager.receive(&mut connection.comm_count);
+        let request = match connection.receive(received) {
+            Step::Wait => continue,
+            Step::Close => return,
+            Step::Handle(request) => request,
+        };
+        let Some(reply) = connection.handle(&bank, &audit, &request) else {
+            return;
+        };
+        thread::sleep(
+            connection
+                .reply_due(&request)
+                .saturating_duration_since(Instant::now()),
+        );
+        // an ATM which stops reading replies is treated as gone
+        if manager
+            .send_message(reply, &mut connection.comm_count)
+            .is_err()
+        {
+            return;
+        }
+    }
+}
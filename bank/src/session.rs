@@ -0,0 +1,233 @@
+use crate::{
+    audit::{outcome, Actor, AuditLog},
+    bank::{errors::BankError, Bank},
+    server::SessionTimeouts,
+};
+use common::{
+    io::errors::ReceiveError,
+    message::{
+        Amount, AuthResult, BalanceResponse, EndReason, EndSession, Heartbeat, Message,
+        TransactionResult, TransactionStatus,
+    },
+};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+/// State the bank keeps for each ATM connection
+#[derive(Debug)]
+pub struct Session {
+    /// ATM on the other end, which the audit log credits requests to
+    atm: Actor,
+    /// User authenticated on this connection, if any. Account requests act
+    /// on this user's account
+    user: Option<String>,
+}
+
+impl Session {
+    /// Starts a session for the given ATM with no user logged in
+    pub fn new(atm: Actor) -> Self {
+        Self { atm, user: None }
+    }
+
+    /// Returns the logged in user. A request which needs one when there is
+    /// none is recorded as rejected
+    fn user(&self, audit: &AuditLog, action: &str) -> Option<&str> {
+        if self.user.is_none() {
+            audit.record(&self.atm, action, "", "rejected: no user logged in");
+        }
+        self.user.as_deref()
+    }
+}
+
+/// Everything the bank tracks about an ATM connection between requests.
+/// Decides when the connection ends and when each reply is due, so every
+/// server runtime only has to move messages on and off its stream
+#[derive(Debug)]
+pub struct Connection {
+    session: Session,
+    timeouts: SessionTimeouts,
+    /// Number of messages sent and received so far
+    pub comm_count: u32,
+    /// When the ATM last made a request other than a heartbeat
+    last_request: Instant,
+    /// When the request being handled arrived
+    received: Instant,
+}
+
+/// What a connection does after waiting for a request
+pub enum Step {
+    /// Nothing arrived yet: check the connection and wait again
+    Wait,
+    /// The ATM is gone or broke the protocol: close the connection
+    Close,
+    /// Handle this request and reply to it
+    Handle(Message),
+}
+
+impl Connection {
+    /// Starts tracking a connection from the given ATM whose handshake is complete
+    pub fn new(atm: Actor, timeouts: SessionTimeouts) -> Self {
+        let now = Instant::now();
+        Self {
+            session: Session::new(atm),
+            timeouts,
+            comm_count: 0,
+            last_request: now,
+            received: now,
+        }
+    }
+
+    /// Returns the notice to send the ATM before closing the connection, if
+    /// the bank is shutting down or the session has sat idle for too long
+    pub fn end_notice(&self, shutdown: &AtomicBool) -> Option<Message> {
+        // in-flight requests have been answered: notify the ATM before closing
+        if shutdown.load(Ordering::SeqCst) {
+            return Some(end_notice(EndReason::Maintenance));
+        }
+        // log out abandoned sessions
+        if self.last_request.elapsed() >= self.timeouts.idle {
+            return Some(end_notice(EndReason::Idle));
+        }
+        None
+    }
+
+    /// Decides what to do with the outcome of waiting for a request
+    pub fn receive(&mut self, received: Result<Message, ReceiveError>) -> Step {
+        let request = match received {
+            Err(ReceiveError::TimedOut) => return Step::Wait,
+            Err(_) => return Step::Close,
+            Ok(request) => request,
+        };
+        self.received = Instant::now();
+        // heartbeats are not a customer at the ATM, so they leave the session idle
+        if !matches!(request, Message::Heartbeat(_)) {
+            self.last_request = self.received;
+        }
+        Step::Handle(request)
+    }
+
+    /// Builds the reply to a request, as `handle_request` does
+    pub fn handle(&mut self, bank: &Bank, audit: &AuditLog, request: &Message) -> Option<Message> {
+        handle_request(bank, audit, &mut self.session, request)
+    }
+
+    /// Returns when the reply to the request last received may be sent. Replies
+    /// are held back until their class of request's minimum time is up
+    pub fn reply_due(&self, request: &Message) -> Instant {
+        self.received + self.timeouts.min_response.minimum(request)
+    }
+}
+
+/// Builds the bank's reply to a single ATM request. Shared by every server
+/// runtime so they speak exactly the same protocol. Returns `None` if the
+/// request is malformed, or needs an authenticated user when there is none,
+/// and the connection should be closed.
+///
+/// Any account locks are released before this returns, so the reply is never
+/// sent while an account is locked. Every request but a heartbeat is recorded
+/// in the audit log
+pub fn handle_request(
+    bank: &Bank,
+    audit: &AuditLog,
+    session: &mut Session,
+    request: &Message,
+) -> Option<Message> {
+    match request {
+        Message::AuthRequest(request) => {
+            let username = request.username.to_string();
+            let authenticated = bank.attempt_authentication(&username, request.pin.value());
+            let outcome = if authenticated { "ok" } else { "failed" };
+            audit.record(&session.atm, "login", &username, outcome);
+            session.user = authenticated.then_some(username);
+            // send auth response indicating success
+            Some(AuthResult { authenticated }.into())
+        }
+        Message::BalanceRequest(request) => {
+            // ATMs may only ask after the user they authenticated
+            let username = session.user(audit, "balance")?;
+            if username != request.username.as_str() {
+                let outcome = "rejected: not the logged in user";
+                audit.record(&session.atm, "balance", request.username.as_str(), outcome);
+                return None;
+            }
+            let balance = bank.balance(username);
+            audit.record(&session.atm, "balance", username, &outcome(&balance));
+            // send balance back
+            Some(
+                BalanceResponse {
+                    balance: balance.ok()?,
+                }
+                .into(),
+            )
+        }
+        Message::WithdrawRequest(request) => {
+            let amount = request.amount;
+            let action = format!("withdraw {amount}");
+            let username = session.user(audit, &action)?;
+            let result = bank.withdraw(username, amount);
+            audit.record(&session.atm, &action, username, &outcome(&result));
+            transaction_result(bank, username, result)
+        }
+        Message::DepositRequest(request) => {
+            let amount = request.amount;
+            let action = format!("deposit {amount}");
+            let username = session.user(audit, &action)?;
+            let result = bank.deposit(username, amount);
+            audit.record(&session.atm, &action, username, &outcome(&result));
+            transaction_result(bank, username, result)
+        }
+        Message::TransferRequest(request) => {
+            let amount = request.amount;
+            let recipient = request.recipient.as_str();
+            let action = format!("transfer {amount} to {recipient}");
+            let username = session.user(audit, &action)?;
+            let result = bank.transfer(username, recipient, amount);
+            audit.record(&session.atm, &action, username, &outcome(&result));
+            transaction_result(bank, username, result.and_then(|()| bank.balance(username)))
+        }
+        Message::EndSession(_) => {
+            if let Some(username) = session.user.take() {
+                audit.record(&session.atm, "logout", &username, "ok");
+            }
+            Some(end_notice(EndReason::Requested))
+        }
+        // cover traffic: answered in kind, and otherwise ignored
+        Message::Heartbeat(_) => Some(Heartbeat {}.into()),
+        // messages only the bank sends
+        Message::AuthResult(_) | Message::BalanceResponse(_) | Message::TransactionResult(_) => {
+            None
+        }
+    }
+}
+
+/// Builds the End message the bank sends when it closes a session itself
+pub fn end_notice(reason: EndReason) -> Message {
+    EndSession { reason }.into()
+}
+
+/// Builds the reply to a withdrawal, deposit or transfer from its outcome
+fn transaction_result(
+    bank: &Bank,
+    username: &str,
+    result: Result<Amount, BankError>,
+) -> Option<Message> {
+    let (status, balance) = match result {
+        Ok(balance) => (TransactionStatus::Approved, balance),
+        Err(e) => (transaction_status(&e), bank.balance(username).ok()?),
+    };
+    Some(TransactionResult { status, balance }.into())
+}
+
+/// Maps a refused bank operation onto the status reported to the ATM
+fn transaction_status(e: &BankError) -> TransactionStatus {
+    match e {
+        BankError::InsufficientFunds => TransactionStatus::InsufficientFunds,
+        BankError::SameAccount => TransactionStatus::SameAccount,
+        BankError::Overflow => TransactionStatus::Overflow,
+        // the session's own account exists, so an unknown user is the recipient
+        BankError::UnknownUser(_) => TransactionStatus::UnknownRecipient,
+        _ => TransactionStatus::InvalidAmount,
+    }
+}
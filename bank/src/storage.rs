@@ -0,0 +1,242 @@
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use common::hex::{from_hex, to_hex};
+use rand_core::{OsRng, RngCore};
+use std::{
+    fmt, fs,
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+/// Leads the header of an encrypted data file, telling it apart from a
+/// plaintext one
+const MAGIC: &str = "atm-bank-db";
+/// Version of the encrypted format
+const VERSION: &str = "1";
+/// Size of the random salt each encrypted file is written with
+const SALT_SIZE: usize = 16;
+/// Size of the random nonce in front of every sealed value
+const NONCE_SIZE: usize = 24;
+/// Size of a data key
+const DATA_KEY_SIZE: usize = 32;
+/// Position the record count is sealed at. Records follow from 1
+const COUNT_INDEX: u64 = 0;
+
+/// Secret the bank's data is encrypted with
+#[derive(Clone)]
+pub enum DataKey {
+    /// A random key, kept in a key file as 64 hex digits
+    Key([u8; DATA_KEY_SIZE]),
+    /// A passphrase supplied by an operator, stretched into a key with Argon2id
+    Passphrase(String),
+}
+
+impl DataKey {
+    /// Reads a key file holding a key written as 64 hex digits
+    pub fn load(path: &Path) -> io::Result<Self> {
+        from_hex(fs::read_to_string(path)?.trim())
+            .and_then(|key| key.try_into().ok())
+            .map(Self::Key)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "key must be 64 hex digits"))
+    }
+
+    /// Derives the cipher for a file written with the given salt
+    fn cipher(&self, salt: &[u8]) -> io::Result<XChaCha20Poly1305> {
+        let mut key = [0u8; DATA_KEY_SIZE];
+        match self {
+            Self::Key(raw) => key = *raw,
+            Self::Passphrase(passphrase) => Argon2::default()
+                .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?,
+        }
+        Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+// keys are kept out of logs and error messages
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Key(_) => write!(f, "DataKey::Key(..)"),
+            Self::Passphrase(_) => write!(f, "DataKey::Passphrase(..)"),
+        }
+    }
+}
+
+/// The file user records are loaded from and persisted to, along with the key
+/// they are encrypted with, if any.
+///
+/// A plaintext file holds the records as they are, one per line. An encrypted
+/// file starts with a header line, `atm-bank-db 1 <salt> <count>`, followed by
+/// one line per record. The count and every record are sealed with
+/// XChaCha20-Poly1305 and written as hex, bound to the file's salt and their
+/// position, so records cannot be dropped, reordered or swapped in from
+/// another copy of the file without the bank noticing
+#[derive(Debug, Clone)]
+pub struct DataFile {
+    path: PathBuf,
+    key: Option<DataKey>,
+}
+
+impl DataFile {
+    /// Describes the data file at the given path. Records are kept in plaintext
+    /// unless a key is given
+    pub fn new(path: PathBuf, key: Option<DataKey>) -> Self {
+        Self { path, key }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads every record from the file. A missing file holds no records.
+    /// Fails if the file is encrypted with another key, has been tampered
+    /// with, or is not encrypted the way this file is configured
+    pub fn read(&self) -> io::Result<Vec<String>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+            Ok(contents) => contents,
+        };
+        let invalid = |reason: &str| io::Error::new(ErrorKind::InvalidData, reason.to_string());
+
+        let mut lines = contents.lines();
+        let header = match lines.next() {
+            None => return Ok(Vec::new()),
+            Some(header) => header,
+        };
+        let encrypted = header.split_whitespace().next() == Some(MAGIC);
+        let key = match (&self.key, encrypted) {
+            (None, false) => return Ok(contents.lines().map(str::to_string).collect()),
+            (None, true) => return Err(invalid("bank data is encrypted, but no key was given")),
+            (Some(_), false) => {
+                return Err(invalid(
+                    "bank data is not encrypted; rotate to a key to encrypt it",
+                ))
+            }
+            (Some(key), true) => key,
+        };
+
+        // header: `atm-bank-db <version> <salt> <sealed record count>`
+        let malformed = || invalid("invalid header on line 1: malformed");
+        let mut fields = header.split_whitespace().skip(1);
+        if fields.next() != Some(VERSION) {
+            return Err(invalid("invalid header on line 1: unsupported version"));
+        }
+        let salt = fields
+            .next()
+            .and_then(from_hex)
+            .filter(|salt| salt.len() == SALT_SIZE)
+            .ok_or_else(malformed)?;
+        let sealed_count = fields.next().and_then(from_hex).ok_or_else(malformed)?;
+        let cipher = key.cipher(&salt)?;
+        let count = open(&cipher, &salt, COUNT_INDEX, &sealed_count)
+            .and_then(|count| count.try_into().ok())
+            .map(u64::from_be_bytes)
+            .ok_or_else(|| invalid("bank data cannot be decrypted with this key"))?;
+
+        let mut records = Vec::new();
+        for (index, line) in (COUNT_INDEX + 1..).zip(lines) {
+            let record = from_hex(line)
+                .and_then(|sealed| open(&cipher, &salt, index, &sealed))
+                .and_then(|record| String::from_utf8(record).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "invalid user record on line {}: cannot be decrypted",
+                            index + 1
+                        ),
+                    )
+                })?;
+            records.push(record);
+        }
+        if records.len() as u64 != count {
+            return Err(invalid("bank data has had records added or removed"));
+        }
+        Ok(records)
+    }
+
+    /// Writes the given records to the file, encrypted if the file has a key.
+    /// The file is replaced atomically so a crash mid-write cannot corrupt
+    /// existing state
+    pub fn write(&self, records: &[String]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        match &self.key {
+            None => {
+                for record in records {
+                    writeln!(file, "{record}")?;
+                }
+            }
+            Some(key) => {
+                // a fresh salt makes every write a distinct file
+                let mut salt = [0u8; SALT_SIZE];
+                OsRng.fill_bytes(&mut salt);
+                let cipher = key.cipher(&salt)?;
+                let count = (records.len() as u64).to_be_bytes();
+                writeln!(
+                    file,
+                    "{MAGIC} {VERSION} {} {}",
+                    to_hex(&salt),
+                    to_hex(&seal(&cipher, &salt, COUNT_INDEX, &count)),
+                )?;
+                for (index, record) in (COUNT_INDEX + 1..).zip(records) {
+                    writeln!(
+                        file,
+                        "{}",
+                        to_hex(&seal(&cipher, &salt, index, record.as_bytes()))
+                    )?;
+                }
+            }
+        }
+        file.sync_all()?;
+        fs::rename(tmp_path, &self.path)
+    }
+}
+
+//
+// helpers
+
+/// Binds a sealed value to the file it was written to and its place in it
+fn associated_data(salt: &[u8], index: u64) -> Vec<u8> {
+    [MAGIC.as_bytes(), salt, &index.to_be_bytes()].concat()
+}
+
+/// Encrypts a value, returning the nonce, ciphertext and tag
+fn seal(cipher: &XChaCha20Poly1305, salt: &[u8], index: u64, value: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let aad = associated_data(salt, index);
+    let sealed = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: value,
+                aad: &aad,
+            },
+        )
+        .expect("Error encrypting bank data");
+    [&nonce[..], &sealed].concat()
+}
+
+/// Decrypts a value sealed by `seal`, if it is intact and belongs at this place
+fn open(cipher: &XChaCha20Poly1305, salt: &[u8], index: u64, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    let aad = associated_data(salt, index);
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        )
+        .ok()
+}
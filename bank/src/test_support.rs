@@ -0,0 +1,181 @@
+use crate::{
+    audit::AuditKey,
+    bank::Bank,
+    server::{self, ServerConfig},
+};
+use common::{
+    client::{errors::ClientError, BankClient},
+    crypto::AtmCredentials,
+    io::{StreamManager, Timeouts},
+    message::Amount,
+    transport::{Endpoint, Listener, MemoryConnector, Transport},
+};
+use std::{
+    env, fs,
+    net::TcpListener,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// How clients reach a test bank
+enum Connector {
+    Endpoint(Endpoint),
+    Memory(MemoryConnector),
+}
+
+/// A bank server running on a background thread, for tests.
+///
+/// Starts with no accounts: create them through `bank()`. Each test bank
+/// listens on its own ephemeral port, or only in memory, so tests can run in
+/// parallel. The server is shut down when this is dropped
+pub struct TestBank {
+    bank: Arc<Bank>,
+    connector: Connector,
+    shutdown: Arc<AtomicBool>,
+    server: Option<JoinHandle<()>>,
+}
+
+impl TestBank {
+    /// Starts a bank with default settings on an ephemeral TCP port
+    pub fn start() -> Self {
+        Self::start_with(ServerConfig::default())
+    }
+    /// Starts a bank with the given settings on an ephemeral TCP port
+    pub fn start_with(config: ServerConfig) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Error binding test bank");
+        let addr = listener
+            .local_addr()
+            .expect("Error reading test bank address");
+        Self::serve(
+            Listener::Tcp(listener),
+            Connector::Endpoint(Endpoint::Tcp(addr)),
+            config,
+        )
+    }
+    /// Starts a bank with the given settings which can only be reached in memory
+    pub fn start_in_memory(config: ServerConfig) -> Self {
+        let (listener, connector) = Listener::memory();
+        Self::serve(listener, Connector::Memory(connector), config)
+    }
+    /// Serves the bank from a background thread
+    fn serve(listener: Listener, connector: Connector, config: ServerConfig) -> Self {
+        let bank = Arc::new(Bank::new());
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let bank_clone = bank.clone();
+        let shutdown_clone = shutdown.clone();
+        let server =
+            thread::spawn(move || server::serve(listener, bank_clone, &config, shutdown_clone));
+        Self {
+            bank,
+            connector,
+            shutdown,
+            server: Some(server),
+        }
+    }
+
+    //
+    // accessors
+
+    /// Returns the bank being served, for setting up and inspecting accounts
+    pub fn bank(&self) -> &Bank {
+        &self.bank
+    }
+    /// Returns the address the bank listens on, or `None` for an in-memory bank
+    pub fn endpoint(&self) -> Option<&Endpoint> {
+        match &self.connector {
+            Connector::Endpoint(endpoint) => Some(endpoint),
+            Connector::Memory(_) => None,
+        }
+    }
+
+    //
+    // connections
+
+    /// Opens a raw channel to the bank, for tests of behaviour on the wire
+    pub fn connect(&self) -> StreamManager {
+        StreamManager::open(
+            self.stream(),
+            Timeouts::default(),
+            &AtmCredentials::default(),
+        )
+        .expect("Error connecting to test bank")
+    }
+    /// Opens a client connection to the bank
+    pub fn client(&self) -> BankClient {
+        self.client_as(&AtmCredentials::default())
+            .expect("Error connecting to test bank")
+    }
+    /// Opens a client connection to the bank as the ATM with the given credentials
+    pub fn client_as(&self, credentials: &AtmCredentials) -> Result<BankClient, ClientError> {
+        BankClient::open(self.stream(), Timeouts::default(), credentials)
+    }
+    /// Opens a connection to the bank, before any handshake
+    fn stream(&self) -> Box<dyn Transport> {
+        match &self.connector {
+            Connector::Endpoint(endpoint) => endpoint.connect(),
+            Connector::Memory(connector) => connector
+                .connect()
+                .map(|stream| Box::new(stream) as Box<dyn Transport>),
+        }
+        .expect("Error connecting to test bank")
+    }
+
+    /// Asks the server to shut down without waiting for it. Connected clients
+    /// are sent an End message
+    pub fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for TestBank {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(server) = self.server.take() {
+            let _ = server.join();
+        }
+    }
+}
+
+/// An empty directory for one test's files, removed along with everything
+/// in it when dropped, even if the test fails
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Creates an empty directory named after the test. Tests in the same
+    /// binary must use different names
+    pub fn new(test: &str) -> Self {
+        let dir = env::temp_dir().join(format!("bank-{test}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Error creating test directory");
+        Self(dir)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An amount of dollars, for setting up and checking balances in tests
+pub fn dollars(dollars: f64) -> Amount {
+    Amount::from_dollars(dollars).expect("Error building test amount")
+}
+
+/// A fixed key for audit logs opened in tests
+pub fn audit_key() -> AuditKey {
+    AuditKey::from([7; 32])
+}
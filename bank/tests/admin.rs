@@ -0,0 +1,306 @@
+use bank::{
+    admin::{self, AdminConfig},
+    audit::AuditLog,
+    cli::Console,
+    operators::{Operator, Operators, Role},
+    test_support::{audit_key, dollars, TestDir},
+    Bank,
+};
+use common::{
+    admin::{AdminChannel, AdminReply, AdminRequest},
+    crypto::{errors::HandshakeError, AtmCredentials, AtmKeys, Psk},
+    io::Timeouts,
+    transport::{Listener, MemoryConnector, MemoryStream, Transport},
+};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// A bank serving admin consoles in memory to the console `desk-1`, with a
+/// teller tom and a supervisor sam. Deposits over $1000 need approval
+struct TestAdmin {
+    bank: Arc<Bank>,
+    console: Arc<Console>,
+    connector: MemoryConnector,
+    shutdown: Arc<AtomicBool>,
+    server: JoinHandle<()>,
+}
+
+impl TestAdmin {
+    fn start(dir: &Path) -> Self {
+        let operators_path = dir.join("operators");
+        Operators::add(&operators_path, "tom", Role::Teller, "hunter2").unwrap();
+        Operators::add(&operators_path, "sam", Role::Supervisor, "swordfish").unwrap();
+        let operators = Arc::new(Operators::load(&operators_path).unwrap());
+        let mut console_keys = AtmKeys::default();
+        assert!(console_keys.register("desk-1", Psk::from([2; 32])));
+
+        let bank = Arc::new(Bank::new());
+        bank.create_account("amy", 1234, dollars(100.0)).unwrap();
+        let audit = Arc::new(AuditLog::open(&dir.join("audit.log"), audit_key()).unwrap());
+        let console = Arc::new(Console::new(bank.clone(), audit.clone(), dollars(1_000.0)));
+        let config = AdminConfig {
+            console_keys: Arc::new(console_keys),
+            audit,
+            ..AdminConfig::default()
+        };
+        let (listener, connector) = Listener::memory();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (console_clone, shutdown_clone) = (console.clone(), shutdown.clone());
+        let server = thread::spawn(move || {
+            admin::serve(listener, console_clone, operators, &config, shutdown_clone)
+        });
+        Self {
+            bank,
+            console,
+            connector,
+            shutdown,
+            server,
+        }
+    }
+
+    /// Opens a channel to the bank as the console with the given id and key
+    fn connect_as(&self, id: &str, psk: [u8; 32]) -> Result<AdminChannel, HandshakeError> {
+        let stream = self.connector.connect().unwrap();
+        AdminChannel::open(Box::new(stream), Timeouts::default(), &credentials(id, psk))
+    }
+    fn connect(&self) -> AdminChannel {
+        self.connect_as("desk-1", [2; 32]).unwrap()
+    }
+
+    /// Shuts the server down and waits for it
+    fn stop(self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.server.join().unwrap();
+    }
+}
+
+fn credentials(id: &str, psk: [u8; 32]) -> AtmCredentials {
+    AtmCredentials {
+        id: id.to_string(),
+        psk: Some(Psk::from(psk)),
+        bank_key: None,
+    }
+}
+
+/// A stream which sends everything written to it in two halves, pausing
+/// longer than the bank's read timeout in between
+struct Pausing(MemoryStream);
+
+impl Read for Pausing {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl Write for Pausing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (first, rest) = buf.split_at(buf.len() / 2);
+        self.0.write_all(first)?;
+        thread::sleep(Duration::from_millis(250));
+        self.0.write_all(rest)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+impl Transport for Pausing {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(timeout)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.0.set_nonblocking(nonblocking)
+    }
+}
+
+fn send(channel: &mut AdminChannel, request: AdminRequest) -> AdminReply {
+    channel.send_request(&request).unwrap();
+    channel.receive_reply().unwrap()
+}
+fn login(name: &str, password: &str) -> AdminRequest {
+    AdminRequest::Login {
+        name: name.to_string(),
+        password: password.to_string(),
+    }
+}
+fn command(line: &str) -> AdminRequest {
+    AdminRequest::Command(line.to_string())
+}
+
+/// Returns the actor, action, target and outcome of every audit entry
+fn entries(dir: &Path) -> Vec<Vec<String>> {
+    let contents = fs::read_to_string(dir.join("audit.log")).unwrap();
+    contents
+        .lines()
+        .map(|line| {
+            line.split('\t')
+                .skip(2)
+                .take(4)
+                .map(str::to_string)
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn only_registered_consoles_connect() {
+    let dir = TestDir::new("admin-registered");
+    let admin = TestAdmin::start(&dir);
+
+    assert!(admin.connect_as("desk-2", [2; 32]).is_err());
+    assert!(admin.connect_as("desk-1", [3; 32]).is_err());
+    let mut channel = admin.connect();
+    assert!(matches!(
+        send(&mut channel, login("tom", "hunter2")),
+        AdminReply::LoggedIn(_)
+    ));
+    drop(channel);
+    admin.stop();
+
+    let entries = entries(&dir);
+    assert_eq!(entries[0][0], "console@a local peer");
+    assert!(entries[0][3].starts_with("refused"));
+    assert!(entries[1][3].starts_with("refused"));
+    assert_eq!(
+        entries[2],
+        ["console:desk-1@a local peer", "connect", "-", "ok"]
+    );
+}
+
+#[test]
+fn operators_run_their_commands_remotely() {
+    let dir = TestDir::new("admin-commands");
+    let admin = TestAdmin::start(&dir);
+    let mut channel = admin.connect();
+
+    assert!(matches!(
+        send(&mut channel, command("balance amy")),
+        AdminReply::Refused(_)
+    ));
+    assert!(matches!(
+        send(&mut channel, login("tom", "hunter3")),
+        AdminReply::Refused(_)
+    ));
+    match send(&mut channel, login("tom", "hunter2")) {
+        AdminReply::LoggedIn(greeting) => assert!(greeting.contains("tom (teller)")),
+        reply => panic!("unexpected reply {reply:?}"),
+    }
+
+    match send(&mut channel, command("deposit amy 5")) {
+        AdminReply::Output(output) => assert!(output.contains("Balance for amy is: $105.00")),
+        reply => panic!("unexpected reply {reply:?}"),
+    }
+    // the permission table applies remotely too
+    match send(&mut channel, command("users")) {
+        AdminReply::Output(output) => assert!(output.contains("may not run `users`")),
+        reply => panic!("unexpected reply {reply:?}"),
+    }
+    assert!(matches!(
+        send(&mut channel, command("logout")),
+        AdminReply::Ended(_)
+    ));
+    admin.stop();
+
+    let entries = entries(&dir);
+    let described: Vec<[&str; 4]> = entries
+        .iter()
+        .skip(1)
+        .map(|fields| [&*fields[0], &*fields[1], &*fields[2], &*fields[3]])
+        .collect();
+    assert_eq!(
+        described,
+        [
+            ["console:desk-1@a local peer", "login", "tom", "failed"],
+            ["console:desk-1@a local peer", "login", "tom", "ok"],
+            ["operator:tom", "deposit 5.00", "amy", "ok"],
+            ["operator:tom", "users", "-", "denied"],
+            ["operator:tom", "logout", "-", "ok"],
+        ]
+    );
+}
+
+#[test]
+fn waits_out_records_split_across_read_timeouts() {
+    let dir = TestDir::new("admin-split");
+    let admin = TestAdmin::start(&dir);
+    let stream = Pausing(admin.connector.connect().unwrap());
+    let mut channel = AdminChannel::open(
+        Box::new(stream),
+        Timeouts::default(),
+        &credentials("desk-1", [2; 32]),
+    )
+    .unwrap();
+
+    // every record arrives in two pieces, further apart than the read timeout
+    assert!(matches!(
+        send(&mut channel, login("tom", "hunter2")),
+        AdminReply::LoggedIn(_)
+    ));
+    assert!(matches!(
+        send(&mut channel, command("balance amy")),
+        AdminReply::Output(_)
+    ));
+    admin.stop();
+}
+
+#[test]
+fn repeated_failed_logins_end_the_session() {
+    let dir = TestDir::new("admin-logins");
+    let admin = TestAdmin::start(&dir);
+    let mut channel = admin.connect();
+
+    for _ in 1..admin::MAX_LOGIN_ATTEMPTS {
+        assert!(matches!(
+            send(&mut channel, login("sam", "wrong")),
+            AdminReply::Refused(_)
+        ));
+    }
+    assert!(matches!(
+        send(&mut channel, login("sam", "wrong")),
+        AdminReply::Ended(_)
+    ));
+    assert!(channel.receive_reply().is_err());
+    admin.stop();
+}
+
+#[test]
+fn remote_supervisors_approve_and_shut_down() {
+    let dir = TestDir::new("admin-supervisor");
+    let admin = TestAdmin::start(&dir);
+    let teller = Operator {
+        name: "tom".to_string(),
+        role: Role::Teller,
+    };
+    // held at the bank's own prompt, approved from a console
+    admin
+        .console
+        .process_input(&teller, "deposit amy 5000", &mut String::new());
+    assert_eq!(admin.bank.balance("amy").unwrap(), dollars(100.0));
+
+    let mut channel = admin.connect();
+    send(&mut channel, login("sam", "swordfish"));
+    match send(&mut channel, command("pending")) {
+        AdminReply::Output(output) => assert!(output.contains("#1 deposit 5000.00 for amy")),
+        reply => panic!("unexpected reply {reply:?}"),
+    }
+    send(&mut channel, command("approve 1"));
+    assert_eq!(admin.bank.balance("amy").unwrap(), dollars(5100.0));
+
+    assert!(matches!(
+        send(&mut channel, command("exit")),
+        AdminReply::Ended(_)
+    ));
+    assert!(admin.shutdown.load(Ordering::SeqCst));
+    admin.server.join().unwrap();
+}
@@ -0,0 +1,224 @@
+use bank::{
+    audit::{self, errors::AuditError, Actor, AuditKey, AuditLog},
+    server::ServerConfig,
+    test_support::{audit_key, dollars, TestBank, TestDir},
+};
+use common::crypto::{AtmCredentials, AtmKeys, Psk};
+use std::{fs, path::Path, sync::Arc, thread};
+
+/// Opens a log at the given path holding three operator entries
+fn log_with_entries(path: &Path) -> AuditLog {
+    let log = AuditLog::open(path, audit_key()).unwrap();
+    log.record(
+        &Actor::Operator("tom".to_string()),
+        "create-user 100.00",
+        "amy",
+        "ok",
+    );
+    log.record(
+        &Actor::Operator("tom".to_string()),
+        "deposit 5.00",
+        "amy",
+        "ok",
+    );
+    log.record(
+        &Actor::Operator("tom".to_string()),
+        "balance",
+        "bob",
+        "refused: unknown",
+    );
+    log
+}
+
+#[test]
+fn chain_verifies_and_continues_after_reopening() {
+    let dir = TestDir::new("audit-chain");
+    let path = dir.join("audit.log");
+    let log = log_with_entries(&path);
+    assert_eq!(log.verify().unwrap().entries, 3);
+    drop(log);
+
+    let log = AuditLog::open(&path, audit_key()).unwrap();
+    log.record(&Actor::Bank, "start", "", "ok");
+    assert_eq!(log.verify().unwrap().entries, 4);
+    assert_eq!(audit::verify(&path, &audit_key()).unwrap().entries, 4);
+    // the head file is replaced whole, leaving no temporary file beside it
+    let mut files: Vec<String> = fs::read_dir(&*dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["audit.head", "audit.log"]);
+}
+
+#[test]
+fn concurrent_records_share_one_chain() {
+    let dir = TestDir::new("audit-concurrent");
+    let path = dir.join("audit.log");
+    let log = Arc::new(AuditLog::open(&path, audit_key()).unwrap());
+    let writers: Vec<_> = (0..8)
+        .map(|i| {
+            let log = log.clone();
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    log.record(&Actor::Operator(format!("op{i}")), "balance", "amy", "ok");
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(log.verify().unwrap().entries, 200);
+    // every entry was flushed before its record returned
+    let head = fs::read_to_string(dir.join("audit.head")).unwrap();
+    assert!(head.starts_with("200 "));
+}
+
+#[test]
+fn background_sync_flushes_everything_before_stopping() {
+    let dir = TestDir::new("audit-background");
+    let path = dir.join("audit.log");
+    let log = AuditLog::open(&path, audit_key()).unwrap();
+    let background = log.sync_in_background();
+    thread::scope(|scope| {
+        for i in 0..4 {
+            let log = &log;
+            scope.spawn(move || {
+                for _ in 0..25 {
+                    log.record(&Actor::Operator(format!("op{i}")), "balance", "amy", "ok");
+                }
+            });
+        }
+    });
+    drop(background);
+    let head = fs::read_to_string(dir.join("audit.head")).unwrap();
+    assert!(head.starts_with("100 "));
+
+    // records flush themselves again once the thread has stopped
+    log.record(&Actor::Bank, "shutdown", "", "ok");
+    let head = fs::read_to_string(dir.join("audit.head")).unwrap();
+    assert!(head.starts_with("101 "));
+    assert_eq!(log.verify().unwrap().entries, 101);
+}
+
+#[test]
+fn detects_modified_entries() {
+    let dir = TestDir::new("audit-modified");
+    let path = dir.join("audit.log");
+    let log = log_with_entries(&path);
+
+    let contents = fs::read_to_string(&path).unwrap();
+    fs::write(&path, contents.replacen("deposit 5.00", "deposit 9.00", 1)).unwrap();
+    assert!(matches!(log.verify(), Err(AuditError::Broken(2))));
+    drop(log);
+    // a bank will not append to a log which has been tampered with
+    assert!(AuditLog::open(&path, audit_key()).is_err());
+}
+
+#[test]
+fn chain_cannot_be_rewritten_without_the_key() {
+    let dir = TestDir::new("audit-keyed");
+    let path = dir.join("audit.log");
+    drop(log_with_entries(&path));
+
+    // the hashes are only right under the bank's own key
+    let other_key = AuditKey::from([8; 32]);
+    assert!(matches!(
+        audit::verify(&path, &other_key),
+        Err(AuditError::Broken(1))
+    ));
+    assert!(AuditLog::open(&path, other_key).is_err());
+}
+
+#[test]
+fn creates_a_private_key_file_once() {
+    let dir = TestDir::new("audit-key-file");
+    let key_path = dir.join("audit.key");
+    let path = dir.join("audit.log");
+    let log = AuditLog::open(&path, AuditKey::load_or_create(&key_path).unwrap()).unwrap();
+    log.record(&Actor::Bank, "start", "", "ok");
+    drop(log);
+
+    // the same key is read back on the next start
+    let key = AuditKey::load_or_create(&key_path).unwrap();
+    assert_eq!(audit::verify(&path, &key).unwrap().entries, 1);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+#[test]
+fn detects_truncation() {
+    let dir = TestDir::new("audit-truncated");
+    let path = dir.join("audit.log");
+    let log = log_with_entries(&path);
+
+    let contents = fs::read_to_string(&path).unwrap();
+    let kept: Vec<&str> = contents.lines().take(2).collect();
+    fs::write(&path, kept.join("\n") + "\n").unwrap();
+    assert!(matches!(
+        log.verify(),
+        Err(AuditError::Truncated {
+            expected: 3,
+            found: 2
+        })
+    ));
+    // the head file still names the lost entry after a restart
+    drop(log);
+    assert!(audit::verify(&path, &audit_key()).is_err());
+}
+
+#[test]
+fn records_atm_logins_and_requests() {
+    let dir = TestDir::new("audit-atm");
+    let path = dir.join("audit.log");
+    let mut keys = AtmKeys::default();
+    assert!(keys.register("atm-1", Psk::from([1; 32])));
+    let test_bank = TestBank::start_with(ServerConfig {
+        atm_keys: Arc::new(keys),
+        audit: Arc::new(AuditLog::open(&path, audit_key()).unwrap()),
+        ..ServerConfig::default()
+    });
+    test_bank
+        .bank()
+        .create_account("amy", 1234, dollars(100.0))
+        .unwrap();
+
+    let credentials = AtmCredentials {
+        id: "atm-1".to_string(),
+        psk: Some(Psk::from([1; 32])),
+        bank_key: None,
+    };
+    let mut client = test_bank.client_as(&credentials).unwrap();
+    assert!(!client.authenticate("amy", 4321).unwrap());
+    assert!(client.authenticate("amy", 1234).unwrap());
+    client.withdraw(dollars(10.0)).unwrap();
+    drop(client);
+    assert!(test_bank.client_as(&AtmCredentials::default()).is_err());
+    // wait for the server to finish with every connection
+    drop(test_bank);
+
+    let contents = fs::read_to_string(&path).unwrap();
+    let entries: Vec<Vec<&str>> = contents
+        .lines()
+        .map(|line| line.split('\t').collect())
+        .collect();
+    let described: Vec<[&str; 4]> = entries
+        .iter()
+        .map(|fields| [fields[2], fields[3], fields[4], fields[5]])
+        .collect();
+    assert!(described[0][0].starts_with("atm:atm-1@"));
+    assert_eq!(described[0][1..], ["connect", "-", "ok"]);
+    assert_eq!(described[1][1..], ["login", "amy", "failed"]);
+    assert_eq!(described[2][1..], ["login", "amy", "ok"]);
+    assert_eq!(described[3][1..], ["withdraw 10.00", "amy", "ok"]);
+    // the anonymous ATM is refused before it can say who it is
+    assert!(described[4][0].starts_with("atm@"));
+    assert_eq!(described[4][1], "connect");
+    assert!(described[4][3].starts_with("refused"));
+    assert_eq!(audit::verify(&path, &audit_key()).unwrap().entries, 5);
+}
@@ -0,0 +1,161 @@
+use bank::{
+    server::ServerConfig,
+    test_support::{dollars, TestBank, TestDir},
+};
+use common::{
+    client::errors::ClientError,
+    crypto::{errors::HandshakeError, AtmCredentials, AtmKeys, BankKey, BankSecret, Psk},
+};
+use std::sync::Arc;
+
+/// Starts a bank which only serves the ATM "atm-1", holding a key of all ones,
+/// and an account for amy
+fn bank_with_registered_atm() -> TestBank {
+    bank_with_registered_atm_and(None)
+}
+
+/// Starts a bank like `bank_with_registered_atm` which also offers the Noise
+/// handshake with the given key
+fn bank_with_registered_atm_and(noise_key: Option<BankSecret>) -> TestBank {
+    let mut keys = AtmKeys::default();
+    assert!(keys.register("atm-1", Psk::from([1; 32])));
+    let test_bank = TestBank::start_with(ServerConfig {
+        atm_keys: Arc::new(keys),
+        noise_key,
+        ..ServerConfig::default()
+    });
+    test_bank
+        .bank()
+        .create_account("amy", 1234, dollars(100.0))
+        .unwrap();
+    test_bank
+}
+
+/// The bank's Noise key used by these tests
+fn noise_key() -> BankSecret {
+    BankSecret::from([7; 32])
+}
+
+/// Builds the credentials of an ATM
+fn credentials(id: &str, psk: Option<u8>) -> AtmCredentials {
+    AtmCredentials {
+        id: id.to_string(),
+        psk: psk.map(|byte| Psk::from([byte; 32])),
+        bank_key: None,
+    }
+}
+
+/// Builds the credentials of an ATM using the Noise handshake
+fn noise_credentials(id: &str, psk: Option<u8>, bank_key: BankKey) -> AtmCredentials {
+    AtmCredentials {
+        bank_key: Some(bank_key),
+        ..credentials(id, psk)
+    }
+}
+
+/// Checks the bank refuses to open a channel for the given credentials
+fn assert_refused(test_bank: &TestBank, credentials: &AtmCredentials) {
+    assert_handshake_fails(test_bank, credentials, HandshakeError::Refused);
+}
+
+/// Checks opening a channel with the given credentials fails the given way
+fn assert_handshake_fails(
+    test_bank: &TestBank,
+    credentials: &AtmCredentials,
+    expected: HandshakeError,
+) {
+    match test_bank.client_as(credentials) {
+        Err(ClientError::Handshake(e)) => {
+            assert_eq!(e, expected, "{credentials:?} failed the wrong way")
+        }
+        Err(e) => panic!("{credentials:?} failed outside the handshake: {e}"),
+        Ok(_) => panic!("{credentials:?} was not refused"),
+    }
+}
+
+#[test]
+fn serves_registered_atm_with_its_key() {
+    let test_bank = bank_with_registered_atm();
+    let mut client = test_bank.client_as(&credentials("atm-1", Some(1))).unwrap();
+
+    assert!(client.authenticate("amy", 1234).unwrap());
+    assert_eq!(client.withdraw(dollars(10.0)).unwrap(), dollars(90.0));
+}
+
+#[test]
+fn refuses_atm_without_the_right_key() {
+    let test_bank = bank_with_registered_atm();
+
+    assert_refused(&test_bank, &credentials("atm-1", None));
+    assert_refused(&test_bank, &credentials("atm-1", Some(2)));
+    assert_refused(&test_bank, &credentials("atm-2", Some(1)));
+    assert_refused(&test_bank, &AtmCredentials::default());
+}
+
+#[test]
+fn open_bank_refuses_atm_with_a_key() {
+    // the ATM expects its key to be checked, so it must not settle for less
+    let test_bank = TestBank::start();
+
+    assert_refused(&test_bank, &credentials("atm-1", Some(1)));
+    assert!(test_bank.client_as(&AtmCredentials::default()).is_ok());
+}
+
+#[test]
+fn serves_noise_atm_holding_the_bank_key() {
+    let test_bank = bank_with_registered_atm_and(Some(noise_key()));
+    let credentials = noise_credentials("atm-1", Some(1), noise_key().public());
+    let mut client = test_bank.client_as(&credentials).unwrap();
+
+    assert!(client.authenticate("amy", 1234).unwrap());
+    assert_eq!(client.withdraw(dollars(10.0)).unwrap(), dollars(90.0));
+}
+
+#[test]
+fn noise_bank_still_serves_classic_atms() {
+    let test_bank = bank_with_registered_atm_and(Some(noise_key()));
+    let mut client = test_bank.client_as(&credentials("atm-1", Some(1))).unwrap();
+
+    assert!(client.authenticate("amy", 1234).unwrap());
+}
+
+#[test]
+fn noise_handshake_needs_matching_keys() {
+    let test_bank = bank_with_registered_atm_and(Some(noise_key()));
+    let public = noise_key().public();
+
+    // the bank cannot read a hello meant for another bank's key
+    let impostor = BankSecret::from([8; 32]).public();
+    assert_refused(&test_bank, &noise_credentials("atm-1", Some(1), impostor));
+    // the ATM cannot read the reply of a bank mixing in another pre-shared
+    // key, and an unregistered ATM cannot tell it apart from holding one
+    for (id, psk) in [("atm-1", None), ("atm-1", Some(2)), ("atm-2", Some(1))] {
+        assert_handshake_fails(
+            &test_bank,
+            &noise_credentials(id, psk, public),
+            HandshakeError::KeyMismatch,
+        );
+    }
+}
+
+#[test]
+fn noise_atm_does_not_fall_back_to_classic() {
+    let test_bank = bank_with_registered_atm();
+    let credentials = noise_credentials("atm-1", Some(1), noise_key().public());
+
+    assert_handshake_fails(&test_bank, &credentials, HandshakeError::Unsupported);
+}
+
+#[test]
+fn loads_key_files() {
+    let dir = TestDir::new("channel-keys");
+    let path = dir.join("atm_keys");
+    let key = "01".repeat(32);
+
+    std::fs::write(&path, format!("atm-1 {key}\n\natm-2 {key}\n")).unwrap();
+    assert!(!AtmKeys::load(&path).unwrap().is_open());
+    std::fs::write(&path, format!("atm-1 {key}\natm-1 {key}\n")).unwrap();
+    assert!(AtmKeys::load(&path).is_err());
+    std::fs::write(&path, "atm-1 0123\n").unwrap();
+    assert!(AtmKeys::load(&path).is_err());
+}
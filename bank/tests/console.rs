@@ -0,0 +1,228 @@
+use bank::{
+    audit::AuditLog,
+    cli::{Console, Next},
+    operators::{Operator, Operators, Role},
+    test_support::{audit_key, dollars, TestDir},
+    Bank,
+};
+use std::{fs, path::Path, sync::Arc};
+
+/// A console over a bank holding an account for amy, auditing to the given
+/// directory. Deposits over $1000 need approval
+fn console(dir: &Path) -> (Arc<Bank>, Console) {
+    let bank = Arc::new(Bank::new());
+    bank.create_account("amy", 1234, dollars(100.0)).unwrap();
+    let audit = AuditLog::open(&dir.join("audit.log"), audit_key()).unwrap();
+    let console = Console::new(bank.clone(), Arc::new(audit), dollars(1_000.0));
+    (bank, console)
+}
+
+fn operator(name: &str, role: Role) -> Operator {
+    Operator {
+        name: name.to_string(),
+        role,
+    }
+}
+
+/// Returns the actor, action, target and outcome of the newest audit entry
+fn last_entry(dir: &Path) -> Vec<String> {
+    let contents = fs::read_to_string(dir.join("audit.log")).unwrap();
+    let line = contents.lines().last().unwrap();
+    line.split('\t')
+        .skip(2)
+        .take(4)
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn roles_only_run_permitted_commands() {
+    let dir = TestDir::new("console-permissions");
+    let (bank, console) = console(&dir);
+    let teller = operator("tom", Role::Teller);
+    let auditor = operator("ann", Role::Auditor);
+
+    assert_eq!(
+        console.process_input(&auditor, "deposit amy 5", &mut String::new()),
+        Next::Prompt
+    );
+    assert_eq!(bank.balance("amy").unwrap(), dollars(100.0));
+    assert_eq!(last_entry(&dir), ["operator:ann", "deposit", "-", "denied"]);
+    console.process_input(&teller, "users", &mut String::new());
+    assert_eq!(last_entry(&dir), ["operator:tom", "users", "-", "denied"]);
+    // only a supervisor may shut the bank down
+    assert_eq!(
+        console.process_input(&teller, "exit", &mut String::new()),
+        Next::Prompt
+    );
+    assert_eq!(
+        console.process_input(&teller, "logout", &mut String::new()),
+        Next::Logout
+    );
+    let supervisor = operator("sam", Role::Supervisor);
+    assert_eq!(
+        console.process_input(&supervisor, "exit", &mut String::new()),
+        Next::Shutdown
+    );
+
+    console.process_input(&teller, "deposit amy 5", &mut String::new());
+    assert_eq!(bank.balance("amy").unwrap(), dollars(105.0));
+    assert_eq!(
+        last_entry(&dir),
+        ["operator:tom", "deposit 5.00", "amy", "ok"]
+    );
+}
+
+#[test]
+fn large_deposits_need_a_second_operator() {
+    let dir = TestDir::new("console-deposits");
+    let (bank, console) = console(&dir);
+    let teller = operator("tom", Role::Teller);
+    let first = operator("sam", Role::Supervisor);
+    let second = operator("sue", Role::Supervisor);
+
+    console.process_input(&first, "deposit amy 5000", &mut String::new());
+    assert_eq!(bank.balance("amy").unwrap(), dollars(100.0));
+    assert_eq!(
+        last_entry(&dir),
+        [
+            "operator:sam",
+            "deposit 5000.00",
+            "amy",
+            "held for approval as #1"
+        ]
+    );
+    // neither a teller nor the supervisor who asked may approve it
+    console.process_input(&teller, "approve 1", &mut String::new());
+    console.process_input(&first, "approve 1", &mut String::new());
+    assert_eq!(bank.balance("amy").unwrap(), dollars(100.0));
+
+    console.process_input(&second, "approve 1", &mut String::new());
+    assert_eq!(bank.balance("amy").unwrap(), dollars(5100.0));
+    assert_eq!(
+        last_entry(&dir),
+        ["operator:sue", "approve #1 deposit 5000.00", "amy", "ok"]
+    );
+    // a request is only carried out once
+    console.process_input(&second, "approve 1", &mut String::new());
+    assert_eq!(bank.balance("amy").unwrap(), dollars(5100.0));
+}
+
+#[test]
+fn large_opening_balances_need_a_second_operator() {
+    let dir = TestDir::new("console-create-user");
+    let (bank, console) = console(&dir);
+    let teller = operator("tom", Role::Teller);
+    let supervisor = operator("sam", Role::Supervisor);
+
+    console.process_input(&teller, "create-user bob 4242 50", &mut String::new());
+    assert_eq!(bank.balance("bob").unwrap(), dollars(50.0));
+
+    console.process_input(&teller, "create-user cat 1111 2000", &mut String::new());
+    assert!(bank.balance("cat").is_err());
+    assert_eq!(
+        last_entry(&dir),
+        [
+            "operator:tom",
+            "create-user 2000.00",
+            "cat",
+            "held for approval as #1"
+        ]
+    );
+    // the pin is kept out of the pending list
+    let mut out = String::new();
+    console.process_input(&supervisor, "pending", &mut out);
+    assert!(out.contains("#1 create-user 2000.00 for cat"));
+    assert!(!out.contains("1111"));
+
+    console.process_input(&supervisor, "approve 1", &mut String::new());
+    assert_eq!(bank.balance("cat").unwrap(), dollars(2000.0));
+    assert!(bank.attempt_authentication("cat", 1111));
+}
+
+#[test]
+fn large_transfers_need_a_second_operator() {
+    let dir = TestDir::new("console-transfers");
+    let (bank, console) = console(&dir);
+    let teller = operator("tom", Role::Teller);
+    let supervisor = operator("sam", Role::Supervisor);
+    bank.create_account("bob", 42, dollars(5000.0)).unwrap();
+
+    console.process_input(&teller, "transfer bob amy 1000", &mut String::new());
+    assert_eq!(bank.balance("amy").unwrap(), dollars(1100.0));
+
+    console.process_input(&teller, "transfer bob amy 1500", &mut String::new());
+    assert_eq!(bank.balance("amy").unwrap(), dollars(1100.0));
+    assert_eq!(
+        last_entry(&dir),
+        [
+            "operator:tom",
+            "transfer 1500.00 to amy",
+            "bob",
+            "held for approval as #1"
+        ]
+    );
+    console.process_input(&supervisor, "reject 1", &mut String::new());
+    assert_eq!(bank.balance("bob").unwrap(), dollars(4000.0));
+
+    console.process_input(&teller, "transfer bob amy 1500", &mut String::new());
+    console.process_input(&supervisor, "approve 2", &mut String::new());
+    assert_eq!(bank.balance("bob").unwrap(), dollars(2500.0));
+    assert_eq!(bank.balance("amy").unwrap(), dollars(2600.0));
+    assert_eq!(
+        last_entry(&dir),
+        [
+            "operator:sam",
+            "approve #2 transfer 1500.00 to amy",
+            "bob",
+            "ok"
+        ]
+    );
+}
+
+#[test]
+fn pin_resets_need_a_second_operator() {
+    let dir = TestDir::new("console-pins");
+    let (bank, console) = console(&dir);
+    let teller = operator("tom", Role::Teller);
+    let supervisor = operator("sam", Role::Supervisor);
+
+    console.process_input(&teller, "reset-pin amy 4321", &mut String::new());
+    console.process_input(&teller, "reset-pin amy 5555", &mut String::new());
+    assert!(bank.attempt_authentication("amy", 1234));
+
+    console.process_input(&supervisor, "reject 1", &mut String::new());
+    console.process_input(&supervisor, "approve 2", &mut String::new());
+    assert!(bank.attempt_authentication("amy", 5555));
+    // the new pin is never written to the audit log
+    let log = fs::read_to_string(dir.join("audit.log")).unwrap();
+    for line in log.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        assert!(fields[2..6].iter().all(|field| !field.contains("5555")));
+    }
+    // nor shown to operators listing users
+    let mut out = String::new();
+    console.process_input(&supervisor, "users", &mut out);
+    assert!(out.contains("amy -> $100.00"));
+    assert!(!out.contains("5555"));
+}
+
+#[test]
+fn operators_log_in_with_their_password() {
+    let dir = TestDir::new("console-operators");
+    let path = dir.join("operators");
+    Operators::add(&path, "tom", Role::Teller, "hunter2").unwrap();
+    assert!(Operators::add(&path, "tom", Role::Auditor, "other").is_err());
+    assert!(Operators::add(&path, "not valid", Role::Auditor, "other").is_err());
+
+    let operators = Operators::load(&path).unwrap();
+    assert_eq!(
+        operators.authenticate("tom", "hunter2"),
+        Some(operator("tom", Role::Teller))
+    );
+    assert_eq!(operators.authenticate("tom", "hunter3"), None);
+    assert_eq!(operators.authenticate("sam", "hunter2"), None);
+
+    fs::write(&path, "tom teller not-a-hash\n").unwrap();
+    assert!(Operators::load(&path).is_err());
+}
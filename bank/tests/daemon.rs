@@ -0,0 +1,176 @@
+#![cfg(unix)]
+use bank::{
+    operators::{Operators, Role},
+    test_support::TestDir,
+};
+use common::transport::{Endpoint, Listener};
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Starts a headless bank keeping its files in the given directory
+fn start_bank(dir: &Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_bank"))
+        .arg("--headless")
+        .arg("--allow-anonymous-atms")
+        .arg("--listen")
+        .arg(format!("unix:{}", dir.join("bank.sock").display()))
+        .arg("--data-dir")
+        .arg(dir)
+        .arg("--pid-file")
+        .arg(dir.join("bank.pid"))
+        .arg("--log-file")
+        .arg(dir.join("bank.log"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Error starting bank")
+}
+
+/// Sends the bank a signal by name
+fn signal(bank: &Child, name: &str) {
+    let status = Command::new("kill")
+        .arg(format!("-{name}"))
+        .arg(bank.id().to_string())
+        .status()
+        .expect("Error running kill");
+    assert!(status.success());
+}
+
+/// Waits until the bank's log contains the given text
+fn wait_for_log(dir: &Path, text: &str) {
+    let start = Instant::now();
+    while !fs::read_to_string(dir.join("bank.log")).is_ok_and(|log| log.contains(text)) {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "bank never logged {text:?}"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn runs_headless_until_signalled() {
+    let dir = TestDir::new("daemon-headless");
+    let mut bank = start_bank(&dir);
+    wait_for_log(&dir, "Bank ready");
+    let pid = fs::read_to_string(dir.join("bank.pid")).unwrap();
+    assert_eq!(pid.trim(), bank.id().to_string());
+    let socket = fs::metadata(dir.join("bank.sock")).unwrap();
+    assert_eq!(socket.permissions().mode() & 0o777, 0o660);
+
+    // a second bank is turned away rather than sharing the data
+    assert!(!start_bank(&dir).wait().unwrap().success());
+    wait_for_log(&dir, &format!("already running as process {}", bank.id()));
+
+    // a broken operators file is refused, and a fixed one taken up
+    let operators = dir.join("operators");
+    fs::write(&operators, "tom teller not-a-hash\n").unwrap();
+    signal(&bank, "HUP");
+    wait_for_log(&dir, "kept the previous settings");
+    fs::remove_file(&operators).unwrap();
+    Operators::add(&operators, "tom", Role::Teller, "hunter2").unwrap();
+    signal(&bank, "HUP");
+    wait_for_log(&dir, "Reloaded operators and keys");
+
+    signal(&bank, "TERM");
+    assert!(bank.wait().unwrap().success());
+    let log = fs::read_to_string(dir.join("bank.log")).unwrap();
+    assert!(log.contains("Bank data saved"));
+    assert!(!dir.join("bank.pid").exists());
+    let audit = fs::read_to_string(dir.join("audit.log")).unwrap();
+    // the outcome of each reload, after the entry's number, time, actor,
+    // action and target
+    let reloads: Vec<&str> = audit
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            (fields[3] == "reload").then_some(fields[5])
+        })
+        .collect();
+    assert_eq!(reloads.len(), 2);
+    assert!(reloads[0].starts_with("refused"));
+    assert_eq!(reloads[1], "ok");
+}
+
+#[test]
+fn replaces_a_stale_pid_file() {
+    let dir = TestDir::new("daemon-stale");
+    // no process has this id: Linux caps them far lower
+    fs::write(dir.join("bank.pid"), "2147483646\n").unwrap();
+    let mut bank = start_bank(&dir);
+    wait_for_log(&dir, "Bank ready");
+    let pid = fs::read_to_string(dir.join("bank.pid")).unwrap();
+    assert_eq!(pid.trim(), bank.id().to_string());
+
+    signal(&bank, "TERM");
+    assert!(bank.wait().unwrap().success());
+}
+
+#[test]
+fn removes_its_pid_file_when_it_cannot_start() {
+    let dir = TestDir::new("daemon-failed-start");
+    // the socket's path is taken by a file the bank will not replace
+    fs::write(dir.join("bank.sock"), "precious").unwrap();
+    let mut bank = start_bank(&dir);
+    assert!(!bank.wait().unwrap().success());
+    wait_for_log(&dir, "could not bind");
+    assert!(!dir.join("bank.pid").exists());
+}
+
+#[test]
+fn socket_files_get_the_configured_mode() {
+    let dir = TestDir::new("daemon-socket-mode");
+    let path = dir.join("bank.sock");
+    let listener = Listener::bind_with_mode(&Endpoint::Unix(path.clone()), 0o600).unwrap();
+    let socket = fs::metadata(&path).unwrap();
+    assert_eq!(socket.permissions().mode() & 0o777, 0o600);
+
+    // the file is removed with the listener, and a stale one is replaced
+    drop(listener);
+    assert!(!path.exists());
+    let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    drop(stale);
+    assert!(path.exists());
+    let listener = Listener::bind(&Endpoint::Unix(path.clone())).unwrap();
+
+    // but a socket still being listened on is not, and nothing is left behind
+    let err = Listener::bind(&Endpoint::Unix(path)).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::AddrInUse);
+    drop(listener);
+    assert_eq!(fs::read_dir(&*dir).unwrap().count(), 0);
+}
+
+#[test]
+fn never_replaces_a_file_which_is_not_a_socket() {
+    let dir = TestDir::new("daemon-not-socket");
+    let path = dir.join("bank.sock");
+    fs::write(&path, "precious").unwrap();
+    let err = Listener::bind(&Endpoint::Unix(path.clone())).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "precious");
+}
+
+#[test]
+fn refuses_to_serve_atms_without_keys() {
+    let dir = TestDir::new("daemon-no-atm-keys");
+    let output = Command::new(env!("CARGO_BIN_EXE_bank"))
+        .arg("--headless")
+        .arg("--listen")
+        .arg(format!("unix:{}", dir.join("bank.sock").display()))
+        .arg("--data-dir")
+        .arg(&*dir)
+        .stdin(Stdio::null())
+        .output()
+        .expect("Error running bank");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("atm_keys"));
+    assert!(!dir.join("bank.sock").exists());
+}
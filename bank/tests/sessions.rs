@@ -0,0 +1,216 @@
+use bank::{
+    errors::BankError,
+    server::ServerConfig,
+    test_support::{dollars, TestBank},
+};
+use common::{
+    client::errors::ClientError,
+    message::{Amount, TransactionStatus},
+};
+
+/// Starts a bank holding accounts for amy and bob
+fn bank_with_users() -> TestBank {
+    let test_bank = TestBank::start();
+    test_bank
+        .bank()
+        .create_account("amy", 1234, dollars(100.0))
+        .unwrap();
+    test_bank
+        .bank()
+        .create_account("bob", 42, dollars(5.0))
+        .unwrap();
+    test_bank
+}
+
+#[test]
+fn authenticates_with_correct_pin_only() {
+    let test_bank = bank_with_users();
+    let mut client = test_bank.client();
+
+    assert!(!client.authenticate("amy", 4321).unwrap());
+    assert_eq!(client.user(), None);
+    assert!(!client.authenticate("zed", 1234).unwrap());
+    assert!(client.authenticate("amy", 1234).unwrap());
+    assert_eq!(client.user(), Some("amy"));
+}
+
+#[test]
+fn applies_one_username_rule_on_both_sides() {
+    let test_bank = bank_with_users();
+    let mut client = test_bank.client();
+
+    for name in ["amy2", "", "amy_b"] {
+        assert_eq!(
+            test_bank.bank().create_account(name, 1, dollars(0.0)),
+            Err(BankError::InvalidUsername)
+        );
+        assert!(matches!(
+            client.authenticate(name, 1),
+            Err(ClientError::InvalidUsername)
+        ));
+    }
+    assert!(matches!(
+        client.authenticate(&"a".repeat(21), 1),
+        Err(ClientError::UsernameTooLong(20))
+    ));
+}
+
+#[test]
+fn authenticates_pins_with_leading_zeros() {
+    let test_bank = bank_with_users();
+    let mut client = test_bank.client();
+
+    assert!(client.authenticate("bob", 42).unwrap());
+    assert_eq!(client.balance().unwrap(), dollars(5.0));
+}
+
+#[test]
+fn withdraws_and_deposits() {
+    let test_bank = bank_with_users();
+    let mut client = test_bank.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    assert_eq!(client.balance().unwrap(), dollars(100.0));
+    assert_eq!(client.withdraw(dollars(30.5)).unwrap(), dollars(69.5));
+    assert_eq!(client.deposit(dollars(10.0)).unwrap(), dollars(79.5));
+    assert_eq!(client.balance().unwrap(), dollars(79.5));
+    assert_eq!(test_bank.bank().balance("amy").unwrap(), dollars(79.5));
+}
+
+#[test]
+fn transfers_between_accounts() {
+    let test_bank = bank_with_users();
+    let mut client = test_bank.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    assert_eq!(
+        client.transfer("bob", dollars(20.0)).unwrap(),
+        dollars(80.0)
+    );
+    assert_eq!(test_bank.bank().balance("bob").unwrap(), dollars(25.0));
+}
+
+#[test]
+fn declines_transactions_without_changing_balances() {
+    let test_bank = bank_with_users();
+    let mut client = test_bank.client();
+    client.authenticate("bob", 42).unwrap();
+
+    let declined = |result: Result<Amount, ClientError>| match result {
+        Err(ClientError::Declined(status)) => status,
+        other => panic!("expected a declined transaction, got {other:?}"),
+    };
+    assert_eq!(
+        declined(client.withdraw(dollars(5.01))),
+        TransactionStatus::InsufficientFunds
+    );
+    assert_eq!(
+        declined(client.transfer("amy", dollars(6.0))),
+        TransactionStatus::InsufficientFunds
+    );
+    assert_eq!(
+        declined(client.transfer("zed", dollars(1.0))),
+        TransactionStatus::UnknownRecipient
+    );
+    assert_eq!(
+        declined(client.transfer("bob", dollars(1.0))),
+        TransactionStatus::SameAccount
+    );
+
+    assert_eq!(client.balance().unwrap(), dollars(5.0));
+    assert_eq!(test_bank.bank().balance("amy").unwrap(), dollars(100.0));
+}
+
+#[test]
+fn account_requests_need_an_authenticated_user() {
+    let test_bank = bank_with_users();
+    let mut client = test_bank.client();
+
+    assert!(matches!(
+        client.balance(),
+        Err(ClientError::NotAuthenticated)
+    ));
+    assert!(matches!(
+        client.withdraw(dollars(1.0)),
+        Err(ClientError::NotAuthenticated)
+    ));
+    assert_eq!(test_bank.bank().balance("amy").unwrap(), dollars(100.0));
+}
+
+#[test]
+fn ending_a_session_allows_another_user_on_the_same_connection() {
+    let test_bank = bank_with_users();
+    let mut client = test_bank.client();
+
+    client.authenticate("amy", 1234).unwrap();
+    client.end_session().unwrap();
+    assert_eq!(client.user(), None);
+    assert!(matches!(
+        client.balance(),
+        Err(ClientError::NotAuthenticated)
+    ));
+
+    client.authenticate("bob", 42).unwrap();
+    assert_eq!(client.balance().unwrap(), dollars(5.0));
+}
+
+#[test]
+fn heartbeats_never_wear_out_a_session() {
+    let test_bank = bank_with_users();
+    let mut client = test_bank.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    // an idle ATM sends heartbeats for as long as its customer stays logged in
+    for _ in 0..300 {
+        client.heartbeat().unwrap();
+    }
+    assert_eq!(client.user(), Some("amy"));
+    assert_eq!(client.balance().unwrap(), dollars(100.0));
+}
+
+#[test]
+fn serves_clients_concurrently() {
+    let test_bank = bank_with_users();
+    let mut amy = test_bank.client();
+    let mut bob = test_bank.client();
+    amy.authenticate("amy", 1234).unwrap();
+    bob.authenticate("bob", 42).unwrap();
+
+    amy.transfer("bob", dollars(10.0)).unwrap();
+    assert_eq!(bob.balance().unwrap(), dollars(15.0));
+    bob.transfer("amy", dollars(15.0)).unwrap();
+    assert_eq!(amy.balance().unwrap(), dollars(105.0));
+}
+
+#[test]
+fn runs_the_protocol_in_memory() {
+    let test_bank = TestBank::start_in_memory(ServerConfig::default());
+    test_bank
+        .bank()
+        .create_account("amy", 1234, dollars(100.0))
+        .unwrap();
+    let mut client = test_bank.client();
+
+    assert!(test_bank.endpoint().is_none());
+    assert!(client.authenticate("amy", 1234).unwrap());
+    assert_eq!(client.withdraw(dollars(40.0)).unwrap(), dollars(60.0));
+    client.end_session().unwrap();
+}
+
+#[test]
+fn reports_balances_up_to_the_largest_amount() {
+    let test_bank = bank_with_users();
+    test_bank
+        .bank()
+        .create_account("carl", 1111, dollars(0.0))
+        .unwrap();
+    let mut client = test_bank.client();
+    client.authenticate("carl", 1111).unwrap();
+
+    assert_eq!(client.deposit(Amount::MAX).unwrap(), Amount::MAX);
+    assert_eq!(client.balance().unwrap(), Amount::MAX);
+    assert!(matches!(
+        client.deposit(dollars(0.01)),
+        Err(ClientError::Declined(TransactionStatus::Overflow))
+    ));
+}
@@ -0,0 +1,107 @@
+use bank::{
+    storage::{DataFile, DataKey},
+    test_support::{dollars, TestDir},
+    Bank,
+};
+use std::fs;
+
+/// A bank holding accounts for amy and bob
+fn bank() -> Bank {
+    let bank = Bank::new();
+    bank.create_account("amy", 1234, dollars(100.0)).unwrap();
+    bank.create_account("bob", 42, dollars(0.5)).unwrap();
+    bank
+}
+
+fn key(byte: u8) -> Option<DataKey> {
+    Some(DataKey::Key([byte; 32]))
+}
+
+/// Checks a loaded bank holds the accounts of `bank()`
+fn assert_loaded(bank: &Bank) {
+    assert_eq!(bank.balance("amy").unwrap(), dollars(100.0));
+    assert_eq!(bank.balance("bob").unwrap(), dollars(0.5));
+}
+
+#[test]
+fn encrypted_data_round_trips() {
+    let dir = TestDir::new("storage-round-trip");
+    let data = DataFile::new(dir.join("bank.db"), key(1));
+
+    bank().save(&data).unwrap();
+    let contents = fs::read_to_string(data.path()).unwrap();
+    assert!(!contents.contains("amy"));
+    assert_loaded(&Bank::load(&data).unwrap());
+
+    let passphrase = Some(DataKey::Passphrase("correct horse".to_string()));
+    let data = DataFile::new(dir.join("bank.db"), passphrase);
+    bank().save(&data).unwrap();
+    assert_loaded(&Bank::load(&data).unwrap());
+}
+
+#[test]
+fn refuses_the_wrong_key() {
+    let dir = TestDir::new("storage-wrong-key");
+    let path = dir.join("bank.db");
+    bank().save(&DataFile::new(path.clone(), key(1))).unwrap();
+
+    assert!(Bank::load(&DataFile::new(path.clone(), key(2))).is_err());
+    assert!(Bank::load(&DataFile::new(path.clone(), None)).is_err());
+    // a plaintext file is not silently read as if it were encrypted
+    bank().save(&DataFile::new(path.clone(), None)).unwrap();
+    assert!(Bank::load(&DataFile::new(path, key(1))).is_err());
+}
+
+#[test]
+fn detects_tampered_records() {
+    let dir = TestDir::new("storage-tampered");
+    let data = DataFile::new(dir.join("bank.db"), key(1));
+    bank().save(&data).unwrap();
+    let contents = fs::read_to_string(data.path()).unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+
+    // records swapped
+    fs::write(data.path(), [lines[0], lines[2], lines[1]].join("\n")).unwrap();
+    assert!(Bank::load(&data).is_err());
+    // a record dropped
+    fs::write(data.path(), [lines[0], lines[1]].join("\n")).unwrap();
+    assert!(Bank::load(&data).is_err());
+    // a record flipped
+    let mut flipped = lines[1].to_string();
+    let last = if flipped.pop() == Some('0') { '1' } else { '0' };
+    flipped.push(last);
+    fs::write(data.path(), [lines[0], &flipped, lines[2]].join("\n")).unwrap();
+    assert!(Bank::load(&data).is_err());
+}
+
+#[test]
+fn rotates_to_a_new_key() {
+    let dir = TestDir::new("storage-rotate");
+    let path = dir.join("bank.db");
+    let plaintext = DataFile::new(path.clone(), None);
+    let old = DataFile::new(path.clone(), key(1));
+    let new = DataFile::new(path, key(2));
+
+    // plaintext data is encrypted by rotating to a key
+    bank().save(&plaintext).unwrap();
+    Bank::load(&plaintext).unwrap().save(&old).unwrap();
+    Bank::load(&old).unwrap().save(&new).unwrap();
+
+    assert_loaded(&Bank::load(&new).unwrap());
+    assert!(Bank::load(&old).is_err());
+}
+
+#[test]
+fn balances_are_kept_in_whole_cents() {
+    let dir = TestDir::new("storage-cents");
+    let data = DataFile::new(dir.join("bank.db"), None);
+    bank().save(&data).unwrap();
+    let contents = fs::read_to_string(data.path()).unwrap();
+    assert!(contents.contains("amy 1234 100.00"));
+
+    // a balance which is not a whole number of cents is never rounded
+    for balance in ["0.30000000000000004", "1e2", "-1.00"] {
+        fs::write(data.path(), format!("amy 1234 {balance}\n")).unwrap();
+        assert!(Bank::load(&data).is_err(), "{balance} was loaded");
+    }
+}
@@ -0,0 +1,367 @@
+use bank::{
+    pool::ConnectionLimits,
+    server::{ResponseTimes, ServerConfig, SessionTimeouts},
+    test_support::{dollars, TestBank},
+};
+use common::{
+    client::{errors::ClientError, BankNotice},
+    crypto::{
+        AtmCredentials, AtmKeys, Channel, Protocol, ServerHandshake, CLIENT_HELLO_SIZE,
+        PROTOCOL_CHOICE_SIZE, SEALED_FRAME_SIZE,
+    },
+    io::{errors::ReceiveError, StreamManager, Timeouts},
+    message::{
+        constants::*, Amount, AuthRequest, AuthResult, BalanceRequest, BalanceResponse,
+        DepositRequest, EndReason, EndSession, Message, Pin, TransactionResult, TransactionStatus,
+        TransferRequest, Username, WithdrawRequest,
+    },
+    transport::{memory_pair, MemoryStream},
+};
+use std::{
+    io::{Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Time given to the bank to notice something between its periodic checks
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Starts a bank with the given settings holding an account for amy
+fn bank_with_user(config: ServerConfig) -> TestBank {
+    let test_bank = TestBank::start_with(config);
+    test_bank
+        .bank()
+        .create_account("amy", 1234, dollars(100.0))
+        .unwrap();
+    test_bank
+}
+
+/// Builds a login request
+fn login(username: &str, pin: u16) -> AuthRequest {
+    AuthRequest {
+        username: Username::new(username).unwrap(),
+        pin: Pin::new(pin).unwrap(),
+    }
+}
+
+/// Builds one message of every type
+fn every_message() -> Vec<Message> {
+    let amy = Username::new("amy").unwrap();
+    let amount = Amount::from_dollars(12.5).unwrap();
+    vec![
+        login("amy", 1234).into(),
+        BalanceRequest {
+            username: amy.clone(),
+        }
+        .into(),
+        WithdrawRequest { amount }.into(),
+        DepositRequest { amount }.into(),
+        EndSession {
+            reason: EndReason::Requested,
+        }
+        .into(),
+        AuthResult {
+            authenticated: true,
+        }
+        .into(),
+        TransferRequest {
+            recipient: amy,
+            amount,
+        }
+        .into(),
+        BalanceResponse { balance: amount }.into(),
+        TransactionResult {
+            status: TransactionStatus::Approved,
+            balance: amount,
+        }
+        .into(),
+    ]
+}
+
+/// Opens a manager over an in-memory stream whose far end plays the bank by
+/// hand. Returns the manager, the far end and the bank's side of the channel
+fn open_by_hand() -> (StreamManager, MemoryStream, Channel) {
+    let (near, mut far) = memory_pair();
+    let opening = thread::spawn(move || {
+        StreamManager::open(
+            Box::new(near),
+            Timeouts::default(),
+            &AtmCredentials::default(),
+        )
+        .unwrap()
+    });
+
+    let (handshake, hello) = ServerHandshake::start(None);
+    far.write_all(&hello).unwrap();
+    let mut choice = [0u8; PROTOCOL_CHOICE_SIZE];
+    far.read_exact(&mut choice).unwrap();
+    assert_eq!(handshake.choose(choice[0]).unwrap(), Protocol::Classic);
+    let mut client_hello = [0u8; CLIENT_HELLO_SIZE];
+    far.read_exact(&mut client_hello).unwrap();
+    let accepted = handshake
+        .finish(Protocol::Classic, &client_hello, &AtmKeys::default())
+        .unwrap();
+    far.write_all(&accepted.reply).unwrap();
+    (opening.join().unwrap(), far, accepted.channel)
+}
+
+#[test]
+fn every_message_is_the_same_length() {
+    // play the bank by hand, so the far end sees the raw bytes on the wire
+    let (mut manager, mut far, _) = open_by_hand();
+    let mut comm_count = 0;
+
+    // measure what actually crosses the connection, as an eavesdropper would
+    let mut lengths = Vec::new();
+    for message in every_message() {
+        manager.send_message(message, &mut comm_count).unwrap();
+        let mut buf = [0u8; 2 * SEALED_FRAME_SIZE];
+        lengths.push(far.read(&mut buf).unwrap());
+    }
+    assert!(lengths.iter().all(|&length| length == SEALED_FRAME_SIZE));
+}
+
+#[test]
+fn waits_out_a_frame_split_across_read_timeouts() {
+    let (mut manager, mut far, channel) = open_by_hand();
+    manager
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let notice = Message::from(EndSession {
+        reason: EndReason::Requested,
+    });
+    let sealed = channel.seal(&notice.encode(0));
+    let sender = thread::spawn(move || {
+        far.write_all(&sealed[..10]).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        far.write_all(&sealed[10..]).unwrap();
+        far
+    });
+
+    // the rest of a frame is given the full request timeout, not the read timeout
+    let mut comm_count = 0;
+    let received = loop {
+        match manager.receive(&mut comm_count) {
+            Err(ReceiveError::TimedOut) => continue,
+            received => break received.unwrap(),
+        }
+    };
+    assert_eq!(received, notice);
+    sender.join().unwrap();
+}
+
+#[test]
+fn messages_are_padded_with_random_bytes() {
+    for message in every_message() {
+        let first = message.encode(0);
+        let second = message.encode(0);
+        // at least a byte of padding follows every message's fields
+        assert_ne!(
+            first,
+            second,
+            "{:?} has no random padding",
+            message.msg_type()
+        );
+        assert_eq!(Message::decode(&first).unwrap(), message);
+        assert_eq!(Message::decode(&second).unwrap(), message);
+    }
+}
+
+#[test]
+fn closes_connection_on_replayed_message() {
+    let test_bank = bank_with_user(ServerConfig::default());
+    let mut manager = test_bank.connect();
+    let mut comm_count = 0;
+
+    let captured = Message::from(login("amy", 1234)).encode(comm_count);
+    manager
+        .send_message(login("amy", 1234), &mut comm_count)
+        .unwrap();
+    let response = manager.receive(&mut comm_count).unwrap();
+    assert!(matches!(response, Message::AuthResult(result) if result.authenticated));
+
+    // resend the captured login with its stale counter
+    manager.send_frame(&captured).unwrap();
+    assert!(matches!(
+        manager.receive(&mut comm_count),
+        Err(ReceiveError::EndOfStream)
+    ));
+}
+
+#[test]
+fn closes_connection_on_out_of_range_pin() {
+    let test_bank = bank_with_user(ServerConfig::default());
+    let mut manager = test_bank.connect();
+
+    // a PIN of 65535 fits in its two bytes but is not 4 digits
+    let mut frame = Message::from(login("amy", 1234)).encode(0);
+    let pin_idx = MESSAGE_START_IDX + USERNAME_LEN_SIZE + MAX_USERNAME_SIZE;
+    frame[pin_idx..pin_idx + PIN_SIZE].copy_from_slice(&u16::MAX.to_be_bytes());
+    manager.send_frame(&frame).unwrap();
+
+    let mut comm_count = 1;
+    assert!(matches!(
+        manager.receive(&mut comm_count),
+        Err(ReceiveError::EndOfStream)
+    ));
+}
+
+#[test]
+fn closes_connection_on_unauthenticated_transaction() {
+    let test_bank = bank_with_user(ServerConfig::default());
+    let mut manager = test_bank.connect();
+    let mut comm_count = 0;
+
+    manager
+        .send_message(
+            WithdrawRequest {
+                amount: Amount::from_dollars(50.0).unwrap(),
+            },
+            &mut comm_count,
+        )
+        .unwrap();
+
+    assert!(matches!(
+        manager.receive(&mut comm_count),
+        Err(ReceiveError::EndOfStream)
+    ));
+    assert_eq!(test_bank.bank().balance("amy").unwrap(), dollars(100.0));
+}
+
+#[test]
+fn closes_connection_on_balance_request_for_another_user() {
+    let test_bank = bank_with_user(ServerConfig::default());
+    test_bank
+        .bank()
+        .create_account("bob", 42, dollars(5.0))
+        .unwrap();
+    let mut manager = test_bank.connect();
+    let mut comm_count = 0;
+
+    manager
+        .send_message(login("bob", 42), &mut comm_count)
+        .unwrap();
+    manager.receive(&mut comm_count).unwrap();
+
+    let request = BalanceRequest {
+        username: Username::new("amy").unwrap(),
+    };
+    manager.send_message(request, &mut comm_count).unwrap();
+    assert!(matches!(
+        manager.receive(&mut comm_count),
+        Err(ReceiveError::EndOfStream)
+    ));
+}
+
+#[test]
+fn turns_away_connections_over_the_limit() {
+    let test_bank = bank_with_user(ServerConfig {
+        limits: ConnectionLimits {
+            max_connections: 1,
+            max_per_peer: 1,
+        },
+        ..ServerConfig::default()
+    });
+    let mut first = test_bank.client();
+    // a completed request shows the first connection has been accepted
+    assert!(first.authenticate("amy", 1234).unwrap());
+
+    // the bank says it is busy in place of its half of the handshake
+    let second = test_bank.client_as(&AtmCredentials::default());
+    assert!(matches!(second, Err(ClientError::Busy)));
+    assert_eq!(first.balance().unwrap(), dollars(100.0));
+}
+
+#[test]
+fn holds_replies_for_the_minimum_response_time() {
+    let minimum = Duration::from_millis(200);
+    let test_bank = bank_with_user(ServerConfig {
+        timeouts: SessionTimeouts {
+            min_response: ResponseTimes {
+                auth: minimum,
+                query: minimum,
+                transaction: minimum,
+            },
+            ..SessionTimeouts::default()
+        },
+        ..ServerConfig::default()
+    });
+    let mut client = test_bank.client();
+
+    // an unknown user takes as long to turn away as a wrong pin
+    let timed = |request: &mut dyn FnMut() -> bool| {
+        let start = Instant::now();
+        assert!(request());
+        start.elapsed()
+    };
+    assert!(timed(&mut || !client.authenticate("zed", 1234).unwrap()) >= minimum);
+    assert!(timed(&mut || !client.authenticate("amy", 4321).unwrap()) >= minimum);
+    assert!(timed(&mut || client.authenticate("amy", 1234).unwrap()) >= minimum);
+    assert!(timed(&mut || client.balance().is_ok()) >= minimum);
+    assert!(timed(&mut || client.withdraw(dollars(10.0)).is_ok()) >= minimum);
+}
+
+#[test]
+fn ends_idle_sessions() {
+    let test_bank = bank_with_user(ServerConfig {
+        timeouts: SessionTimeouts {
+            idle: Duration::from_millis(300),
+            ..SessionTimeouts::default()
+        },
+        ..ServerConfig::default()
+    });
+    let mut client = test_bank.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    thread::sleep(SETTLE_TIME);
+    assert_eq!(
+        client.poll_notice().unwrap(),
+        Some(BankNotice::Ended(EndReason::Idle))
+    );
+    assert_eq!(client.user(), None);
+}
+
+#[test]
+fn heartbeats_do_not_keep_idle_sessions_alive() {
+    let idle = Duration::from_millis(300);
+    let test_bank = bank_with_user(ServerConfig {
+        timeouts: SessionTimeouts {
+            idle,
+            ..SessionTimeouts::default()
+        },
+        ..ServerConfig::default()
+    });
+    let mut client = test_bank.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    // the bank answers heartbeats until the session has sat idle too long
+    let start = Instant::now();
+    let ended = loop {
+        match client.heartbeat() {
+            Ok(()) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => break e,
+        }
+        assert!(start.elapsed() < 2 * SETTLE_TIME, "session was kept alive");
+    };
+    assert!(start.elapsed() >= idle);
+    assert!(
+        matches!(ended, ClientError::SessionEnded(EndReason::Idle)),
+        "{ended:?}"
+    );
+    assert_eq!(client.user(), None);
+}
+
+#[test]
+fn ends_sessions_on_shutdown() {
+    let test_bank = bank_with_user(ServerConfig::default());
+    let mut client = test_bank.client();
+    client.authenticate("amy", 1234).unwrap();
+    assert_eq!(client.poll_notice().unwrap(), None);
+
+    test_bank.stop();
+    thread::sleep(SETTLE_TIME);
+    assert_eq!(
+        client.poll_notice().unwrap(),
+        Some(BankNotice::Ended(EndReason::Maintenance))
+    );
+}
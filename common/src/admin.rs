@@ -0,0 +1,299 @@
+use self::errors::AdminError;
+use crate::{
+    crypto::{
+        errors::HandshakeError, AtmCredentials, AtmKeys, BankSecret, Channel, RECORD_OVERHEAD,
+    },
+    io::{accept_channel, open_channel, set_timeouts, Timeouts},
+    transport::Transport,
+};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    str,
+    time::{Duration, Instant},
+};
+
+/// Default address the bank listens on for admin consoles, and consoles connect to
+pub const ADMIN_SERVER_ADDR: &str = "127.0.0.1:32010";
+/// Longest record either side accepts, before sealing
+pub const MAX_RECORD_SIZE: usize = 1 << 20;
+/// Length of the prefix giving a sealed record's length
+const LENGTH_SIZE: usize = 4;
+
+/// Messages a console sends to the bank
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminRequest {
+    /// Logs an operator in. Every other request needs an operator logged in
+    Login { name: String, password: String },
+    /// A line typed at the console's prompt
+    Command(String),
+}
+
+/// Messages the bank sends to a console
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminReply {
+    /// The operator is logged in. Holds a greeting listing their commands
+    LoggedIn(String),
+    /// The request was turned down, with the reason why
+    Refused(String),
+    /// What a command had to say
+    Output(String),
+    /// The bank ended the session, with anything left to say. The bank may
+    /// send this unprompted, for example when it shuts down
+    Ended(String),
+}
+
+impl AdminRequest {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            // operator names and passwords never hold a line break
+            Self::Login { name, password } => encode(0, &format!("{name}\n{password}")),
+            Self::Command(line) => encode(1, line),
+        }
+    }
+    fn decode(record: &[u8]) -> Result<Self, AdminError> {
+        match decode(record)? {
+            (0, login) => {
+                let (name, password) = login.split_once('\n').ok_or(AdminError::Malformed)?;
+                Ok(Self::Login {
+                    name: name.to_string(),
+                    password: password.to_string(),
+                })
+            }
+            (1, line) => Ok(Self::Command(line.to_string())),
+            _ => Err(AdminError::Malformed),
+        }
+    }
+}
+
+impl AdminReply {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::LoggedIn(text) => encode(0, text),
+            Self::Refused(text) => encode(1, text),
+            Self::Output(text) => encode(2, text),
+            Self::Ended(text) => encode(3, text),
+        }
+    }
+    fn decode(record: &[u8]) -> Result<Self, AdminError> {
+        match decode(record)? {
+            (0, text) => Ok(Self::LoggedIn(text.to_string())),
+            (1, text) => Ok(Self::Refused(text.to_string())),
+            (2, text) => Ok(Self::Output(text.to_string())),
+            (3, text) => Ok(Self::Ended(text.to_string())),
+            _ => Err(AdminError::Malformed),
+        }
+    }
+}
+
+/// Writes a record as its type followed by its text
+fn encode(kind: u8, text: &str) -> Vec<u8> {
+    let mut record = Vec::with_capacity(1 + text.len());
+    record.push(kind);
+    record.extend_from_slice(text.as_bytes());
+    record
+}
+
+/// Reads a record written by `encode`
+fn decode(record: &[u8]) -> Result<(u8, &str), AdminError> {
+    let (kind, text) = record.split_first().ok_or(AdminError::Malformed)?;
+    let text = str::from_utf8(text).map_err(|_| AdminError::Malformed)?;
+    Ok((*kind, text))
+}
+
+/// A channel between the bank and an admin console, which runs the bank's
+/// command line from elsewhere. Every record is sealed with the channel
+/// established when the stream was opened.
+///
+/// A console opens the channel with the same handshake as an ATM, presenting
+/// its own id and pre-shared key, and then trades records rather than fixed
+/// size frames, as command output can be any length. Each record is sent as
+/// its sealed length, a big endian u32, followed by the sealed record
+pub struct AdminChannel {
+    stream: Box<dyn Transport>,
+    channel: Channel,
+    /// Id of the console on the other end, on the bank's side of the channel
+    console_id: String,
+    /// Longest wait for the rest of a record once it starts to arrive
+    record_timeout: Option<Duration>,
+    /// Sequence number of the next record to send
+    sent: u64,
+    /// Sequence number of the next record expected
+    received: u64,
+}
+
+impl AdminChannel {
+    //
+    // constructors
+
+    /// Consumes a stream to the bank's admin listener and opens a channel over
+    /// it with the given console's credentials
+    pub fn open(
+        mut stream: Box<dyn Transport>,
+        timeouts: Timeouts,
+        credentials: &AtmCredentials,
+    ) -> Result<Self, HandshakeError> {
+        set_timeouts(stream.as_ref(), timeouts)?;
+        let channel = open_channel(stream.as_mut(), credentials)?;
+        Ok(Self::new(stream, timeouts, channel, credentials.id.clone()))
+    }
+    /// Consumes a stream from a console and accepts a channel over it, if the
+    /// console presents credentials matching the registered keys
+    pub fn accept(
+        mut stream: Box<dyn Transport>,
+        timeouts: Timeouts,
+        keys: &AtmKeys,
+        noise_key: Option<&BankSecret>,
+    ) -> Result<Self, HandshakeError> {
+        set_timeouts(stream.as_ref(), timeouts)?;
+        let accepted = accept_channel(stream.as_mut(), keys, noise_key)?;
+        Ok(Self::new(
+            stream,
+            timeouts,
+            accepted.channel,
+            accepted.atm_id,
+        ))
+    }
+    fn new(
+        stream: Box<dyn Transport>,
+        timeouts: Timeouts,
+        channel: Channel,
+        console_id: String,
+    ) -> Self {
+        Self {
+            stream,
+            channel,
+            console_id,
+            record_timeout: timeouts.read,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Returns the id of the console this channel was opened by
+    pub fn console_id(&self) -> &str {
+        &self.console_id
+    }
+
+    /// Changes how long to wait for a record to arrive. Once one starts to
+    /// arrive, the rest of it is waited for as long as the read timeout the
+    /// stream was opened with
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    //
+    // requests and replies
+
+    pub fn send_request(&mut self, request: &AdminRequest) -> Result<(), AdminError> {
+        self.send_record(&request.encode())
+    }
+    pub fn receive_request(&mut self) -> Result<AdminRequest, AdminError> {
+        AdminRequest::decode(&self.receive_record()?)
+    }
+    pub fn send_reply(&mut self, reply: &AdminReply) -> Result<(), AdminError> {
+        self.send_record(&reply.encode())
+    }
+    pub fn receive_reply(&mut self) -> Result<AdminReply, AdminError> {
+        AdminReply::decode(&self.receive_record()?)
+    }
+
+    //
+    // records
+
+    /// Seals a record and writes it to the stream with its length
+    fn send_record(&mut self, record: &[u8]) -> Result<(), AdminError> {
+        if record.len() > MAX_RECORD_SIZE {
+            return Err(AdminError::Oversized);
+        }
+        let sealed = self.channel.seal_record(self.sent, record);
+        self.sent += 1;
+        let mut buf = (sealed.len() as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(&sealed);
+        self.stream.write_all(&buf).map_err(stream_error)
+    }
+
+    /// Blocks until a whole record is read from the stream, then opens it
+    fn receive_record(&mut self) -> Result<Vec<u8>, AdminError> {
+        let mut length = [0u8; LENGTH_SIZE];
+        // only a wait for a record to start counts as a timeout: once it has
+        // started, the rest is expected to follow
+        let read = match self.stream.read(&mut length) {
+            Ok(0) => return Err(AdminError::Closed),
+            Ok(read) => read,
+            Err(e) => return Err(stream_error(e)),
+        };
+        let deadline = self.record_timeout.map(|timeout| Instant::now() + timeout);
+        self.read_rest(&mut length[read..], deadline)?;
+        let length = u32::from_be_bytes(length) as usize;
+        if !(RECORD_OVERHEAD..=RECORD_OVERHEAD + MAX_RECORD_SIZE).contains(&length) {
+            return Err(AdminError::Oversized);
+        }
+
+        let mut sealed = vec![0u8; length];
+        self.read_rest(&mut sealed, deadline)?;
+        let record = self
+            .channel
+            .open_record(self.received, &sealed)
+            .ok_or(AdminError::Forged)?;
+        self.received += 1;
+        Ok(record)
+    }
+
+    /// Reads the rest of a record which has started to arrive. The rest may
+    /// arrive split across several reads, each of which may outlast a read
+    /// timeout shorter than the record timeout
+    fn read_rest(&mut self, buf: &mut [u8], deadline: Option<Instant>) -> Result<(), AdminError> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.stream.read(&mut buf[read..]) {
+                Ok(0) => return Err(AdminError::Closed),
+                Ok(more) => read += more,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(AdminError::Incomplete);
+                    }
+                }
+                Err(_) => return Err(AdminError::Closed),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Maps a failure on the stream onto its cause
+fn stream_error(e: io::Error) -> AdminError {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => AdminError::TimedOut,
+        _ => AdminError::Closed,
+    }
+}
+
+/// Error types related to the admin protocol
+pub mod errors {
+    use thiserror::Error;
+
+    /// Error sending or receiving a record on an admin channel
+    #[derive(Debug, Error, PartialEq, Eq)]
+    pub enum AdminError {
+        /// Stream has been closed
+        #[error("This stream has been closed")]
+        Closed,
+        /// Nothing arrived, or could be written, before the stream's timeout elapsed
+        #[error("Timed out waiting for the other party.")]
+        TimedOut,
+        /// A record started to arrive, but the rest of it did not arrive in time
+        #[error("Timed out partway through a record. Stream must be closed.")]
+        Incomplete,
+        /// A record was longer than either side accepts
+        #[error("Record is too long.")]
+        Oversized,
+        /// Received record was not sealed by the other party, or was altered,
+        /// replayed or reordered on the way
+        #[error("Record failed authentication. An adversary may have forged or altered it.")]
+        Forged,
+        /// Received record could not be decoded
+        #[error("Received record could not be decoded.")]
+        Malformed,
+    }
+}
@@ -0,0 +1,222 @@
+#[cfg(unix)]
+use crate::transport::SocketFile;
+use crate::{
+    crypto::{
+        errors::HandshakeError, AtmKeys, BankSecret, Channel, ServerHandshake,
+        PROTOCOL_CHOICE_SIZE, SEALED_FRAME_SIZE,
+    },
+    io::{
+        errors::{ReceiveError, SendError},
+        handshake_error, validate, Timeouts,
+    },
+    message::{Frame, Message},
+    transport::{Listener, PeerAddr},
+};
+use std::{
+    future::Future,
+    io::{self, ErrorKind},
+    time::Duration,
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    time,
+};
+
+/// A connection messages can be sent over on a tokio runtime.
+/// Async equivalent of `Transport`
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncTransport for T {}
+
+/// Accepts connections on a tokio runtime. Async equivalent of `Listener`
+pub enum AsyncListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        _file: SocketFile,
+    },
+}
+
+impl AsyncListener {
+    /// Registers a bound listener with the current runtime. Must be called from
+    /// within a runtime. In-memory listeners are not supported
+    pub fn from_std(listener: Listener) -> io::Result<Self> {
+        match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(Self::Tcp(TcpListener::from_std(listener)?))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, file } => {
+                listener.set_nonblocking(true)?;
+                Ok(Self::Unix {
+                    listener: UnixListener::from_std(listener)?,
+                    _file: file,
+                })
+            }
+            Listener::Memory { .. } => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "in-memory listeners cannot be used on an async runtime",
+            )),
+        }
+    }
+
+    /// Waits for a connection
+    pub async fn accept(&self) -> io::Result<(Box<dyn AsyncTransport>, PeerAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Tcp(peer)))
+            }
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Local))
+            }
+        }
+    }
+}
+
+/// Async equivalent of `StreamManager` for use on a tokio runtime.
+/// Speaks exactly the same protocol
+pub struct AsyncStreamManager {
+    stream: Box<dyn AsyncTransport>,
+    timeouts: Timeouts,
+    /// Longest wait for the rest of a message once its first bytes arrive
+    frame_timeout: Option<Duration>,
+    channel: Channel,
+    /// Id of the ATM on the other end
+    atm_id: String,
+}
+
+impl AsyncStreamManager {
+    //
+    // constructors
+
+    /// Consumes a stream from an ATM and accepts a channel over it, if the
+    /// ATM presents credentials matching the registered keys. The Noise
+    /// handshake is offered if the bank has a Noise key
+    pub async fn accept(
+        mut stream: Box<dyn AsyncTransport>,
+        timeouts: Timeouts,
+        keys: &AtmKeys,
+        noise_key: Option<&BankSecret>,
+    ) -> Result<Self, HandshakeError> {
+        let (handshake, hello) = ServerHandshake::start(noise_key);
+        write_handshake(&mut stream, timeouts, &hello).await?;
+        let mut choice = [0u8; PROTOCOL_CHOICE_SIZE];
+        read_handshake(&mut stream, timeouts, &mut choice).await?;
+        let protocol = handshake.choose(choice[0])?;
+        let mut reply = vec![0u8; protocol.client_hello_size()];
+        read_handshake(&mut stream, timeouts, &mut reply).await?;
+        let accepted = handshake.finish(protocol, &reply, keys)?;
+        write_handshake(&mut stream, timeouts, &accepted.reply).await?;
+        Ok(Self {
+            stream,
+            timeouts,
+            frame_timeout: timeouts.read,
+            channel: accepted.channel,
+            atm_id: accepted.atm_id,
+        })
+    }
+
+    /// Returns the id of the ATM this channel was opened by. Empty for an
+    /// anonymous ATM
+    pub fn atm_id(&self) -> &str {
+        &self.atm_id
+    }
+
+    /// Changes how long to wait for a message to arrive. Once one starts to
+    /// arrive, the rest of it is waited for as long as the read timeout the
+    /// stream was accepted with
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeouts.read = timeout;
+    }
+
+    //
+    // low level send / receive helpers
+
+    /// Writes a given message to the stream and increments communication count
+    pub async fn send_message(
+        &mut self,
+        message: impl Into<Message>,
+        comm_count: &mut u32,
+    ) -> Result<(), SendError> {
+        self.send_frame(&message.into().encode(*comm_count)).await?;
+        *comm_count += 1;
+        Ok(())
+    }
+
+    /// Seals the given frame and writes it to the stream
+    pub async fn send_frame(&mut self, frame: &Frame) -> Result<(), SendError> {
+        let sealed = self.channel.seal(frame);
+        match with_timeout(self.timeouts.write, self.stream.write_all(&sealed)).await {
+            None => Err(SendError::TimedOut),
+            Some(Err(_)) => Err(SendError::Closed),
+            Some(Ok(())) => Ok(()),
+        }
+    }
+
+    /// Waits until a full message is read from the stream and validates it
+    pub async fn receive(&mut self, comm_count: &mut u32) -> Result<Message, ReceiveError> {
+        let mut buf = [0u8; SEALED_FRAME_SIZE];
+        // reads are cancel safe: a timeout here loses no data
+        let read = match with_timeout(self.timeouts.read, self.stream.read(&mut buf)).await {
+            None => return Err(ReceiveError::TimedOut),
+            Some(Ok(0)) => return Err(ReceiveError::EndOfStream),
+            Some(Ok(read)) => read,
+            Some(Err(e)) if e.kind() == ErrorKind::TimedOut => return Err(ReceiveError::TimedOut),
+            Some(Err(_)) => return Err(ReceiveError::EndOfStream),
+        };
+        // a message may arrive split across several reads
+        if read < SEALED_FRAME_SIZE {
+            let rest = self.stream.read_exact(&mut buf[read..]);
+            match with_timeout(self.frame_timeout, rest).await {
+                None => return Err(ReceiveError::Incomplete),
+                Some(Ok(_)) => (),
+                Some(Err(_)) => return Err(ReceiveError::EndOfStream),
+            }
+        }
+        let frame = self.channel.open(&buf).ok_or(ReceiveError::Forged)?;
+        validate(frame, comm_count)
+    }
+}
+
+/// Reads a whole handshake message from the stream
+async fn read_handshake(
+    stream: &mut Box<dyn AsyncTransport>,
+    timeouts: Timeouts,
+    buf: &mut [u8],
+) -> Result<(), HandshakeError> {
+    match with_timeout(timeouts.read, stream.read_exact(buf)).await {
+        None => Err(HandshakeError::TimedOut),
+        Some(Err(e)) => Err(handshake_error(e)),
+        Some(Ok(_)) => Ok(()),
+    }
+}
+
+/// Writes a whole handshake message to the stream
+async fn write_handshake(
+    stream: &mut Box<dyn AsyncTransport>,
+    timeouts: Timeouts,
+    buf: &[u8],
+) -> Result<(), HandshakeError> {
+    match with_timeout(timeouts.write, stream.write_all(buf)).await {
+        None => Err(HandshakeError::TimedOut),
+        Some(Err(e)) => Err(handshake_error(e)),
+        Some(Ok(())) => Ok(()),
+    }
+}
+
+/// Runs a future to completion, or until the timeout elapses if there is one.
+/// Returns `None` on timeout
+async fn with_timeout<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        None => Some(future.await),
+        Some(timeout) => time::timeout(timeout, future).await.ok(),
+    }
+}
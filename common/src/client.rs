@@ -0,0 +1,313 @@
+use self::errors::ClientError;
+use crate::{
+    crypto::{errors::HandshakeError, AtmCredentials},
+    io::{StreamManager, Timeouts},
+    message::{
+        errors::FieldError, Amount, AuthRequest, BalanceRequest, DepositRequest, EndReason,
+        EndSession, Heartbeat, Message, MessageType, Pin, TransactionStatus, TransferRequest,
+        Username, WithdrawRequest,
+    },
+    transport::{Endpoint, Transport},
+};
+use rand_core::{OsRng, RngCore};
+use std::time::{Duration, Instant};
+
+/// Messages the bank sends without being asked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BankNotice {
+    /// The bank ended the session, for example because it is shutting down
+    Ended(EndReason),
+}
+
+/// When to send the next heartbeat. Each wait is drawn at random from half to
+/// one and a half times the interval, so heartbeats do not fall into a rhythm
+/// which tells them apart from requests
+#[derive(Debug, Clone)]
+pub struct HeartbeatSchedule {
+    interval: Duration,
+    next: Instant,
+}
+
+impl HeartbeatSchedule {
+    /// Schedules the first heartbeat
+    pub fn new(interval: Duration) -> Self {
+        let mut schedule = Self {
+            interval,
+            next: Instant::now(),
+        };
+        schedule.reschedule();
+        schedule
+    }
+
+    /// Returns when the next heartbeat is due
+    pub fn next(&self) -> Instant {
+        self.next
+    }
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next
+    }
+    /// Schedules the next heartbeat, counting from now
+    pub fn reschedule(&mut self) {
+        let jitter = OsRng.next_u32() as f64 / u32::MAX as f64;
+        self.next = Instant::now() + self.interval.mul_f64(0.5 + jitter);
+    }
+}
+
+/// Speaks the ATM side of the bank protocol over a single connection.
+///
+/// Each method sends one request and waits for the bank's reply. Requests for
+/// an account can only be made once a user has authenticated, and act on that
+/// user's account
+pub struct BankClient {
+    manager: StreamManager,
+    /// Tracks number of communications. Incremented after SEND and RECEIVE
+    comm_count: u32,
+    /// User authenticated on this connection, if any
+    user: Option<Username>,
+}
+
+impl BankClient {
+    //
+    // constructors
+
+    /// Connects to the bank at the given endpoint and opens a channel to it
+    /// with the given ATM's credentials
+    pub fn connect(
+        endpoint: &Endpoint,
+        timeouts: Timeouts,
+        credentials: &AtmCredentials,
+    ) -> Result<Self, ClientError> {
+        let stream = endpoint.connect().map_err(ClientError::Connect)?;
+        Self::open(stream, timeouts, credentials)
+    }
+    /// Opens a channel to the bank over an existing connection with the given
+    /// ATM's credentials
+    pub fn open(
+        stream: Box<dyn Transport>,
+        timeouts: Timeouts,
+        credentials: &AtmCredentials,
+    ) -> Result<Self, ClientError> {
+        let manager = StreamManager::open(stream, timeouts, credentials).map_err(|e| match e {
+            HandshakeError::Busy => ClientError::Busy,
+            e => ClientError::Handshake(e),
+        })?;
+        Ok(Self::from_manager(manager))
+    }
+    /// Speaks the protocol over an existing connection to the bank
+    pub fn from_manager(manager: StreamManager) -> Self {
+        Self {
+            manager,
+            comm_count: 0,
+            user: None,
+        }
+    }
+
+    //
+    // session state
+
+    /// Returns the user authenticated on this connection, if any
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_ref().map(Username::as_str)
+    }
+
+    /// Checks for a message the bank sent unprompted, without blocking.
+    /// Returns `None` if the bank has not sent anything
+    pub fn poll_notice(&mut self) -> Result<Option<BankNotice>, ClientError> {
+        let message = match self.manager.poll(&mut self.comm_count)? {
+            None => return Ok(None),
+            Some(message) => message,
+        };
+        match Self::check_notice(&message) {
+            Err(ClientError::SessionEnded(reason)) => {
+                self.user = None;
+                Ok(Some(BankNotice::Ended(reason)))
+            }
+            Err(e) => Err(e),
+            Ok(()) => Err(ClientError::UnexpectedReply(message.msg_type())),
+        }
+    }
+
+    //
+    // requests
+
+    /// Asks the bank to authenticate the given user. On success, later
+    /// requests act on this user's account
+    pub fn authenticate(&mut self, username: &str, pin: u16) -> Result<bool, ClientError> {
+        let username = check_username(username)?;
+        let pin = Pin::new(pin).map_err(|_| ClientError::InvalidPin)?;
+
+        let request = AuthRequest {
+            username: username.clone(),
+            pin,
+        };
+        self.manager.send_message(request, &mut self.comm_count)?;
+        let authenticated = match self.reply(MessageType::AuthResult)? {
+            Message::AuthResult(result) => result.authenticated,
+            _ => unreachable!("reply is checked to be an AuthResult"),
+        };
+        self.user = authenticated.then_some(username);
+        Ok(authenticated)
+    }
+
+    /// Retrieves the authenticated user's balance
+    pub fn balance(&mut self) -> Result<Amount, ClientError> {
+        let username = self.user.clone().ok_or(ClientError::NotAuthenticated)?;
+        self.manager
+            .send_message(BalanceRequest { username }, &mut self.comm_count)?;
+        match self.reply(MessageType::BalanceResponse)? {
+            Message::BalanceResponse(response) => Ok(response.balance),
+            _ => unreachable!("reply is checked to be a BalanceResponse"),
+        }
+    }
+
+    /// Takes money out of the authenticated user's account. Returns the balance left
+    pub fn withdraw(&mut self, amount: Amount) -> Result<Amount, ClientError> {
+        self.transaction(amount, |amount| WithdrawRequest { amount }.into())
+    }
+
+    /// Puts money into the authenticated user's account. Returns the new balance
+    pub fn deposit(&mut self, amount: Amount) -> Result<Amount, ClientError> {
+        self.transaction(amount, |amount| DepositRequest { amount }.into())
+    }
+
+    /// Moves money from the authenticated user's account into another user's.
+    /// Returns the balance left in the authenticated user's account
+    pub fn transfer(&mut self, recipient: &str, amount: Amount) -> Result<Amount, ClientError> {
+        let recipient = check_username(recipient)?;
+        self.transaction(amount, |amount| {
+            TransferRequest { recipient, amount }.into()
+        })
+    }
+
+    /// Ends the authenticated user's session. The connection stays open for
+    /// another user to authenticate
+    pub fn end_session(&mut self) -> Result<(), ClientError> {
+        self.user = None;
+        let request = EndSession {
+            reason: EndReason::Requested,
+        };
+        self.manager.send_message(request, &mut self.comm_count)?;
+        self.reply(MessageType::EndSession)?;
+        Ok(())
+    }
+
+    /// Sends a heartbeat and waits for the bank to answer it. Nothing changes
+    /// on the bank, but an error shows the connection is no longer usable
+    pub fn heartbeat(&mut self) -> Result<(), ClientError> {
+        self.manager
+            .send_message(Heartbeat {}, &mut self.comm_count)?;
+        self.reply(MessageType::Heartbeat)?;
+        Ok(())
+    }
+
+    //
+    // helpers
+
+    /// Sends a withdrawal, deposit or transfer and interprets the bank's verdict
+    fn transaction(
+        &mut self,
+        amount: Amount,
+        request: impl FnOnce(Amount) -> Message,
+    ) -> Result<Amount, ClientError> {
+        if self.user.is_none() {
+            return Err(ClientError::NotAuthenticated);
+        }
+
+        self.manager
+            .send_message(request(amount), &mut self.comm_count)?;
+
+        match self.reply(MessageType::TransactionResult)? {
+            Message::TransactionResult(result) => match result.status {
+                TransactionStatus::Approved => Ok(result.balance),
+                status => Err(ClientError::Declined(status)),
+            },
+            _ => unreachable!("reply is checked to be a TransactionResult"),
+        }
+    }
+
+    /// Receives the bank's reply to a request, which must be of the expected type
+    fn reply(&mut self, expected: MessageType) -> Result<Message, ClientError> {
+        let message = self.manager.receive(&mut self.comm_count)?;
+
+        if message.msg_type() == expected {
+            return Ok(message);
+        }
+        // the bank may have ended the session instead of answering
+        if let Err(e) = Self::check_notice(&message) {
+            if matches!(e, ClientError::SessionEnded(_)) {
+                self.user = None;
+            }
+            return Err(e);
+        }
+        Err(ClientError::UnexpectedReply(message.msg_type()))
+    }
+
+    /// Turns a message the bank sends unprompted into the matching error.
+    /// Returns Ok if the message is not such a notice
+    fn check_notice(message: &Message) -> Result<(), ClientError> {
+        match message {
+            Message::EndSession(end) => Err(ClientError::SessionEnded(end.reason)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Ensures a username can be sent to the bank
+fn check_username(username: &str) -> Result<Username, ClientError> {
+    Username::new(username).map_err(|e| match e {
+        FieldError::UsernameTooLong(max) => ClientError::UsernameTooLong(max),
+        _ => ClientError::InvalidUsername,
+    })
+}
+
+/// Error types related to the bank client
+pub mod errors {
+    use crate::{
+        crypto::errors::HandshakeError,
+        io::errors::{ReceiveError, SendError},
+        message::{EndReason, MessageType, TransactionStatus},
+    };
+    use std::io;
+    use thiserror::Error;
+
+    /// Reasons a request to the bank did not succeed
+    #[derive(Debug, Error)]
+    pub enum ClientError {
+        /// The bank could not be reached
+        #[error("Could not connect to the bank: {0}")]
+        Connect(#[source] io::Error),
+        /// No secure channel could be established with the bank
+        #[error("Could not establish a secure channel with the bank: {0}")]
+        Handshake(#[source] HandshakeError),
+        /// The request could not be sent
+        #[error(transparent)]
+        Send(#[from] SendError),
+        /// No valid reply was received
+        #[error(transparent)]
+        Receive(#[from] ReceiveError),
+        /// The bank replied with a message that does not answer the request
+        #[error("Bank replied with an unexpected {0:?} message.")]
+        UnexpectedReply(MessageType),
+        /// The bank ended the session instead of answering
+        #[error("Bank ended the session: {0:?}.")]
+        SessionEnded(EndReason),
+        /// The bank is at its connection limit and closed the connection
+        #[error("Bank is too busy to serve this connection.")]
+        Busy,
+        /// The request needs an authenticated user
+        #[error("No user is authenticated.")]
+        NotAuthenticated,
+        /// Usernames may only contain letters
+        #[error("Username must only contain letters.")]
+        InvalidUsername,
+        /// The username does not fit in a message
+        #[error("Username must be {0} characters or less.")]
+        UsernameTooLong(usize),
+        /// Pins are 4 digits
+        #[error("PIN must be 4 digits.")]
+        InvalidPin,
+        /// The bank refused the transaction
+        #[error("Bank declined the transaction: {0:?}.")]
+        Declined(TransactionStatus),
+    }
+}
@@ -0,0 +1,111 @@
+use self::errors::ConfigError;
+use crate::transport::{Endpoint, UNIX_SCHEME};
+use serde::de::DeserializeOwned;
+use std::{
+    fs,
+    net::{SocketAddr, ToSocketAddrs},
+    path::Path,
+    time::Duration,
+};
+
+/// Reads and parses a TOML config file
+pub fn load_toml<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|e| ConfigError::Read {
+        path: path.display().to_string(),
+        source: e,
+    })?;
+    toml::from_str(&contents).map_err(|e| ConfigError::Parse {
+        path: path.display().to_string(),
+        source: e,
+    })
+}
+
+/// Resolves a `host:port` address, which may name a host rather than an IP
+pub fn resolve_addr(setting: &str, addr: &str) -> Result<SocketAddr, ConfigError> {
+    addr.to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| ConfigError::Invalid {
+            setting: setting.to_string(),
+            reason: format!("`{addr}` is not a valid host:port address"),
+        })
+}
+
+/// Parses an address which is either `host:port` or, on Unix, `unix:<path>`
+/// naming a Unix domain socket
+pub fn resolve_endpoint(setting: &str, addr: &str) -> Result<Endpoint, ConfigError> {
+    let Some(path) = addr.strip_prefix(UNIX_SCHEME) else {
+        return Ok(Endpoint::Tcp(resolve_addr(setting, addr)?));
+    };
+    if path.is_empty() {
+        return Err(ConfigError::Invalid {
+            setting: setting.to_string(),
+            reason: format!("`{addr}` does not name a socket path"),
+        });
+    }
+    #[cfg(unix)]
+    return Ok(Endpoint::Unix(path.into()));
+    #[cfg(not(unix))]
+    Err(ConfigError::Invalid {
+        setting: setting.to_string(),
+        reason: "Unix sockets are not supported on this platform".to_string(),
+    })
+}
+
+/// Parses the permissions of a Unix socket file, given as octal digits like `660`
+pub fn parse_socket_mode(setting: &str, mode: &str) -> Result<u32, ConfigError> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| ConfigError::Invalid {
+            setting: setting.to_string(),
+            reason: format!("`{mode}` is not an octal file mode such as 660"),
+        })
+}
+
+/// Converts a setting given in whole seconds into a Duration, rejecting zero
+pub fn nonzero_secs(setting: &str, secs: u64) -> Result<Duration, ConfigError> {
+    match secs {
+        0 => Err(ConfigError::Invalid {
+            setting: setting.to_string(),
+            reason: "must be at least 1 second".to_string(),
+        }),
+        secs => Ok(Duration::from_secs(secs)),
+    }
+}
+
+/// Rejects a count setting of zero
+pub fn nonzero_count(setting: &str, count: usize) -> Result<usize, ConfigError> {
+    match count {
+        0 => Err(ConfigError::Invalid {
+            setting: setting.to_string(),
+            reason: "must be at least 1".to_string(),
+        }),
+        count => Ok(count),
+    }
+}
+
+/// Error types related to configuration
+pub mod errors {
+    use thiserror::Error;
+
+    /// Errors loading or validating configuration
+    #[derive(Debug, Error)]
+    pub enum ConfigError {
+        /// Config file could not be read
+        #[error("Cannot read config file {path}: {source}")]
+        Read {
+            path: String,
+            source: std::io::Error,
+        },
+        /// Config file is not valid TOML or has unexpected settings
+        #[error("Cannot parse config file {path}: {source}")]
+        Parse {
+            path: String,
+            source: toml::de::Error,
+        },
+        /// A setting has a value which cannot be used
+        #[error("Invalid setting `{setting}`: {reason}")]
+        Invalid { setting: String, reason: String },
+    }
+}
@@ -1,4 +1,24 @@
-use x25519_dalek::{EphemeralSecret, PublicKey};
+use self::errors::HandshakeError;
+use crate::{
+    hex::{from_hex, to_hex},
+    message::{constants::MAX_PLAINTEXT_SIZE, Frame},
+};
+use blake2::{Blake2s256, Digest};
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    Key, Tag, XChaCha20Poly1305, XNonce,
+};
+use rand_core::{OsRng, RngCore};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::Path,
+    str,
+    sync::RwLock,
+};
+use subtle::ConstantTimeEq;
+use x25519_dalek::{x25519, EphemeralSecret, PublicKey, SharedSecret, X25519_BASEPOINT_BYTES};
 
 // for encryption sources see:
 //  https://cryptography.rs/
@@ -7,10 +27,309 @@ use x25519_dalek::{EphemeralSecret, PublicKey};
 //  x25519-dalek        v2       for key exchange: https://crates.io/crates/x25519-dalek
 //  blake2              v0.10.5  for KDF: https://crates.io/crates/blake2
 //  XChaCha20-Poly1305  v0.10.1  for encryption: https://docs.rs/chacha20poly1305/latest/chacha20poly1305/
+//  snow                v0.9     for the optional Noise handshake: https://crates.io/crates/snow
 
 const XCHACHA20_POLY1305_KEY_SIZE: usize = 32; // 32 byte key
 const XCHACHA20_POLY1305_NONCE_SIZE: usize = 24; // 24 byte nonce
+const POLY1305_TAG_SIZE: usize = 16; // 16 byte authentication tag
+const X25519_PUBLIC_KEY_SIZE: usize = 32; // 32 byte public key
 
+/// Length of the value each party sends to prove it derived the same keys
+pub const CONFIRMATION_SIZE: usize = 16;
+/// Length of a pre-shared key
+pub const PSK_SIZE: usize = 32;
+/// Length of the bank's static Noise keys, private and public
+pub const BANK_KEY_SIZE: usize = 32;
+/// Maximum length of an ATM's id
+pub const MAX_ATM_ID_SIZE: usize = 20;
+/// Length of an ATM's id as sent in a handshake: id length and padded id
+const ATM_ID_FIELD_SIZE: usize = 1 + MAX_ATM_ID_SIZE;
+
+/// Length of the bank's hello: protocols offered and public key
+pub const SERVER_HELLO_SIZE: usize = 1 + X25519_PUBLIC_KEY_SIZE;
+/// Length of the ATM's choice of protocol, sent ahead of its hello
+pub const PROTOCOL_CHOICE_SIZE: usize = 1;
+/// Length of the ATM's hello in the classic handshake: id field, public key
+/// and confirmation
+pub const CLIENT_HELLO_SIZE: usize = ATM_ID_FIELD_SIZE + X25519_PUBLIC_KEY_SIZE + CONFIRMATION_SIZE;
+/// Length of the ATM's Noise message: ephemeral key, encrypted static key
+/// and encrypted id field
+pub const NOISE_CLIENT_HELLO_SIZE: usize = X25519_PUBLIC_KEY_SIZE
+    + (BANK_KEY_SIZE + POLY1305_TAG_SIZE)
+    + (ATM_ID_FIELD_SIZE + POLY1305_TAG_SIZE);
+/// Length of the bank's Noise message: ephemeral key and an empty encrypted payload
+pub const NOISE_SERVER_REPLY_SIZE: usize = X25519_PUBLIC_KEY_SIZE + POLY1305_TAG_SIZE;
+/// Length of a frame on the wire: nonce, encrypted frame and tag
+pub const SEALED_FRAME_SIZE: usize =
+    XCHACHA20_POLY1305_NONCE_SIZE + MAX_PLAINTEXT_SIZE + POLY1305_TAG_SIZE;
+
+/// Bytes a sealed record takes up beyond the record itself: nonce and tag
+pub const RECORD_OVERHEAD: usize = XCHACHA20_POLY1305_NONCE_SIZE + POLY1305_TAG_SIZE;
+
+/// A frame as sent over the wire once sealed
+pub type SealedFrame = [u8; SEALED_FRAME_SIZE];
+
+/// Protocols offered by a bank hello which turns the connection away
+const BUSY: u8 = 0;
+
+/// Mixed into every derived key, so keys are never shared with another protocol
+const PROTOCOL_NAME: &[u8] = b"atm-bank X25519 XChaCha20Poly1305 BLAKE2s";
+/// Noise pattern of the Noise handshake. The ATM knows the bank's static key
+/// in advance, and the ATM's pre-shared key is mixed in once the bank has
+/// read the ATM's id
+const NOISE_PARAMS: &str = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+/// Position of the pre-shared key in the Noise pattern
+const NOISE_PSK_LOCATION: u8 = 2;
+/// Mixed in by ATMs without a pre-shared key, since the Noise pattern needs one
+const NO_PSK: [u8; PSK_SIZE] = [0; PSK_SIZE];
+
+/// Handshakes an ATM can choose from those the bank offers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// X25519 key agreement, with keys derived by BLAKE2s and confirmed by both sides
+    Classic = 0,
+    /// Noise IK handshake, which also authenticates the bank by its static key
+    /// and hides the ATM's id from eavesdroppers
+    NoiseIk = 1,
+}
+
+impl Protocol {
+    /// Looks up a protocol by the id an ATM sends to choose it
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Classic),
+            1 => Some(Self::NoiseIk),
+            _ => None,
+        }
+    }
+    /// Returns the length of the ATM's hello, after its choice of protocol
+    pub fn client_hello_size(self) -> usize {
+        match self {
+            Self::Classic => CLIENT_HELLO_SIZE,
+            Self::NoiseIk => NOISE_CLIENT_HELLO_SIZE,
+        }
+    }
+    /// Returns the length of the bank's reply to the ATM's hello
+    pub fn server_reply_size(self) -> usize {
+        match self {
+            Self::Classic => CONFIRMATION_SIZE,
+            Self::NoiseIk => NOISE_SERVER_REPLY_SIZE,
+        }
+    }
+
+    /// Returns the bit set in a bank hello which offers this protocol
+    fn offer(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Parses a key written as 64 hex digits
+fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    from_hex(hex)?.try_into().ok()
+}
+
+/// Reads a key file holding a key written as 64 hex digits
+fn load_key(path: &Path) -> io::Result<[u8; 32]> {
+    parse_key(fs::read_to_string(path)?.trim())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "key must be 64 hex digits"))
+}
+
+/// A secret key shared ahead of time between the bank and one ATM, such as a
+/// factory key. It is mixed into the session keys, so an ATM and a bank which
+/// do not hold the same key cannot establish a channel
+#[derive(Clone)]
+pub struct Psk([u8; PSK_SIZE]);
+
+impl Psk {
+    /// Parses a key written as 64 hex digits
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        parse_key(hex).map(Self)
+    }
+    /// Reads a key file holding a key written as 64 hex digits
+    pub fn load(path: &Path) -> io::Result<Self> {
+        load_key(path).map(Self)
+    }
+}
+
+impl From<[u8; PSK_SIZE]> for Psk {
+    fn from(key: [u8; PSK_SIZE]) -> Self {
+        Self(key)
+    }
+}
+
+// keys are kept out of logs and error messages
+impl fmt::Debug for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Psk(..)")
+    }
+}
+
+/// The bank's static Noise key. Its public half is given to every ATM which
+/// uses the Noise handshake, so the ATM can tell it is talking to this bank
+#[derive(Clone)]
+pub struct BankSecret([u8; BANK_KEY_SIZE]);
+
+impl BankSecret {
+    /// Parses a private key written as 64 hex digits
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        parse_key(hex).map(Self)
+    }
+    /// Reads a key file holding a private key written as 64 hex digits
+    pub fn load(path: &Path) -> io::Result<Self> {
+        load_key(path).map(Self)
+    }
+    /// Returns the public half of the key
+    pub fn public(&self) -> BankKey {
+        BankKey(x25519(self.0, X25519_BASEPOINT_BYTES))
+    }
+}
+
+impl From<[u8; BANK_KEY_SIZE]> for BankSecret {
+    fn from(key: [u8; BANK_KEY_SIZE]) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Debug for BankSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BankSecret(..)")
+    }
+}
+
+/// Public half of the bank's static Noise key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankKey([u8; BANK_KEY_SIZE]);
+
+impl BankKey {
+    /// Parses a public key written as 64 hex digits
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        parse_key(hex).map(Self)
+    }
+}
+
+impl fmt::Display for BankKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&to_hex(&self.0))
+    }
+}
+
+/// Checks an ATM id can be sent in a handshake: up to 20 letters, digits, `-` or `_`
+pub fn valid_atm_id(id: &str) -> bool {
+    id.len() <= MAX_ATM_ID_SIZE
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// What an ATM presents to the bank when opening a channel
+#[derive(Debug, Clone, Default)]
+pub struct AtmCredentials {
+    /// Names the ATM, so the bank can find its key. May be empty if the ATM
+    /// has no key
+    pub id: String,
+    /// Key provisioned for this ATM, if any
+    pub psk: Option<Psk>,
+    /// Bank's public Noise key. When set, the ATM only opens a channel with
+    /// the Noise handshake
+    pub bank_key: Option<BankKey>,
+}
+
+impl AtmCredentials {
+    /// Returns the handshake this ATM uses
+    fn protocol(&self) -> Protocol {
+        match self.bank_key {
+            None => Protocol::Classic,
+            Some(_) => Protocol::NoiseIk,
+        }
+    }
+}
+
+/// Pre-shared keys of the ATMs registered with the bank. While no ATM is
+/// registered, any ATM may open a channel without a key. The keys can be
+/// replaced while the bank is serving, and apply from the next handshake
+#[derive(Debug, Default)]
+pub struct AtmKeys {
+    keys: RwLock<HashMap<String, Psk>>,
+}
+
+impl Clone for AtmKeys {
+    fn clone(&self) -> Self {
+        Self {
+            keys: RwLock::new(self.keys.read().unwrap().clone()),
+        }
+    }
+}
+
+impl AtmKeys {
+    /// Loads the registered ATMs from the given key file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut keys = Self::default();
+        // each line registers a single ATM: `<atm-id> <key>`
+        for (line_num, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let invalid_record = |reason: &str| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid ATM record on line {}: {reason}", line_num + 1),
+                )
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (id, psk) = match fields[..] {
+                [] => continue,
+                [id, key] => (
+                    id,
+                    Psk::from_hex(key)
+                        .ok_or_else(|| invalid_record("key must be 64 hex digits"))?,
+                ),
+                _ => return Err(invalid_record("malformed")),
+            };
+            if !keys.register(id, psk) {
+                return Err(invalid_record("invalid or duplicate ATM id"));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Registers an ATM's key. Returns false, registering nothing, if the id
+    /// is empty, invalid or already registered
+    pub fn register(&mut self, id: &str, psk: Psk) -> bool {
+        let keys = self.keys.get_mut().unwrap();
+        if id.is_empty() || !valid_atm_id(id) || keys.contains_key(id) {
+            return false;
+        }
+        keys.insert(id.to_string(), psk);
+        true
+    }
+    /// Replaces every registered key with those of `other`, such as keys
+    /// loaded afresh from the key file
+    pub fn replace(&self, other: AtmKeys) {
+        *self.keys.write().unwrap() = other.keys.into_inner().unwrap();
+    }
+    /// Checks whether any ATM may open a channel without a key
+    pub fn is_open(&self) -> bool {
+        self.keys.read().unwrap().is_empty()
+    }
+
+    /// Returns the key the given ATM must hold, if it needs one, and whether
+    /// the ATM is registered. An ATM which is not is given a random key no
+    /// one holds, so its handshake does the same work as one with the wrong
+    /// key and only fails once its confirmation is checked
+    fn psk(&self, id: &str) -> (Option<Psk>, bool) {
+        let keys = self.keys.read().unwrap();
+        if keys.is_empty() {
+            return (None, true);
+        }
+        match keys.get(id) {
+            Some(psk) => (Some(psk.clone()), true),
+            None => {
+                let mut decoy = [0u8; PSK_SIZE];
+                OsRng.fill_bytes(&mut decoy);
+                (Some(Psk(decoy)), false)
+            }
+        }
+    }
+}
+
+/// An ephemeral X25519 key pair, used for a single handshake
 pub struct CryptoState {
     secret: EphemeralSecret,
     public: PublicKey,
@@ -25,4 +344,462 @@ impl CryptoState {
             secret,
         }
     }
+
+    /// Completes the key exchange with the other party's public key. Keys
+    /// which would leave the secret up to the other party are refused
+    fn agree(self, peer: [u8; X25519_PUBLIC_KEY_SIZE]) -> Result<SharedSecret, HandshakeError> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer));
+        if !shared.was_contributory() {
+            return Err(HandshakeError::Malformed);
+        }
+        Ok(shared)
+    }
+}
+
+impl Default for CryptoState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keys and confirmations derived by both parties from a handshake
+struct SessionKeys {
+    atm_to_bank: [u8; XCHACHA20_POLY1305_KEY_SIZE],
+    bank_to_atm: [u8; XCHACHA20_POLY1305_KEY_SIZE],
+    atm_confirmation: [u8; CONFIRMATION_SIZE],
+    bank_confirmation: [u8; CONFIRMATION_SIZE],
+}
+
+impl SessionKeys {
+    /// Derives the session's keys from the shared secret, the pre-shared key
+    /// if there is one, and everything both hellos carried in the clear
+    fn derive(
+        shared: &SharedSecret,
+        psk: Option<&Psk>,
+        server_hello: &[u8; SERVER_HELLO_SIZE],
+        client_fields: &[u8],
+    ) -> Self {
+        let derive = |label: &str| -> [u8; 32] {
+            let mut hash = Blake2s256::new();
+            hash.update(PROTOCOL_NAME);
+            hash.update([label.len() as u8]);
+            hash.update(label);
+            match psk {
+                None => hash.update([0]),
+                Some(psk) => {
+                    hash.update([1]);
+                    hash.update(psk.0);
+                }
+            }
+            hash.update(shared.as_bytes());
+            hash.update(server_hello);
+            hash.update(client_fields);
+            hash.finalize().into()
+        };
+        let confirmation = |label: &str| {
+            let mut confirmation = [0u8; CONFIRMATION_SIZE];
+            confirmation.copy_from_slice(&derive(label)[..CONFIRMATION_SIZE]);
+            confirmation
+        };
+        Self {
+            atm_to_bank: derive("atm to bank"),
+            bank_to_atm: derive("bank to atm"),
+            atm_confirmation: confirmation("atm confirmation"),
+            bank_confirmation: confirmation("bank confirmation"),
+        }
+    }
+}
+
+/// The bank's side of a handshake, after its hello has been sent
+pub struct ServerHandshake {
+    state: CryptoState,
+    hello: [u8; SERVER_HELLO_SIZE],
+    noise_key: Option<BankSecret>,
+}
+
+impl ServerHandshake {
+    /// Starts a handshake, offering the Noise handshake if the bank has a
+    /// Noise key. Returns the hello to send to the ATM
+    pub fn start(noise_key: Option<&BankSecret>) -> (Self, [u8; SERVER_HELLO_SIZE]) {
+        let state = CryptoState::new();
+        let mut hello = [0u8; SERVER_HELLO_SIZE];
+        hello[0] = Protocol::Classic.offer();
+        if noise_key.is_some() {
+            hello[0] |= Protocol::NoiseIk.offer();
+        }
+        hello[1..].copy_from_slice(state.public.as_bytes());
+        let handshake = Self {
+            state,
+            hello,
+            noise_key: noise_key.cloned(),
+        };
+        (handshake, hello)
+    }
+
+    /// Checks the protocol the ATM chose was offered
+    pub fn choose(&self, choice: u8) -> Result<Protocol, HandshakeError> {
+        Protocol::from_id(choice)
+            .filter(|protocol| self.hello[0] & protocol.offer() != 0)
+            .ok_or(HandshakeError::Unsupported)
+    }
+
+    /// Checks the ATM's hello against the registered keys. Returns the channel,
+    /// the ATM's id and the reply to send back to the ATM
+    pub fn finish(
+        self,
+        protocol: Protocol,
+        hello: &[u8],
+        keys: &AtmKeys,
+    ) -> Result<Accepted, HandshakeError> {
+        if hello.len() != protocol.client_hello_size() {
+            return Err(HandshakeError::Malformed);
+        }
+        match protocol {
+            Protocol::Classic => self.finish_classic(hello, keys),
+            Protocol::NoiseIk => self.finish_noise(hello, keys),
+        }
+    }
+
+    fn finish_classic(self, hello: &[u8], keys: &AtmKeys) -> Result<Accepted, HandshakeError> {
+        let (fields, confirmation) = hello.split_at(CLIENT_HELLO_SIZE - CONFIRMATION_SIZE);
+        let (id_field, public) = fields.split_at(ATM_ID_FIELD_SIZE);
+        let id = decode_atm_id(id_field)?;
+        let (psk, registered) = keys.psk(id);
+
+        let shared = self.state.agree(public.try_into().unwrap())?;
+        let keys = SessionKeys::derive(&shared, psk.as_ref(), &self.hello, fields);
+        let confirmed = bool::from(keys.atm_confirmation.ct_eq(confirmation));
+        if !registered {
+            return Err(HandshakeError::UnknownAtm(id.to_string()));
+        }
+        if !confirmed {
+            return Err(HandshakeError::KeyMismatch);
+        }
+        Ok(Accepted {
+            channel: Channel::new(&keys.bank_to_atm, &keys.atm_to_bank),
+            atm_id: id.to_string(),
+            reply: keys.bank_confirmation.to_vec(),
+        })
+    }
+
+    fn finish_noise(self, hello: &[u8], keys: &AtmKeys) -> Result<Accepted, HandshakeError> {
+        let secret = self.noise_key.ok_or(HandshakeError::Unsupported)?;
+        let mut handshake = noise_builder(&self.hello)
+            .local_private_key(&secret.0)
+            .build_responder()
+            .map_err(|_| HandshakeError::Malformed)?;
+
+        // only an ATM holding the bank's public key can have written the hello
+        let mut id_field = [0u8; NOISE_CLIENT_HELLO_SIZE];
+        let len = handshake
+            .read_message(hello, &mut id_field)
+            .map_err(|_| HandshakeError::KeyMismatch)?;
+        let id = decode_atm_id(&id_field[..len])?;
+        // the bank cannot check the ATM's key until its first frame, so an
+        // unregistered ATM is answered like one holding the wrong key
+        let psk = keys.psk(id).0.map_or(NO_PSK, |psk| psk.0);
+        handshake
+            .set_psk(NOISE_PSK_LOCATION.into(), &psk)
+            .map_err(|_| HandshakeError::Malformed)?;
+
+        let mut reply = vec![0u8; NOISE_SERVER_REPLY_SIZE];
+        handshake
+            .write_message(&[], &mut reply)
+            .map_err(|_| HandshakeError::Malformed)?;
+        let (atm_to_bank, bank_to_atm) = handshake.dangerously_get_raw_split();
+        Ok(Accepted {
+            channel: Channel::new(&bank_to_atm, &atm_to_bank),
+            atm_id: id.to_string(),
+            reply,
+        })
+    }
+}
+
+/// A handshake the bank has accepted
+pub struct Accepted {
+    pub channel: Channel,
+    /// Id the ATM presented. Empty for an anonymous ATM
+    pub atm_id: String,
+    /// Reply to send back to the ATM
+    pub reply: Vec<u8>,
+}
+
+/// Builds the hello the bank sends to a connection it turns away
+pub fn busy_hello() -> [u8; SERVER_HELLO_SIZE] {
+    let mut hello = [0u8; SERVER_HELLO_SIZE];
+    OsRng.fill_bytes(&mut hello[1..]);
+    hello[0] = BUSY;
+    hello
+}
+
+/// Starts a Noise handshake bound to the bank's hello, so a bank which
+/// offered other protocols cannot be made to look as if it offered only the
+/// classic one
+fn noise_builder(server_hello: &[u8; SERVER_HELLO_SIZE]) -> snow::Builder<'_> {
+    let params = NOISE_PARAMS
+        .parse()
+        .expect("Error: invalid Noise parameters");
+    snow::Builder::new(params).prologue(server_hello)
+}
+
+/// Where the ATM's side of a handshake has got to
+enum ClientState {
+    Classic(SessionKeys),
+    Noise(Box<snow::HandshakeState>),
+}
+
+/// The ATM's side of a handshake, after its hello has been sent
+pub struct ClientHandshake {
+    state: ClientState,
+}
+
+impl ClientHandshake {
+    /// Answers the bank's hello with the ATM's credentials. Returns the
+    /// ATM's choice of protocol followed by its hello, to send back to the bank
+    pub fn start(
+        hello: &[u8; SERVER_HELLO_SIZE],
+        credentials: &AtmCredentials,
+    ) -> Result<(Self, Vec<u8>), HandshakeError> {
+        let protocol = credentials.protocol();
+        match hello[0] {
+            BUSY => return Err(HandshakeError::Busy),
+            offers if offers & protocol.offer() == 0 => return Err(HandshakeError::Unsupported),
+            _ => (),
+        }
+        let mut reply = vec![protocol as u8];
+        let state = match protocol {
+            Protocol::Classic => Self::start_classic(hello, credentials, &mut reply)?,
+            Protocol::NoiseIk => Self::start_noise(hello, credentials, &mut reply)?,
+        };
+        Ok((Self { state }, reply))
+    }
+
+    fn start_classic(
+        hello: &[u8; SERVER_HELLO_SIZE],
+        credentials: &AtmCredentials,
+        reply: &mut Vec<u8>,
+    ) -> Result<ClientState, HandshakeError> {
+        let state = CryptoState::new();
+        let mut fields = [0u8; CLIENT_HELLO_SIZE - CONFIRMATION_SIZE];
+        encode_atm_id(&credentials.id, &mut fields[..ATM_ID_FIELD_SIZE])?;
+        fields[ATM_ID_FIELD_SIZE..].copy_from_slice(state.public.as_bytes());
+
+        let shared = state.agree(hello[1..].try_into().unwrap())?;
+        let keys = SessionKeys::derive(&shared, credentials.psk.as_ref(), hello, &fields);
+        reply.extend_from_slice(&fields);
+        reply.extend_from_slice(&keys.atm_confirmation);
+        Ok(ClientState::Classic(keys))
+    }
+
+    fn start_noise(
+        hello: &[u8; SERVER_HELLO_SIZE],
+        credentials: &AtmCredentials,
+        reply: &mut Vec<u8>,
+    ) -> Result<ClientState, HandshakeError> {
+        let bank_key = credentials.bank_key.ok_or(HandshakeError::Unsupported)?;
+        let mut id_field = [0u8; ATM_ID_FIELD_SIZE];
+        encode_atm_id(&credentials.id, &mut id_field)?;
+
+        // ATMs are told apart by their ids and pre-shared keys, so each
+        // handshake uses a throwaway static key
+        let mut static_key = [0u8; BANK_KEY_SIZE];
+        OsRng.fill_bytes(&mut static_key);
+        let psk = credentials.psk.as_ref().map_or(&NO_PSK, |psk| &psk.0);
+        let mut handshake = noise_builder(hello)
+            .local_private_key(&static_key)
+            .remote_public_key(&bank_key.0)
+            .psk(NOISE_PSK_LOCATION, psk)
+            .build_initiator()
+            .map_err(|_| HandshakeError::Malformed)?;
+
+        let mut message = [0u8; NOISE_CLIENT_HELLO_SIZE];
+        handshake
+            .write_message(&id_field, &mut message)
+            .map_err(|_| HandshakeError::Malformed)?;
+        reply.extend_from_slice(&message);
+        Ok(ClientState::Noise(Box::new(handshake)))
+    }
+
+    /// Returns the length of the bank's reply to wait for
+    pub fn reply_size(&self) -> usize {
+        match self.state {
+            ClientState::Classic(_) => Protocol::Classic.server_reply_size(),
+            ClientState::Noise(_) => Protocol::NoiseIk.server_reply_size(),
+        }
+    }
+
+    /// Checks the bank derived the same keys. Returns the channel
+    pub fn finish(self, reply: &[u8]) -> Result<Channel, HandshakeError> {
+        if reply.len() != self.reply_size() {
+            return Err(HandshakeError::Malformed);
+        }
+        match self.state {
+            ClientState::Classic(keys) => {
+                if !bool::from(keys.bank_confirmation.ct_eq(reply)) {
+                    return Err(HandshakeError::KeyMismatch);
+                }
+                Ok(Channel::new(&keys.atm_to_bank, &keys.bank_to_atm))
+            }
+            ClientState::Noise(mut handshake) => {
+                handshake
+                    .read_message(reply, &mut [])
+                    .map_err(|_| HandshakeError::KeyMismatch)?;
+                let (atm_to_bank, bank_to_atm) = handshake.dangerously_get_raw_split();
+                Ok(Channel::new(&atm_to_bank, &bank_to_atm))
+            }
+        }
+    }
+}
+
+/// Writes an ATM's id as a length byte followed by the id and random padding
+fn encode_atm_id(id: &str, field: &mut [u8]) -> Result<(), HandshakeError> {
+    if !valid_atm_id(id) {
+        return Err(HandshakeError::InvalidAtmId);
+    }
+    OsRng.fill_bytes(field);
+    field[0] = id.len() as u8;
+    field[1..1 + id.len()].copy_from_slice(id.as_bytes());
+    Ok(())
+}
+
+/// Reads an ATM's id written by `encode_atm_id`
+fn decode_atm_id(field: &[u8]) -> Result<&str, HandshakeError> {
+    let len = field[0] as usize;
+    let id = field
+        .get(1..1 + len)
+        .and_then(|id| str::from_utf8(id).ok())
+        .ok_or(HandshakeError::Malformed)?;
+    if !valid_atm_id(id) {
+        return Err(HandshakeError::Malformed);
+    }
+    Ok(id)
+}
+
+/// Encrypts the frames one party sends and decrypts the frames it receives,
+/// once a handshake is complete. Each direction has its own key, so a frame
+/// cannot be reflected back to the party which sent it
+pub struct Channel {
+    sealer: XChaCha20Poly1305,
+    opener: XChaCha20Poly1305,
+}
+
+impl Channel {
+    fn new(
+        send_key: &[u8; XCHACHA20_POLY1305_KEY_SIZE],
+        receive_key: &[u8; XCHACHA20_POLY1305_KEY_SIZE],
+    ) -> Self {
+        Self {
+            sealer: XChaCha20Poly1305::new(Key::from_slice(send_key)),
+            opener: XChaCha20Poly1305::new(Key::from_slice(receive_key)),
+        }
+    }
+
+    /// Encrypts a frame under a fresh random nonce
+    pub fn seal(&self, frame: &Frame) -> SealedFrame {
+        let mut sealed = [0u8; SEALED_FRAME_SIZE];
+        let (nonce, rest) = sealed.split_at_mut(XCHACHA20_POLY1305_NONCE_SIZE);
+        let (body, tag) = rest.split_at_mut(MAX_PLAINTEXT_SIZE);
+        OsRng.fill_bytes(nonce);
+        body.copy_from_slice(frame);
+        let computed = self
+            .sealer
+            .encrypt_in_place_detached(XNonce::from_slice(nonce), &[], body)
+            .expect("Error: frame too long to encrypt");
+        tag.copy_from_slice(&computed);
+        sealed
+    }
+
+    /// Decrypts a sealed frame. Returns `None` if the frame was not sealed by
+    /// the other party, or was altered on the way
+    pub fn open(&self, sealed: &SealedFrame) -> Option<Frame> {
+        let (nonce, rest) = sealed.split_at(XCHACHA20_POLY1305_NONCE_SIZE);
+        let (body, tag) = rest.split_at(MAX_PLAINTEXT_SIZE);
+        let mut frame: Frame = body.try_into().unwrap();
+        self.opener
+            .decrypt_in_place_detached(
+                XNonce::from_slice(nonce),
+                &[],
+                &mut frame,
+                Tag::from_slice(tag),
+            )
+            .ok()?;
+        Some(frame)
+    }
+
+    /// Encrypts a record of any length under a fresh random nonce. The
+    /// record's sequence number is authenticated with it, so records cannot
+    /// be replayed, dropped or reordered unnoticed
+    pub fn seal_record(&self, seq: u64, record: &[u8]) -> Vec<u8> {
+        let mut sealed = vec![0u8; XCHACHA20_POLY1305_NONCE_SIZE];
+        OsRng.fill_bytes(&mut sealed);
+        sealed.extend_from_slice(record);
+        let (nonce, body) = sealed.split_at_mut(XCHACHA20_POLY1305_NONCE_SIZE);
+        let tag = self
+            .sealer
+            .encrypt_in_place_detached(XNonce::from_slice(nonce), &seq.to_be_bytes(), body)
+            .expect("Error: record too long to encrypt");
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    /// Decrypts a record sealed by `seal_record`. Returns `None` if the
+    /// record was not sealed by the other party with this sequence number,
+    /// or was altered on the way
+    pub fn open_record(&self, seq: u64, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < RECORD_OVERHEAD {
+            return None;
+        }
+        let (nonce, rest) = sealed.split_at(XCHACHA20_POLY1305_NONCE_SIZE);
+        let (body, tag) = rest.split_at(rest.len() - POLY1305_TAG_SIZE);
+        let mut record = body.to_vec();
+        self.opener
+            .decrypt_in_place_detached(
+                XNonce::from_slice(nonce),
+                &seq.to_be_bytes(),
+                &mut record,
+                Tag::from_slice(tag),
+            )
+            .ok()?;
+        Some(record)
+    }
+}
+
+/// Error types related to cryptography
+pub mod errors {
+    use thiserror::Error;
+
+    /// Reasons a channel could not be established
+    #[derive(Debug, Error, PartialEq, Eq)]
+    pub enum HandshakeError {
+        /// The connection closed or failed before the handshake completed
+        #[error("Connection closed during the handshake.")]
+        Closed,
+        /// The other party did not answer before the stream's timeout elapsed
+        #[error("Timed out during the handshake.")]
+        TimedOut,
+        /// The bank is at its connection limit and turned the connection away
+        #[error("Bank is too busy to serve this connection.")]
+        Busy,
+        /// A hello could not be decoded, or carried an unusable public key
+        #[error("Handshake message is malformed.")]
+        Malformed,
+        /// The ATM's own id cannot be sent
+        #[error("ATM id must be up to 20 letters, digits, `-` or `_`.")]
+        InvalidAtmId,
+        /// The bank has keys registered, but none for this ATM. Only told
+        /// apart from `KeyMismatch` once the ATM's confirmation has failed
+        #[error("ATM `{0}` is not registered with the bank.")]
+        UnknownAtm(String),
+        /// The other party derived different keys, so does not hold the
+        /// pre-shared key or Noise key it was expected to
+        #[error("The other party does not hold the expected key.")]
+        KeyMismatch,
+        /// The ATM wants a protocol the bank does not offer
+        #[error("Bank does not offer the handshake this ATM is configured for.")]
+        Unsupported,
+        /// The bank closed the connection after the ATM's hello, so refused
+        /// the ATM's id or key
+        #[error("Bank refused this ATM's credentials.")]
+        Refused,
+    }
 }
@@ -1,82 +1,316 @@
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
+    io::{self, ErrorKind, Read, Write},
     str,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    io::errors::ReceiveError,
-    message::{constants::*, Plaintext, Response},
+    crypto::{
+        errors::HandshakeError, Accepted, AtmCredentials, AtmKeys, BankSecret, Channel,
+        ClientHandshake, SealedFrame, ServerHandshake, PROTOCOL_CHOICE_SIZE, SEALED_FRAME_SIZE,
+        SERVER_HELLO_SIZE,
+    },
+    io::errors::{ReceiveError, SendError},
+    message::{constants::*, EndReason, Frame, Message},
+    transport::Transport,
 };
 
+/// Default address the bank listens on and ATMs connect to
 pub const BANK_SERVER_ADDR: &str = "127.0.0.1:32001";
 
-pub const AUTH_SUCCESS: u8 = 0;
-pub const AUTH_FAILURE: u8 = 1;
+/// Default time the bank lets an ATM session sit idle before ending it
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Default time either party waits for a message to be read or written
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// Abstracts stream management away from bank and atm
+/// Read and write deadlines applied to a managed stream. `None` blocks indefinitely
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Longest wait for a message to arrive
+    pub read: Option<Duration>,
+    /// Longest wait for a message to be written
+    pub write: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            read: Some(DEFAULT_REQUEST_TIMEOUT),
+            write: Some(DEFAULT_REQUEST_TIMEOUT),
+        }
+    }
+}
+
+/// Abstracts stream management away from bank and atm. Every frame is
+/// sealed with the channel established when the stream was opened
 pub struct StreamManager {
-    stream: TcpStream,
+    stream: Box<dyn Transport>,
+    channel: Channel,
+    /// Longest wait for the rest of a message once its first bytes arrive
+    frame_timeout: Option<Duration>,
+    /// Id of the ATM on the other end, on the bank's side of the channel
+    atm_id: String,
 }
 
 impl StreamManager {
     //
     // constructors
 
-    /// Consumes a stream and returns instance of a new stream manager
-    pub fn from_stream(stream: TcpStream) -> Self {
-        Self { stream }
+    /// Consumes a stream to the bank and opens a channel over it with the
+    /// given ATM's credentials
+    pub fn open(
+        mut stream: Box<dyn Transport>,
+        timeouts: Timeouts,
+        credentials: &AtmCredentials,
+    ) -> Result<Self, HandshakeError> {
+        set_timeouts(stream.as_ref(), timeouts)?;
+        let channel = open_channel(stream.as_mut(), credentials)?;
+        Ok(Self {
+            stream,
+            channel,
+            frame_timeout: timeouts.read,
+            atm_id: credentials.id.clone(),
+        })
     }
-    /// Creates and returns instance of a new stream manager
-    pub fn from_addr(addr: &str) -> Self {
-        Self {
-            stream: TcpStream::connect(addr)
-                .expect("Error creating stream with provided address: {addr}"),
-        }
+    /// Consumes a stream from an ATM and accepts a channel over it, if the
+    /// ATM presents credentials matching the registered keys. The Noise
+    /// handshake is offered if the bank has a Noise key
+    pub fn accept(
+        mut stream: Box<dyn Transport>,
+        timeouts: Timeouts,
+        keys: &AtmKeys,
+        noise_key: Option<&BankSecret>,
+    ) -> Result<Self, HandshakeError> {
+        set_timeouts(stream.as_ref(), timeouts)?;
+        let accepted = accept_channel(stream.as_mut(), keys, noise_key)?;
+        Ok(Self {
+            stream,
+            channel: accepted.channel,
+            frame_timeout: timeouts.read,
+            atm_id: accepted.atm_id,
+        })
+    }
+
+    /// Returns the id of the ATM this channel was opened by. Empty for an
+    /// anonymous ATM
+    pub fn atm_id(&self) -> &str {
+        &self.atm_id
+    }
+
+    /// Changes how long to wait for a message to arrive. Once one starts to
+    /// arrive, the rest of it is waited for as long as the read timeout the
+    /// stream was opened with
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
     }
 
     //
     // low level send / receive helpers
 
-    /// Writes a given plaintext to the stream and increments communication count
-    pub fn send_plaintext(&mut self, mut plaintext: Plaintext) {
-        // TODO encrypt
-        self.stream.write(plaintext.get_bytes()).unwrap();
-        plaintext.update_count();
+    /// Writes a given message to the stream and increments communication count
+    pub fn send_message(
+        &mut self,
+        message: impl Into<Message>,
+        comm_count: &mut u32,
+    ) -> Result<(), SendError> {
+        self.send_frame(&message.into().encode(*comm_count))?;
+        *comm_count += 1;
+        Ok(())
+    }
+
+    /// Seals the given frame and writes it to the stream
+    pub fn send_frame(&mut self, frame: &Frame) -> Result<(), SendError> {
+        let sealed = self.channel.seal(frame);
+        self.stream.write_all(&sealed).map_err(|e| match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => SendError::TimedOut,
+            _ => SendError::Closed,
+        })
     }
 
-    /// Writes given buffer to the stream
-    pub fn send_bytes(&mut self, message: &[u8]) {
-        // TODO encrypt prior to send
-        self.stream.write(message).unwrap();
+    /// Blocks until a full message is read from the stream and validates it
+    pub fn receive(&mut self, comm_count: &mut u32) -> Result<Message, ReceiveError> {
+        let mut buf = [0u8; SEALED_FRAME_SIZE];
+        let read = match self.stream.read(&mut buf) {
+            Ok(0) => return Err(ReceiveError::EndOfStream),
+            Ok(read) => read,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(ReceiveError::TimedOut)
+            }
+            Err(_) => return Err(ReceiveError::EndOfStream),
+        };
+        self.finish_receive(buf, read, comm_count)
     }
 
-    pub fn receive(&mut self, comm_count: &mut u8) -> Result<Response, ReceiveError> {
-        let mut buf = [0u8; MAX_PLAINTEXT_SIZE];
-        if let Ok(0) = self.stream.read(&mut buf) {
-            return Err(ReceiveError::EndOfStream);
+    /// Checks the stream for a message the other party sent unprompted, without blocking.
+    /// Returns `None` if no message is waiting
+    pub fn poll(&mut self, comm_count: &mut u32) -> Result<Option<Message>, ReceiveError> {
+        let mut buf = [0u8; SEALED_FRAME_SIZE];
+        // a stream whose mode cannot be changed is as good as closed
+        self.stream
+            .set_nonblocking(true)
+            .map_err(|_| ReceiveError::EndOfStream)?;
+        let pending = self.stream.read(&mut buf);
+        self.stream
+            .set_nonblocking(false)
+            .map_err(|_| ReceiveError::EndOfStream)?;
+
+        match pending {
+            Ok(0) => Err(ReceiveError::EndOfStream),
+            Ok(read) => self.finish_receive(buf, read, comm_count).map(Some),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(_) => Err(ReceiveError::EndOfStream),
         }
-        // check for stale connection
-        if buf[COMM_COUNTER_IDX] >= MAX_COMM_COUNTER {
-            return Err(ReceiveError::StaleStream);
+    }
+
+    /// Reads the rest of a message whose first `read` bytes are in `buf`,
+    /// then opens and validates it
+    fn finish_receive(
+        &mut self,
+        mut buf: SealedFrame,
+        mut read: usize,
+        comm_count: &mut u32,
+    ) -> Result<Message, ReceiveError> {
+        // a message may arrive split across several reads, each of which may
+        // outlast a read timeout shorter than the frame timeout
+        let deadline = self.frame_timeout.map(|timeout| Instant::now() + timeout);
+        while read < SEALED_FRAME_SIZE {
+            match self.stream.read(&mut buf[read..]) {
+                Ok(0) => return Err(ReceiveError::EndOfStream),
+                Ok(more) => read += more,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(ReceiveError::Incomplete);
+                    }
+                }
+                Err(_) => return Err(ReceiveError::EndOfStream),
+            }
         }
-        // check for external tampering
-        if buf[COMM_COUNTER_IDX] != *comm_count {
-            return Err(ReceiveError::InvalidCount);
+        let frame = self.channel.open(&buf).ok_or(ReceiveError::Forged)?;
+        validate(frame, comm_count)
+    }
+}
+
+/// Runs the client's side of a handshake over the stream with the given
+/// credentials. Returns the channel
+pub(crate) fn open_channel(
+    stream: &mut dyn Transport,
+    credentials: &AtmCredentials,
+) -> Result<Channel, HandshakeError> {
+    let mut hello = [0u8; SERVER_HELLO_SIZE];
+    read_handshake(stream, &mut hello)?;
+    let (handshake, reply) = ClientHandshake::start(&hello, credentials)?;
+    write_handshake(stream, &reply)?;
+
+    // the bank hangs up on clients whose id or key it does not accept
+    let mut confirmation = vec![0u8; handshake.reply_size()];
+    read_handshake(stream, &mut confirmation).map_err(|e| match e {
+        HandshakeError::Closed => HandshakeError::Refused,
+        e => e,
+    })?;
+    handshake.finish(&confirmation)
+}
+
+/// Runs the bank's side of a handshake over the stream, accepting clients
+/// which present credentials matching the registered keys. The Noise
+/// handshake is offered if the bank has a Noise key
+pub(crate) fn accept_channel(
+    stream: &mut dyn Transport,
+    keys: &AtmKeys,
+    noise_key: Option<&BankSecret>,
+) -> Result<Accepted, HandshakeError> {
+    let (handshake, hello) = ServerHandshake::start(noise_key);
+    write_handshake(stream, &hello)?;
+    let mut choice = [0u8; PROTOCOL_CHOICE_SIZE];
+    read_handshake(stream, &mut choice)?;
+    let protocol = handshake.choose(choice[0])?;
+    let mut reply = vec![0u8; protocol.client_hello_size()];
+    read_handshake(stream, &mut reply)?;
+    let accepted = handshake.finish(protocol, &reply, keys)?;
+    write_handshake(stream, &accepted.reply)?;
+    Ok(accepted)
+}
+
+/// Applies read and write deadlines to a stream about to be handed to a manager
+pub(crate) fn set_timeouts(
+    stream: &dyn Transport,
+    timeouts: Timeouts,
+) -> Result<(), HandshakeError> {
+    stream
+        .set_read_timeout(timeouts.read)
+        .and_then(|()| stream.set_write_timeout(timeouts.write))
+        .map_err(|_| HandshakeError::Closed)
+}
+
+/// Reads a whole handshake message from the stream
+fn read_handshake(stream: &mut dyn Transport, buf: &mut [u8]) -> Result<(), HandshakeError> {
+    stream.read_exact(buf).map_err(handshake_error)
+}
+
+/// Writes a whole handshake message to the stream
+fn write_handshake(stream: &mut dyn Transport, buf: &[u8]) -> Result<(), HandshakeError> {
+    stream.write_all(buf).map_err(handshake_error)
+}
+
+/// Maps a failure on the stream during a handshake onto its cause
+pub(crate) fn handshake_error(e: io::Error) -> HandshakeError {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => HandshakeError::TimedOut,
+        _ => HandshakeError::Closed,
+    }
+}
+
+/// Validates the counter of a received frame and decodes the Message in it
+pub(crate) fn validate(buf: Frame, comm_count: &mut u32) -> Result<Message, ReceiveError> {
+    let received = Message::comm_count(&buf);
+    // check for stale connection
+    if received >= MAX_COMM_COUNTER {
+        return Err(ReceiveError::StaleStream);
+    }
+    // the other party may have ended the session just as a request was sent,
+    // in which case its notice carries the counter from before the request
+    if received.wrapping_add(1) == *comm_count {
+        if let Some(notice) = crossed_notice(&buf) {
+            return Ok(notice);
         }
-        *comm_count += 1;
+    }
+    // check for external tampering
+    if received != *comm_count {
+        return Err(ReceiveError::InvalidCount);
+    }
+    *comm_count += 1;
 
-        // TODO decrypt
+    Message::decode(&buf).map_err(ReceiveError::InvalidMessage)
+}
 
-        // construct Response
-        Response::new(buf).map_err(|_| ReceiveError::InvalidMessage)
+/// Returns the message in a frame if it is a notice which ends the session,
+/// the only message which may cross a request on the wire. The session is
+/// over either way, so the counter is left as it is
+fn crossed_notice(buf: &Frame) -> Option<Message> {
+    match Message::decode(buf) {
+        Ok(Message::EndSession(end)) if end.reason != EndReason::Requested => {
+            Some(Message::EndSession(end))
+        }
+        _ => None,
     }
 }
 
 /// Error types related IO
 pub mod errors {
+    use crate::message::errors::DecodeError;
     use thiserror::Error;
 
+    /// Error writing a message to the stream
+    #[derive(Debug, Error)]
+    pub enum SendError {
+        /// Stream has been closed
+        #[error("This stream has been closed")]
+        Closed,
+        /// The message could not be written before the stream's write timeout elapsed
+        #[error("Timed out writing a message.")]
+        TimedOut,
+    }
+
     /// Error validating response received from stream
     #[derive(Debug, Error)]
     pub enum ReceiveError {
@@ -89,8 +323,17 @@ pub mod errors {
         /// Received message count did not match local count
         #[error("Message count did not match local count. An adversary may have dropped or replayed this message.")]
         InvalidCount,
-        /// Received message type was unrecognized
-        #[error("Received message type was unrecognized.")]
-        InvalidMessage,
+        /// Received message was not sealed by the other party, or was altered on the way
+        #[error("Message failed authentication. An adversary may have forged or altered it.")]
+        Forged,
+        /// Received message could not be decoded
+        #[error("Received message could not be decoded: {0}")]
+        InvalidMessage(#[source] DecodeError),
+        /// No message arrived before the stream's read timeout elapsed
+        #[error("Timed out waiting for a message.")]
+        TimedOut,
+        /// A message started to arrive, but the rest of it did not arrive in time
+        #[error("Timed out partway through a message. Stream must be closed.")]
+        Incomplete,
     }
 }
@@ -1,3 +1,10 @@
+pub mod admin;
+#[cfg(feature = "async")]
+pub mod async_io;
+pub mod client;
+pub mod config;
 pub mod crypto;
+pub mod hex;
 pub mod io;
 pub mod message;
+pub mod transport;
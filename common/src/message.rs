@@ -1,230 +1,524 @@
 /// Structural constants defining message size and makeup
 pub mod constants {
-    /// Index for communication counter byte
+    /// Index for communication counter
     pub const COMM_COUNTER_IDX: usize = 0;
+    /// Length of communication counter, sent as a big endian u32
+    pub const COMM_COUNTER_SIZE: usize = 4;
     /// Maximum communication couter value
-    pub const MAX_COMM_COUNTER: u8 = u8::MAX - 2;
+    pub const MAX_COMM_COUNTER: u32 = u32::MAX - 2;
 
     /// Index for message type byte
-    pub const MESSAGE_TYPE_IDX: usize = COMM_COUNTER_IDX + 1;
+    pub const MESSAGE_TYPE_IDX: usize = COMM_COUNTER_IDX + COMM_COUNTER_SIZE;
 
     /// Index for start of plaintext body
     pub const MESSAGE_START_IDX: usize = MESSAGE_TYPE_IDX + 1;
-    /// Maximum length of message body
-    pub const MESSAGE_BODY_SIZE: usize = MAX_USERNAME_SIZE + PIN_SIZE;
-    /// Index for end of message body
-    pub const MESSAGE_END_IDX: usize = MESSAGE_START_IDX + MESSAGE_BODY_SIZE - 1;
+    /// Maximum length of message body. Every message must fit, which is
+    /// checked when the message is defined
+    pub const MESSAGE_BODY_SIZE: usize = USERNAME_LEN_SIZE + MAX_USERNAME_SIZE + MAX_BALANCE_SIZE;
 
-    /// Index for start of username within plaintext
-    pub const USERNAME_START_IDX: usize = MESSAGE_START_IDX;
+    /// Length of the byte giving a username's length
+    pub const USERNAME_LEN_SIZE: usize = 1;
     /// Maximum length of username
     pub const MAX_USERNAME_SIZE: usize = 20;
-    /// Index for end of username within plaintext
-    pub const USERNAME_END_IDX: usize = USERNAME_START_IDX + MAX_USERNAME_SIZE - 1;
-
-    /// Index for start of PIN within plaintext
-    pub const PIN_START_IDX: usize = USERNAME_START_IDX + MAX_USERNAME_SIZE;
-    /// Length of PIN
-    pub const PIN_SIZE: usize = 4;
-    /// Index for end of PIN within plaintext
-    pub const PIN_END_IDX: usize = PIN_START_IDX + PIN_SIZE - 1;
-
-    /// Maximum size for big endian notation of an f64
+    /// Length of PIN, sent as a big endian u16
+    pub const PIN_SIZE: usize = 2;
+    /// Length of an amount of money, sent as a big endian u64 count of cents
     pub const MAX_BALANCE_SIZE: usize = 8;
 
     /// Length of the entire plaintext
-    pub const MAX_PLAINTEXT_SIZE: usize = 1 + 1 + MESSAGE_BODY_SIZE;
+    pub const MAX_PLAINTEXT_SIZE: usize = COMM_COUNTER_SIZE + 1 + MESSAGE_BODY_SIZE;
 }
 
-use self::errors::{MessageTypeError, ResponseError};
-use crate::{
-    io::{AUTH_FAILURE, AUTH_SUCCESS},
-    message::constants::*,
+use self::errors::{
+    DecodeError, EndReasonError, FieldError, MessageTypeError, TransactionStatusError,
 };
-use std::str;
+use crate::message::constants::*;
+use rand_core::{OsRng, RngCore};
+use std::{fmt, str};
 
-/// Enum representing possible message types sent and received
+/// A whole message as sent over the wire: counter, type and padded body
+pub type Frame = [u8; MAX_PLAINTEXT_SIZE];
+
+/// Enum representing why a session was ended, carried in EndSession messages
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
-pub enum MessageType {
-    AuthUser,
-    Balance,
-    Withdraw,
-    Deposit,
-    End,
-    AuthResult,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    /// The ATM asked to end the session
+    Requested,
+    /// The bank is shutting down
+    Maintenance,
+    /// The session sat idle for too long
+    Idle,
 }
 
-impl TryFrom<u8> for MessageType {
-    type Error = MessageTypeError;
-    /// Conversion from u8 to MessageType
+impl TryFrom<u8> for EndReason {
+    type Error = EndReasonError;
+    /// Conversion from u8 to EndReason
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => Ok(Self::AuthUser),
-            1 => Ok(Self::Balance),
-            2 => Ok(Self::Withdraw),
-            3 => Ok(Self::Deposit),
-            4 => Ok(Self::End),
-            5 => Ok(Self::AuthResult),
-            _ => Err(MessageTypeError::InvalidType(value)),
+            0 => Ok(Self::Requested),
+            1 => Ok(Self::Maintenance),
+            2 => Ok(Self::Idle),
+            _ => Err(EndReasonError::InvalidReason(value)),
         }
     }
 }
 
-/// Provides interfaces for creating and managing a message plaintext
-pub struct Plaintext<'a> {
-    contents: [u8; MAX_PLAINTEXT_SIZE],
-    comm_count: &'a mut u8,
+/// Enum representing the outcome of a withdrawal, deposit or transfer,
+/// carried in the bank's reply
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionStatus {
+    /// The transaction was carried out
+    Approved,
+    /// The account holds less than the amount
+    InsufficientFunds,
+    /// The receiving account does not exist
+    UnknownRecipient,
+    /// The amount is negative or not a number
+    InvalidAmount,
+    /// A transfer named the sending account as the recipient
+    SameAccount,
+    /// The receiving account cannot hold the amount
+    Overflow,
 }
 
-impl<'a> Plaintext<'a> {
-    /// Create a new instance of a plaintext
-    pub fn new(comm_count: &'a mut u8, msg_type: MessageType) -> Self {
-        let mut ptext = [0u8; MAX_PLAINTEXT_SIZE];
-        ptext[COMM_COUNTER_IDX] = *comm_count;
-        ptext[MESSAGE_TYPE_IDX] = msg_type as u8;
-        Self {
-            contents: ptext,
-            comm_count,
+impl TryFrom<u8> for TransactionStatus {
+    type Error = TransactionStatusError;
+    /// Conversion from u8 to TransactionStatus
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Approved),
+            1 => Ok(Self::InsufficientFunds),
+            2 => Ok(Self::UnknownRecipient),
+            3 => Ok(Self::InvalidAmount),
+            4 => Ok(Self::SameAccount),
+            5 => Ok(Self::Overflow),
+            _ => Err(TransactionStatusError::InvalidStatus(value)),
         }
     }
-    /// Returns reference to the message
-    pub fn get_bytes(&self) -> &[u8] {
-        &self.contents
+}
+
+//
+// field types
+
+/// A value which can be a field of a message, encoded in a fixed number of bytes
+pub trait Field: Sized {
+    /// Number of bytes the field takes up in a message body
+    const SIZE: usize;
+    /// Writes the field into a buffer of exactly `SIZE` bytes
+    fn encode(&self, buf: &mut [u8]);
+    /// Reads the field from a buffer of exactly `SIZE` bytes
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError>;
+}
+
+/// A username: one to 20 ASCII letters. The bank and ATMs share this rule, so
+/// every name an ATM can send is one the bank can open an account for. Sent
+/// as its length followed by its bytes, with random padding after them
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Username(String);
+
+impl Username {
+    /// Checks a username is made of letters and fits in a message
+    pub fn new(username: &str) -> Result<Self, FieldError> {
+        if username.is_empty() || !username.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(FieldError::InvalidUsername);
+        }
+        if username.len() > MAX_USERNAME_SIZE {
+            return Err(FieldError::UsernameTooLong(MAX_USERNAME_SIZE));
+        }
+        Ok(Self(username.to_string()))
     }
-    /// Updates comm count reference after send
-    pub fn update_count(&mut self) {
-        *self.comm_count += 1;
+    pub fn as_str(&self) -> &str {
+        &self.0
     }
+}
 
-    //
-    // helpers
+impl fmt::Display for Username {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-    /// Resets the message body contents to avoid possible overlaps caused by
-    /// user error: calling multiple inits
-    fn reset_body(&mut self) {
-        for i in MESSAGE_START_IDX..MAX_PLAINTEXT_SIZE {
-            self.contents[i] = 0;
-        }
+impl Field for Username {
+    const SIZE: usize = USERNAME_LEN_SIZE + MAX_USERNAME_SIZE;
+    fn encode(&self, buf: &mut [u8]) {
+        let (len, bytes) = buf.split_at_mut(USERNAME_LEN_SIZE);
+        len[0] = self.0.len() as u8;
+        bytes[..self.0.len()].copy_from_slice(self.0.as_bytes());
     }
-    /// Generically inserts bytes into the plaintext
-    fn generic_insert(&mut self, bytes: &[u8], offset: usize) {
-        self.contents[offset..(bytes.len() + offset)].copy_from_slice(bytes);
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let (len, bytes) = buf.split_at(USERNAME_LEN_SIZE);
+        let len = usize::from(len[0]);
+        if len > MAX_USERNAME_SIZE {
+            return Err(FieldError::UsernameTooLong(MAX_USERNAME_SIZE).into());
+        }
+        let username = str::from_utf8(&bytes[..len]).map_err(|_| DecodeError::InvalidString)?;
+        Ok(Self::new(username)?)
     }
+}
 
-    //
-    // message type setters
+/// A 4 digit PIN. Leading zeros are digits like any other, so `0042` and
+/// `42` are the same PIN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pin(u16);
 
-    /// Adds username to message
-    pub fn set_user(&mut self, username: &str) {
-        self.reset_body();
-        self.generic_insert(username.as_bytes(), USERNAME_START_IDX);
+impl Pin {
+    /// Largest PIN, as PINs are 4 digits
+    pub const MAX: u16 = 9999;
+
+    /// Checks a PIN has at most 4 digits
+    pub fn new(pin: u16) -> Result<Self, FieldError> {
+        match pin {
+            pin if pin > Self::MAX => Err(FieldError::InvalidPin),
+            pin => Ok(Self(pin)),
+        }
+    }
+    pub fn value(&self) -> u16 {
+        self.0
     }
+}
 
-    /// Adds username and pin to message
-    pub fn set_user_pin(&mut self, username: &str, pin: &str) {
-        self.set_user(username);
-        self.generic_insert(pin.as_bytes(), PIN_START_IDX);
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}", self.0)
     }
+}
 
-    /// Adds authentication result to message
-    pub fn set_auth_result(&mut self, result: bool) {
-        self.reset_body();
-        self.contents[MESSAGE_START_IDX] = match result {
-            true => AUTH_SUCCESS,
-            false => AUTH_FAILURE,
-        };
+impl Field for Pin {
+    const SIZE: usize = PIN_SIZE;
+    fn encode(&self, buf: &mut [u8]) {
+        self.0.encode(buf);
+    }
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self::new(u16::decode(buf)?)?)
     }
+}
+
+/// An amount of money, counted in cents
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Amount(u64);
 
-    /// Adds balance to message
-    pub fn set_balance(&mut self, balance: f64) {
-        self.reset_body();
-        self.generic_insert(format!("{}", balance).as_bytes(), MESSAGE_START_IDX);
+impl Amount {
+    /// Largest amount, so every amount converts to dollars exactly
+    pub const MAX: Self = Self((1 << f64::MANTISSA_DIGITS) - 1);
+
+    /// Checks a count of cents is no larger than `Amount::MAX`
+    pub const fn from_cents(cents: u64) -> Result<Self, FieldError> {
+        if cents > Self::MAX.0 {
+            return Err(FieldError::InvalidAmount);
+        }
+        Ok(Self(cents))
+    }
+    /// Converts dollars to the nearest whole cent. Fails if the amount is
+    /// negative, not a number or larger than `Amount::MAX`
+    pub fn from_dollars(dollars: f64) -> Result<Self, FieldError> {
+        let cents = (dollars * 100.0).round();
+        if !cents.is_finite() || cents < 0.0 || cents > Self::MAX.0 as f64 {
+            return Err(FieldError::InvalidAmount);
+        }
+        Ok(Self(cents as u64))
+    }
+    pub const fn cents(&self) -> u64 {
+        self.0
+    }
+    pub fn to_dollars(&self) -> f64 {
+        self.0 as f64 / 100.0
+    }
+    /// Adds two amounts. `None` if the sum is larger than `Amount::MAX`
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        Self::from_cents(self.0 + other.0).ok()
+    }
+    /// Takes one amount from another. `None` if it is larger
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
     }
 }
 
-/// Provides a friendlier interface with a received plaintext
-pub struct Response {
-    msg_type: MessageType,
-    contents: [u8; MAX_PLAINTEXT_SIZE],
+/// Shown in dollars with two decimal places, like `12.50`
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}", self.0 / 100, self.0 % 100)
+    }
 }
 
-impl Response {
-    /// Creates new instance of a message Response.
-    /// May fail to create if provided message has unrecognized type
-    pub fn new(response_buf: [u8; MAX_PLAINTEXT_SIZE]) -> Result<Self, MessageTypeError> {
-        let msg_type = MessageType::try_from(response_buf[MESSAGE_TYPE_IDX])?;
-        Ok(Self {
-            msg_type,
-            contents: response_buf,
-        })
-    }
-
-    //
-    // getters
-
-    /// Returns this response's message type
-    pub fn get_type(&self) -> MessageType {
-        self.msg_type
-    }
-
-    /// Returns username string or error
-    pub fn get_user(&self) -> Result<String, ResponseError> {
-        match self.msg_type {
-            MessageType::AuthUser | MessageType::Balance => Ok(str::from_utf8(
-                &self.contents[USERNAME_START_IDX..=USERNAME_END_IDX],
-            )
-            .map_err(|_| ResponseError::InvalidBytesForString)?
-            .trim_end_matches(|c| c == '\0')
-            .to_string()),
-            _ => Err(ResponseError::DeconstructError {
-                request: MessageType::AuthUser,
-                actual: self.msg_type,
-            }),
+/// Parses dollars with up to two decimal places, like `12`, `12.5` or `12.50`,
+/// into exactly that many cents
+impl str::FromStr for Amount {
+    type Err = FieldError;
+    fn from_str(dollars: &str) -> Result<Self, Self::Err> {
+        let (whole, fraction) = dollars.split_once('.').unwrap_or((dollars, ""));
+        let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if whole.is_empty() || !digits(whole) || fraction.len() > 2 || !digits(fraction) {
+            return Err(FieldError::InvalidAmount);
         }
+        let cents = format!("{fraction:0<2}").parse::<u64>().unwrap();
+        whole
+            .parse::<u64>()
+            .ok()
+            .and_then(|whole| whole.checked_mul(100))
+            .and_then(|whole| whole.checked_add(cents))
+            .ok_or(FieldError::InvalidAmount)
+            .and_then(Self::from_cents)
+    }
+}
+
+impl Field for Amount {
+    const SIZE: usize = MAX_BALANCE_SIZE;
+    fn encode(&self, buf: &mut [u8]) {
+        self.0.encode(buf);
+    }
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self::from_cents(u64::decode(buf)?)?)
     }
+}
 
-    /// Returns pin u16 or error
-    pub fn get_pin(&self) -> Result<u16, ResponseError> {
-        if !matches!(self.msg_type, MessageType::AuthUser) {
-            return Err(ResponseError::DeconstructError {
-                request: MessageType::AuthUser,
-                actual: self.msg_type,
-            });
+/// Counts, ids and other numbers. Sent in big endian notation
+macro_rules! integer_fields {
+    ($($int:ty),*) => {$(
+        impl Field for $int {
+            const SIZE: usize = std::mem::size_of::<$int>();
+            fn encode(&self, buf: &mut [u8]) {
+                buf.copy_from_slice(&self.to_be_bytes());
+            }
+            fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+                let mut bytes = [0u8; std::mem::size_of::<$int>()];
+                bytes.copy_from_slice(buf);
+                Ok(<$int>::from_be_bytes(bytes))
+            }
         }
-        // TODO consider endian notation instead
-        str::from_utf8(&self.contents[PIN_START_IDX..=PIN_END_IDX])
-            .map_err(|_| ResponseError::InvalidBytesForString)?
-            .trim_end_matches(|c| c == '\0')
-            .parse()
-            .map_err(|_| ResponseError::InvalidBytesForPIN)
-    }
-
-    /// Returns result of authentication or error
-    pub fn get_auth_result(&self) -> Result<bool, ResponseError> {
-        if !matches!(self.msg_type, MessageType::AuthResult) {
-            return Err(ResponseError::DeconstructError {
-                request: MessageType::AuthResult,
-                actual: self.msg_type,
-            });
+    )*};
+}
+
+integer_fields!(u16, u32, u64);
+
+impl Field for bool {
+    const SIZE: usize = 1;
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = u8::from(*self);
+    }
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        match buf[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(DecodeError::InvalidBool(value)),
         }
-        Ok(self.contents[MESSAGE_START_IDX] == AUTH_SUCCESS)
     }
+}
+
+impl Field for EndReason {
+    const SIZE: usize = 1;
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = *self as u8;
+    }
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self::try_from(buf[0])?)
+    }
+}
+
+impl Field for TransactionStatus {
+    const SIZE: usize = 1;
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = *self as u8;
+    }
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self::try_from(buf[0])?)
+    }
+}
+
+/// Writes fields one after another into a message body
+struct BodyWriter<'a> {
+    body: &'a mut [u8],
+    offset: usize,
+}
+
+impl BodyWriter<'_> {
+    fn write<F: Field>(&mut self, field: &F) {
+        field.encode(&mut self.body[self.offset..self.offset + F::SIZE]);
+        self.offset += F::SIZE;
+    }
+}
+
+/// Reads fields one after another from a message body
+struct BodyReader<'a> {
+    body: &'a [u8],
+    offset: usize,
+}
+
+impl BodyReader<'_> {
+    fn read<F: Field>(&mut self) -> Result<F, DecodeError> {
+        let field = F::decode(&self.body[self.offset..self.offset + F::SIZE])?;
+        self.offset += F::SIZE;
+        Ok(field)
+    }
+}
+
+//
+// messages
+
+/// Defines every message: its type byte and its fields, which are laid out in
+/// order from the start of the body. Generates a struct for each message,
+/// `MessageType` of all the type bytes and `Message` of all the messages
+macro_rules! messages {
+    ($(
+        $(#[$doc:meta])*
+        $name:ident = $type_byte:literal {
+            $( $(#[$field_doc:meta])* $field:ident: $field_type:ty ),* $(,)?
+        }
+    )*) => {
+        /// Enum representing possible message types sent and received
+        #[repr(u8)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum MessageType {
+            $( $(#[$doc])* $name = $type_byte, )*
+        }
+
+        impl TryFrom<u8> for MessageType {
+            type Error = MessageTypeError;
+            /// Conversion from u8 to MessageType
+            fn try_from(value: u8) -> Result<Self, Self::Error> {
+                match value {
+                    $( $type_byte => Ok(Self::$name), )*
+                    _ => Err(MessageTypeError::InvalidType(value)),
+                }
+            }
+        }
+
+        $(
+            $(#[$doc])*
+            #[derive(Debug, Clone, PartialEq)]
+            pub struct $name {
+                $( $(#[$field_doc])* pub $field: $field_type, )*
+            }
+
+            impl $name {
+                /// Number of body bytes the message's fields take up
+                pub const SIZE: usize = 0 $( + <$field_type as Field>::SIZE )*;
+
+                #[allow(unused_variables, unused_mut)]
+                fn encode_body(&self, body: &mut [u8]) {
+                    let mut writer = BodyWriter { body, offset: 0 };
+                    $( writer.write(&self.$field); )*
+                }
+                #[allow(unused_variables, unused_mut)]
+                fn decode_body(body: &[u8]) -> Result<Self, DecodeError> {
+                    let mut reader = BodyReader { body, offset: 0 };
+                    Ok(Self { $( $field: reader.read()?, )* })
+                }
+            }
+
+            const _: () = assert!(
+                $name::SIZE <= MESSAGE_BODY_SIZE,
+                concat!(stringify!($name), " does not fit in a message body")
+            );
+
+            impl From<$name> for Message {
+                fn from(message: $name) -> Self {
+                    Self::$name(message)
+                }
+            }
+        )*
+
+        /// Any message either party sends
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Message {
+            $( $name($name), )*
+        }
 
-    /// Returns balance value or error
-    pub fn get_balance(&self) -> Result<f64, ResponseError> {
-        if !matches!(self.msg_type, MessageType::Balance) {
-            return Err(ResponseError::DeconstructError {
-                request: MessageType::Balance,
-                actual: self.msg_type,
-            });
+        impl Message {
+            /// Returns the type of the message
+            pub fn msg_type(&self) -> MessageType {
+                match self {
+                    $( Self::$name(_) => MessageType::$name, )*
+                }
+            }
+            fn encode_body(&self, body: &mut [u8]) {
+                match self {
+                    $( Self::$name(message) => message.encode_body(body), )*
+                }
+            }
+            fn decode_body(msg_type: MessageType, body: &[u8]) -> Result<Self, DecodeError> {
+                match msg_type {
+                    $( MessageType::$name => $name::decode_body(body).map(Self::$name), )*
+                }
+            }
         }
-        // TODO switch to endian notation for easier conversions
-        str::from_utf8(&self.contents[MESSAGE_START_IDX..=MESSAGE_END_IDX])
-            .map_err(|_| ResponseError::InvalidBytesForString)?
-            .trim_end_matches(|c| c == '\0')
-            .parse()
-            .map_err(|_| ResponseError::InvalidBytesForBalance)
+    };
+}
+
+messages! {
+    /// ATM asks the bank to authenticate a user
+    AuthRequest = 0 {
+        username: Username,
+        pin: Pin,
+    }
+    /// ATM asks for the balance of the authenticated user
+    BalanceRequest = 1 {
+        /// Must be the authenticated user
+        username: Username,
+    }
+    /// ATM takes money out of the authenticated user's account
+    WithdrawRequest = 2 {
+        amount: Amount,
+    }
+    /// ATM puts money into the authenticated user's account
+    DepositRequest = 3 {
+        amount: Amount,
+    }
+    /// Either party ends the session. The ATM's request is answered with
+    /// the same message
+    EndSession = 4 {
+        reason: EndReason,
+    }
+    /// Bank answers an AuthRequest
+    AuthResult = 5 {
+        authenticated: bool,
+    }
+    /// ATM moves money from the authenticated user's account into another's
+    TransferRequest = 7 {
+        recipient: Username,
+        amount: Amount,
+    }
+    /// Bank answers a BalanceRequest
+    BalanceResponse = 8 {
+        balance: Amount,
+    }
+    /// Bank answers a withdrawal, deposit or transfer
+    TransactionResult = 9 {
+        status: TransactionStatus,
+        /// Balance left in the authenticated user's account afterwards
+        balance: Amount,
+    }
+    /// Cover traffic, sent by the ATM and answered in kind by the bank. Carries
+    /// nothing, but looks like any other message on the wire
+    Heartbeat = 10 {}
+}
+
+impl Message {
+    /// Encodes the message into a frame carrying the given counter. The rest
+    /// of the body is padded with random bytes, so no two frames share a layout
+    pub fn encode(&self, comm_count: u32) -> Frame {
+        let mut frame = [0u8; MAX_PLAINTEXT_SIZE];
+        OsRng.fill_bytes(&mut frame[MESSAGE_START_IDX..]);
+        comm_count.encode(&mut frame[COMM_COUNTER_IDX..MESSAGE_TYPE_IDX]);
+        frame[MESSAGE_TYPE_IDX] = self.msg_type() as u8;
+        self.encode_body(&mut frame[MESSAGE_START_IDX..]);
+        frame
+    }
+
+    /// Reads the counter a frame was sent with
+    pub fn comm_count(frame: &Frame) -> u32 {
+        u32::from_be_bytes(
+            frame[COMM_COUNTER_IDX..MESSAGE_TYPE_IDX]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    /// Decodes the message in a frame. The counter is not checked
+    pub fn decode(frame: &Frame) -> Result<Self, DecodeError> {
+        let msg_type = MessageType::try_from(frame[MESSAGE_TYPE_IDX])?;
+        Self::decode_body(msg_type, &frame[MESSAGE_START_IDX..])
     }
 }
 
@@ -232,8 +526,6 @@ impl Response {
 pub mod errors {
     use thiserror::Error;
 
-    use super::MessageType;
-
     /// Errors trying to create a MessageType
     #[derive(Debug, Error)]
     pub enum MessageTypeError {
@@ -241,25 +533,53 @@ pub mod errors {
         InvalidType(u8),
     }
 
+    /// Errors trying to create an EndReason
+    #[derive(Debug, Error)]
+    pub enum EndReasonError {
+        #[error("EndReason cannot be created from u8 value: `{0}`")]
+        InvalidReason(u8),
+    }
+
+    /// Errors trying to create a TransactionStatus
+    #[derive(Debug, Error)]
+    pub enum TransactionStatusError {
+        #[error("TransactionStatus cannot be created from u8 value: `{0}`")]
+        InvalidStatus(u8),
+    }
+
+    /// Values which cannot be sent as a message field
+    #[derive(Debug, Error, PartialEq, Eq)]
+    pub enum FieldError {
+        /// Usernames may only contain letters
+        #[error("Username must only contain letters.")]
+        InvalidUsername,
+        /// The username does not fit in a message
+        #[error("Username must be {0} characters or less.")]
+        UsernameTooLong(usize),
+        /// Pins are 4 digits
+        #[error("PIN must be 4 digits.")]
+        InvalidPin,
+        /// Amounts are whole cents from zero up to `Amount::MAX`
+        #[error("Amount must be a non-negative number of cents no larger than {}.", super::Amount::MAX.cents())]
+        InvalidAmount,
+    }
+
+    /// Errors decoding a received frame
     #[derive(Debug, Error)]
-    pub enum ResponseError {
-        /// Invalid message type in received message
-        #[error("Cannot create Response with invalid message type.")]
-        InvalidMessageType(#[from] crate::message::MessageTypeError),
-        /// Unable to retrieve the requested field because it does not exist in the received message type
-        #[error("Cannot retreive requested {request:?} field from a message of type {actual:?}.")]
-        DeconstructError {
-            request: MessageType,
-            actual: MessageType,
-        },
+    pub enum DecodeError {
+        #[error(transparent)]
+        InvalidMessageType(#[from] MessageTypeError),
+        #[error(transparent)]
+        InvalidEndReason(#[from] EndReasonError),
+        #[error(transparent)]
+        InvalidTransactionStatus(#[from] TransactionStatusError),
+        #[error(transparent)]
+        InvalidField(#[from] FieldError),
         /// Failed conversion from received bytes into string
-        #[error("Cannot convert message body into a valid username string.")]
-        InvalidBytesForString,
-        /// Failed u16 PIN conversion from received byte string
-        #[error("Cannot covert message body into a valid PIN.")]
-        InvalidBytesForPIN,
-        /// Failed f64 balance conversion from received byte string
-        #[error("Cannot covert message body into a valid balance.")]
-        InvalidBytesForBalance,
+        #[error("Cannot convert message body into a valid string.")]
+        InvalidString,
+        /// A flag held something other than 0 or 1
+        #[error("Flag cannot be created from u8 value: `{0}`")]
+        InvalidBool(u8),
     }
 }
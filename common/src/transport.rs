@@ -0,0 +1,444 @@
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Prefix marking an address as the path of a Unix domain socket
+pub const UNIX_SCHEME: &str = "unix:";
+/// Permissions a Unix socket file is given by default: only its owner and
+/// group may connect
+pub const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// A connection messages can be sent over.
+///
+/// Implemented for TCP streams, Unix domain sockets and in-memory pipes, so
+/// the bank and ATM speak the same protocol over any of them
+pub trait Transport: Read + Write + Send {
+    /// Sets how long reads may block. `None` blocks indefinitely
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Sets how long writes may block. `None` blocks indefinitely
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Makes reads fail with `WouldBlock` instead of waiting for data
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+/// Implements Transport for socket types from the standard library
+macro_rules! impl_socket_transport {
+    ($socket:ty) => {
+        impl Transport for $socket {
+            fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+                <$socket>::set_read_timeout(self, timeout)
+            }
+            fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+                <$socket>::set_write_timeout(self, timeout)
+            }
+            fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+                <$socket>::set_nonblocking(self, nonblocking)
+            }
+        }
+    };
+}
+
+impl_socket_transport!(TcpStream);
+#[cfg(unix)]
+impl_socket_transport!(UnixStream);
+
+/// Where the bank listens and ATMs connect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A TCP address
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// Opens a connection to this endpoint
+    pub fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        match self {
+            Self::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr)?)),
+            #[cfg(unix)]
+            Self::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "{UNIX_SCHEME}{}", path.display()),
+        }
+    }
+}
+
+/// Address of the other end of an accepted connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAddr {
+    /// A TCP peer
+    Tcp(SocketAddr),
+    /// A peer on the same machine, connected over a Unix socket or in memory
+    Local,
+}
+
+impl PeerAddr {
+    /// Returns the host the peer connected from
+    pub fn host(&self) -> PeerHost {
+        match self {
+            Self::Tcp(addr) => PeerHost::Ip(addr.ip()),
+            Self::Local => PeerHost::Local,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Local => write!(f, "a local peer"),
+        }
+    }
+}
+
+/// Host a connection came from. Local peers all count as the same host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerHost {
+    Ip(IpAddr),
+    Local,
+}
+
+impl fmt::Display for PeerHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{ip}"),
+            Self::Local => write!(f, "the local host"),
+        }
+    }
+}
+
+/// Accepts connections on any transport
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        file: SocketFile,
+    },
+    Memory {
+        incoming: Receiver<MemoryStream>,
+        nonblocking: Cell<bool>,
+    },
+}
+
+impl Listener {
+    /// Listens on the given endpoint. A Unix socket is given the default
+    /// permissions
+    pub fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        Self::bind_with_mode(endpoint, DEFAULT_SOCKET_MODE)
+    }
+
+    /// Listens on the given endpoint, giving a Unix socket file the given
+    /// permissions. A socket file left behind by a listener which is no longer
+    /// running is replaced, but any other file at the path is left alone and
+    /// binding fails with `AlreadyExists`.
+    ///
+    /// The socket is bound in a directory only this process's user can enter
+    /// and given its permissions there, before it is linked into place, so it
+    /// can never be connected to with looser permissions than asked for
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub fn bind_with_mode(endpoint: &Endpoint, mode: u32) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                match fs::symlink_metadata(path) {
+                    Ok(meta) if !meta.file_type().is_socket() => {
+                        return Err(io::Error::new(
+                            ErrorKind::AlreadyExists,
+                            format!("{} exists and is not a socket", path.display()),
+                        ));
+                    }
+                    // only a socket nothing is listening on is stale
+                    Ok(_) => match UnixStream::connect(path) {
+                        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                            fs::remove_file(path)?
+                        }
+                        Err(e) => return Err(e),
+                        Ok(_) => return Err(ErrorKind::AddrInUse.into()),
+                    },
+                    Err(_) => {}
+                }
+
+                let private = private_dir(path)?;
+                let bound = bind_privately(&private, path, mode);
+                let _ = fs::remove_dir_all(&private);
+                let listener = bound?;
+                Ok(Self::Unix {
+                    listener,
+                    file: SocketFile(path.clone()),
+                })
+            }
+        }
+    }
+
+    /// Creates a listener for in-memory connections, along with the
+    /// connector used to open them
+    pub fn memory() -> (Self, MemoryConnector) {
+        let (sender, incoming) = mpsc::channel();
+        let listener = Self::Memory {
+            incoming,
+            nonblocking: Cell::new(false),
+        };
+        (listener, MemoryConnector { sender })
+    }
+
+    /// Makes `accept` fail with `WouldBlock` instead of waiting for a connection
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => listener.set_nonblocking(nonblocking),
+            Self::Memory { nonblocking: n, .. } => {
+                n.set(nonblocking);
+                Ok(())
+            }
+        }
+    }
+
+    /// Accepts a connection. The returned connection is always blocking
+    pub fn accept(&self) -> io::Result<(Box<dyn Transport>, PeerAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok((Box::new(stream), PeerAddr::Tcp(peer)))
+            }
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok((Box::new(stream), PeerAddr::Local))
+            }
+            Self::Memory {
+                incoming,
+                nonblocking,
+            } => {
+                let stream = if nonblocking.get() {
+                    incoming.try_recv().map_err(|e| match e {
+                        TryRecvError::Empty => io::Error::from(ErrorKind::WouldBlock),
+                        TryRecvError::Disconnected => io::Error::from(ErrorKind::NotConnected),
+                    })?
+                } else {
+                    incoming
+                        .recv()
+                        .map_err(|_| io::Error::from(ErrorKind::NotConnected))?
+                };
+                Ok((Box::new(stream), PeerAddr::Local))
+            }
+        }
+    }
+}
+
+/// Path of a bound Unix socket. The socket file is removed when this is dropped
+#[cfg(unix)]
+pub struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Creates an empty directory beside the given socket path which only this
+/// process's user can enter
+#[cfg(unix)]
+fn private_dir(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name().ok_or(ErrorKind::InvalidInput)?;
+    let mut private = name.to_os_string();
+    private.push(format!(".{}.tmp", process::id()));
+    let private = path.with_file_name(private);
+    // left behind by an earlier process with the same id
+    let _ = fs::remove_dir_all(&private);
+    DirBuilder::new().mode(0o700).create(&private)?;
+    Ok(private)
+}
+
+/// Binds a socket in the private directory, gives it the given permissions
+/// and links it to the given path. Fails if anything is already at the path
+#[cfg(unix)]
+fn bind_privately(private: &Path, path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let bound = private.join("socket");
+    let listener = UnixListener::bind(&bound)?;
+    fs::set_permissions(&bound, Permissions::from_mode(mode))?;
+    // unlike a rename, a link never replaces another listener's socket
+    fs::hard_link(&bound, path)?;
+    Ok(listener)
+}
+
+/// Opens in-memory connections to a listener created with `Listener::memory`
+#[derive(Clone)]
+pub struct MemoryConnector {
+    sender: Sender<MemoryStream>,
+}
+
+impl MemoryConnector {
+    /// Opens a connection. Fails if the listener has been dropped
+    pub fn connect(&self) -> io::Result<MemoryStream> {
+        let (client, server) = memory_pair();
+        self.sender
+            .send(server)
+            .map_err(|_| io::Error::from(ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+}
+
+/// Bytes travelling in one direction of an in-memory connection
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    bytes: VecDeque<u8>,
+    /// Set once either end of the connection is dropped, or the writing end
+    /// is closed for writing
+    closed: bool,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory connection, for running the protocol without
+/// opening a socket. Dropping either end closes the connection
+pub struct MemoryStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Cell<Option<Duration>>,
+    nonblocking: Cell<bool>,
+}
+
+/// Creates both ends of an in-memory connection
+pub fn memory_pair() -> (MemoryStream, MemoryStream) {
+    let a_to_b = Arc::new(Pipe::default());
+    let b_to_a = Arc::new(Pipe::default());
+    let end = |incoming, outgoing| MemoryStream {
+        incoming,
+        outgoing,
+        read_timeout: Cell::new(None),
+        nonblocking: Cell::new(false),
+    };
+    (end(b_to_a.clone(), a_to_b.clone()), end(a_to_b, b_to_a))
+}
+
+impl MemoryStream {
+    /// Closes this end for writing, like shutting down the write half of a
+    /// socket. The other end reads everything already written, then sees the
+    /// connection close, while this end can still read its replies
+    pub fn close_write(&self) {
+        self.outgoing.close();
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self
+            .read_timeout
+            .get()
+            .map(|timeout| Instant::now() + timeout);
+        let mut state = self.incoming.state.lock().unwrap();
+        loop {
+            if !state.bytes.is_empty() {
+                let count = buf.len().min(state.bytes.len());
+                for (dest, byte) in buf.iter_mut().zip(state.bytes.drain(..count)) {
+                    *dest = byte;
+                }
+                return Ok(count);
+            }
+            if state.closed {
+                return Ok(0);
+            }
+            if self.nonblocking.get() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            state = match deadline {
+                None => self.incoming.ready.wait(state).unwrap(),
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(ErrorKind::TimedOut.into());
+                    }
+                    self.incoming
+                        .ready
+                        .wait_timeout(state, remaining)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+    }
+}
+
+impl Write for MemoryStream {
+    /// Never blocks: the other end buffers everything written to it
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        state.bytes.extend(buf);
+        self.outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout.set(timeout);
+        Ok(())
+    }
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        // writes never block
+        Ok(())
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.set(nonblocking);
+        Ok(())
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
@@ -0,0 +1,29 @@
+#![no_main]
+// Runs arbitrary text as a command typed at the bank's prompt by a supervisor,
+// who may run every command
+use bank::{
+    audit::AuditLog,
+    cli::Console,
+    operators::{Operator, Role},
+    test_support::dollars,
+    Bank,
+};
+use libfuzzer_sys::fuzz_target;
+use std::sync::Arc;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    let bank = Arc::new(Bank::new());
+    bank.create_account("amy", 1234, dollars(100.0)).unwrap();
+    bank.create_account("bob", 42, dollars(0.0)).unwrap();
+    let console = Console::new(bank, Arc::new(AuditLog::disabled()), dollars(1_000.0));
+    let operator = Operator {
+        name: "sam".to_string(),
+        role: Role::Supervisor,
+    };
+
+    // the prompt trims each line it reads before processing it
+    console.process_input(&operator, input.trim(), &mut String::new());
+});
@@ -0,0 +1,73 @@
+#![no_main]
+// Feeds arbitrary bytes to the bank as everything an ATM sends on one
+// connection, and runs the bank's handling of it to completion. The bytes
+// are sealed on a real channel, so they reach the bank's message decoding
+use bank::{
+    audit::AuditLog,
+    server::{self, SessionTimeouts},
+    test_support::dollars,
+    Bank,
+};
+use common::{
+    crypto::{AtmCredentials, AtmKeys, ClientHandshake, SERVER_HELLO_SIZE},
+    io::{StreamManager, Timeouts},
+    message::{constants::MAX_PLAINTEXT_SIZE, Frame},
+    transport::memory_pair,
+};
+use libfuzzer_sys::fuzz_target;
+use std::{
+    io::{Read, Write},
+    sync::{atomic::AtomicBool, Arc},
+    thread,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let bank = Arc::new(Bank::new());
+    bank.create_account("amy", 1234, dollars(100.0)).unwrap();
+    bank.create_account("bob", 42, dollars(0.0)).unwrap();
+
+    let (mut atm, bank_end) = memory_pair();
+    let serving = thread::spawn(move || {
+        let manager = StreamManager::accept(
+            Box::new(bank_end),
+            Timeouts::default(),
+            &AtmKeys::default(),
+            None,
+        )
+        .unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        server::handle_remote_connection(
+            bank,
+            Arc::new(AuditLog::disabled()),
+            server::atm(manager.atm_id(), &"fuzzer"),
+            manager,
+            shutdown,
+            SessionTimeouts::default(),
+        );
+    });
+
+    // open the channel by hand, so the input can be sealed frame by frame
+    let mut hello = [0u8; SERVER_HELLO_SIZE];
+    atm.read_exact(&mut hello).unwrap();
+    let (handshake, reply) = ClientHandshake::start(&hello, &AtmCredentials::default()).unwrap();
+    atm.write_all(&reply).unwrap();
+    let mut confirmation = vec![0u8; handshake.reply_size()];
+    atm.read_exact(&mut confirmation).unwrap();
+    let channel = handshake.finish(&confirmation).unwrap();
+
+    // the input is followed by the end of the stream, so the bank never waits
+    // on a timeout. It may close the connection before reading all of it
+    for chunk in data.chunks(MAX_PLAINTEXT_SIZE) {
+        let mut frame: Frame = [0u8; MAX_PLAINTEXT_SIZE];
+        frame[..chunk.len()].copy_from_slice(chunk);
+        if atm.write_all(&channel.seal(&frame)).is_err() {
+            break;
+        }
+    }
+    atm.close_write();
+
+    // the bank's replies are read back like an ATM would
+    let mut replies = Vec::new();
+    let _ = atm.read_to_end(&mut replies);
+    serving.join().unwrap();
+});
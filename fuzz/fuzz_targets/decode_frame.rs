@@ -0,0 +1,19 @@
+#![no_main]
+// Decodes arbitrary bytes as a received frame, then checks the decoded
+// message survives being encoded and decoded again
+use common::message::{constants::*, Frame, Message};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // frames are always read whole, so short input is padded as the rest of one
+    let mut buf: Frame = [0u8; MAX_PLAINTEXT_SIZE];
+    let len = data.len().min(MAX_PLAINTEXT_SIZE);
+    buf[..len].copy_from_slice(&data[..len]);
+
+    let Ok(message) = Message::decode(&buf) else {
+        return;
+    };
+    let encoded = message.encode(Message::comm_count(&buf));
+    let decoded = Message::decode(&encoded).expect("Error decoding an encoded message");
+    assert_eq!(decoded.msg_type(), message.msg_type());
+});
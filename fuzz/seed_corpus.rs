@@ -0,0 +1,152 @@
+// Writes a seed corpus of valid messages and commands for each fuzz target
+// into `corpus/<target>/`, so fuzzing starts from inputs which get past the
+// parsers' first checks
+use common::message::{
+    Amount, AuthRequest, AuthResult, BalanceRequest, BalanceResponse, DepositRequest, EndReason,
+    EndSession, Message, Pin, TransactionResult, TransactionStatus, TransferRequest, Username,
+    WithdrawRequest,
+};
+use std::{fs, path::Path};
+
+fn main() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+
+    write_seeds(&corpus.join("decode_frame"), &frames());
+    write_seeds(&corpus.join("bank_request"), &sessions());
+    write_seeds(&corpus.join("bank_cli"), &commands());
+}
+
+/// Writes each seed to its own file in the given directory
+fn write_seeds(dir: &Path, seeds: &[Vec<u8>]) {
+    fs::create_dir_all(dir).expect("Error creating corpus directory");
+    for (i, seed) in seeds.iter().enumerate() {
+        fs::write(dir.join(format!("seed-{i:03}")), seed).expect("Error writing seed");
+    }
+    println!("Wrote {} seeds to {}", seeds.len(), dir.display());
+}
+
+/// Builds one frame of every kind either side sends
+fn frames() -> Vec<Vec<u8>> {
+    let messages: Vec<Message> = vec![
+        login("amy", 1234).into(),
+        balance("amy").into(),
+        WithdrawRequest {
+            amount: dollars(20.0),
+        }
+        .into(),
+        DepositRequest {
+            amount: dollars(0.5),
+        }
+        .into(),
+        TransferRequest {
+            recipient: username("bob"),
+            amount: dollars(12.25),
+        }
+        .into(),
+        end().into(),
+        AuthResult {
+            authenticated: true,
+        }
+        .into(),
+        BalanceResponse {
+            balance: dollars(80.0),
+        }
+        .into(),
+        TransactionResult {
+            status: TransactionStatus::InsufficientFunds,
+            balance: dollars(5.0),
+        }
+        .into(),
+        EndSession {
+            reason: EndReason::Idle,
+        }
+        .into(),
+    ];
+    messages
+        .iter()
+        .map(|message| message.encode(0).to_vec())
+        .collect()
+}
+
+/// Builds whole ATM sessions, each a run of requests with counters in sequence
+fn sessions() -> Vec<Vec<u8>> {
+    let session = |requests: Vec<Message>| {
+        // the bank's replies take every other counter value
+        requests
+            .iter()
+            .enumerate()
+            .flat_map(|(i, request)| request.encode((i * 2) as u32))
+            .collect()
+    };
+    vec![
+        session(vec![login("amy", 1234).into()]),
+        session(vec![login("amy", 9999).into()]),
+        session(vec![login("amy", 1234).into(), balance("amy").into()]),
+        session(vec![
+            login("amy", 1234).into(),
+            WithdrawRequest {
+                amount: dollars(30.0),
+            }
+            .into(),
+            DepositRequest {
+                amount: dollars(10.5),
+            }
+            .into(),
+            balance("amy").into(),
+        ]),
+        session(vec![
+            login("amy", 1234).into(),
+            TransferRequest {
+                recipient: username("bob"),
+                amount: dollars(25.0),
+            }
+            .into(),
+            end().into(),
+            login("bob", 42).into(),
+            balance("bob").into(),
+        ]),
+        session(vec![WithdrawRequest {
+            amount: dollars(1.0),
+        }
+        .into()]),
+    ]
+}
+
+fn dollars(dollars: f64) -> Amount {
+    Amount::from_dollars(dollars).expect("Error building seed amount")
+}
+fn username(name: &str) -> Username {
+    Username::new(name).expect("Error building seed username")
+}
+fn login(name: &str, pin: u16) -> AuthRequest {
+    AuthRequest {
+        username: username(name),
+        pin: Pin::new(pin).expect("Error building seed PIN"),
+    }
+}
+fn balance(name: &str) -> BalanceRequest {
+    BalanceRequest {
+        username: username(name),
+    }
+}
+fn end() -> EndSession {
+    EndSession {
+        reason: EndReason::Requested,
+    }
+}
+
+/// Builds commands as typed at the bank's prompt
+fn commands() -> Vec<Vec<u8>> {
+    [
+        "create-user carl 1111 50.25",
+        "deposit amy 10.50",
+        "balance amy",
+        "transfer amy bob 20",
+        "users",
+        "help",
+        "exit",
+    ]
+    .iter()
+    .map(|command| command.as_bytes().to_vec())
+    .collect()
+}
@@ -0,0 +1,67 @@
+use clap::Parser;
+use common::{
+    config::{errors::ConfigError, resolve_endpoint},
+    io::BANK_SERVER_ADDR,
+    transport::Endpoint,
+};
+use mitm::rules::{errors::RuleError, RuleSet};
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Default address the proxy listens on for ATMs
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:32002";
+
+/// Command line flags
+#[derive(Debug, Parser)]
+#[command(
+    name = "mitm",
+    about = "Proxy which attacks the traffic between ATMs and the bank"
+)]
+struct Args {
+    /// Address to listen on for ATMs: `host:port` or `unix:<socket-path>`
+    #[arg(long, default_value = DEFAULT_LISTEN_ADDR)]
+    listen: String,
+    /// Address of the bank to relay to: `host:port` or `unix:<socket-path>`
+    #[arg(long, default_value = BANK_SERVER_ADDR)]
+    bank: String,
+    /// Path to a TOML rule file. Without one, frames are only logged
+    #[arg(short, long)]
+    rules: Option<PathBuf>,
+}
+
+/// Validated proxy settings
+#[derive(Debug)]
+pub struct MitmConfig {
+    /// Address to listen on for ATMs
+    pub listen: Endpoint,
+    /// Address of the bank to relay to
+    pub bank: Endpoint,
+    /// Attacks to carry out on each connection
+    pub rules: RuleSet,
+}
+
+/// Errors building the proxy's settings
+#[derive(Debug, Error)]
+pub enum MitmConfigError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Rules(#[from] RuleError),
+}
+
+impl MitmConfig {
+    /// Builds the proxy's settings from command line flags and the rule file
+    /// they point to
+    pub fn from_args() -> Result<Self, MitmConfigError> {
+        let args = Args::parse();
+        let rules = match &args.rules {
+            None => RuleSet::default(),
+            Some(path) => RuleSet::load(path)?,
+        };
+        Ok(Self {
+            listen: resolve_endpoint("listen", &args.listen)?,
+            bank: resolve_endpoint("bank", &args.bank)?,
+            rules,
+        })
+    }
+}
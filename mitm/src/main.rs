@@ -0,0 +1,40 @@
+mod config;
+use crate::config::MitmConfig;
+use common::transport::Listener;
+use mitm::proxy;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::sync::{atomic::AtomicBool, Arc};
+
+/// Proxy entrypoint
+fn main() {
+    let config = MitmConfig::from_args().unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        std::process::exit(2);
+    });
+
+    // set by a termination signal
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, shutdown.clone())
+            .expect("Error registering signal handler");
+    }
+
+    let listener = Listener::bind(&config.listen).unwrap_or_else(|e| {
+        eprintln!("Error: proxy could not bind to {}: {e}", config.listen);
+        std::process::exit(1);
+    });
+    if config.rules.is_empty() {
+        println!("No rules given: frames will only be logged");
+    }
+    println!(
+        "Relaying ATMs connecting to {} to the bank at {}",
+        config.listen, config.bank
+    );
+    proxy::serve(
+        listener,
+        config.bank,
+        Arc::new(config.rules),
+        None,
+        shutdown,
+    );
+}
@@ -0,0 +1,475 @@
+use crate::rules::{Action, Delivery, Direction, RuleCounter, RuleSet};
+use common::{
+    crypto::{
+        Protocol, NOISE_CLIENT_HELLO_SIZE, PROTOCOL_CHOICE_SIZE, SEALED_FRAME_SIZE,
+        SERVER_HELLO_SIZE,
+    },
+    hex::to_hex,
+    transport::{Endpoint, Listener, PeerAddr, Transport},
+};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often the proxy checks each side of a connection for frames to relay
+const RELAY_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// How often the accept loop checks whether a shutdown has been requested
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// One side of a proxied connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Atm,
+    Bank,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Atm => write!(f, "atm"),
+            Self::Bank => write!(f, "bank"),
+        }
+    }
+}
+
+/// How the side an attacked frame was sent to responded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reaction {
+    /// It sent a frame
+    Replied,
+    /// It closed the connection
+    Closed,
+}
+
+impl fmt::Display for Reaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Replied => write!(f, "replied"),
+            Self::Closed => write!(f, "closed the connection"),
+        }
+    }
+}
+
+/// A frame the proxy tampered with and what came of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attack {
+    pub direction: Direction,
+    /// Position of the frame among those travelling the same way, counting from 1
+    pub frame: usize,
+    pub actions: Vec<Action>,
+    /// What the receiving side did after the frame was delivered, until
+    /// another frame was delivered to it
+    pub reactions: Vec<Reaction>,
+}
+
+/// Everything which happened on one proxied connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionReport {
+    /// Number of the connection, counting from 1
+    pub id: usize,
+    /// Frames tampered with, in the order they arrived
+    pub attacks: Vec<Attack>,
+    /// Side which closed the connection, or `None` if the proxy shut down first
+    pub closed_by: Option<Side>,
+}
+
+/// Accepts ATM connections and relays each to the bank through the rules,
+/// until a shutdown is requested. Every frame is logged, and a report is
+/// printed and sent to `reports` as each connection ends
+pub fn serve(
+    listener: Listener,
+    bank: Endpoint,
+    rules: Arc<RuleSet>,
+    reports: Option<Sender<ConnectionReport>>,
+    shutdown: Arc<AtomicBool>,
+) {
+    // poll for connections so shutdown requests are noticed
+    listener
+        .set_nonblocking(true)
+        .expect("Error: could not set listener to non-blocking");
+
+    let mut connections = 0;
+    while !shutdown.load(Ordering::SeqCst) {
+        let (atm, peer) = match listener.accept() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Error getting stream from listener: {}", e);
+                continue;
+            }
+            Ok(accepted) => accepted,
+        };
+        connections += 1;
+        let id = connections;
+        let bank = bank.clone();
+        let rules = rules.clone();
+        let reports = reports.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let Some(report) = proxy_connection(id, atm, peer, &bank, &rules, &shutdown) else {
+                return;
+            };
+            if let Some(reports) = reports {
+                // the receiver only goes away when nobody is interested any more
+                let _ = reports.send(report);
+            }
+        });
+    }
+}
+
+/// Connects an accepted ATM to the bank and relays frames between them until
+/// one side closes the connection. Returns `None` if the bank cannot be reached
+fn proxy_connection(
+    id: usize,
+    atm: Box<dyn Transport>,
+    peer: PeerAddr,
+    bank: &Endpoint,
+    rules: &RuleSet,
+    shutdown: &AtomicBool,
+) -> Option<ConnectionReport> {
+    let bank = match bank.connect() {
+        Err(e) => {
+            eprintln!("[conn {id}] Error: could not reach the bank at {bank}: {e}");
+            return None;
+        }
+        Ok(stream) => stream,
+    };
+    let setup = |stream: &dyn Transport| stream.set_read_timeout(Some(RELAY_POLL_INTERVAL));
+    if let Err(e) = setup(atm.as_ref()).and_then(|()| setup(bank.as_ref())) {
+        eprintln!("[conn {id}] Error setting up connection: {e}");
+        return None;
+    }
+    println!("[conn {id}] ATM connected from {peer}");
+
+    let mut connection = Connection {
+        id,
+        atm: Peer::new(Side::Atm, atm),
+        bank: Peer::new(Side::Bank, bank),
+        counter: RuleCounter::new(rules),
+        attacks: Vec::new(),
+        watching: Vec::new(),
+    };
+    let closed_by = connection.relay(shutdown);
+    let report = connection.finish(closed_by);
+    print_report(&report);
+    Some(report)
+}
+
+/// Prints a summary of a finished connection
+fn print_report(report: &ConnectionReport) {
+    let id = report.id;
+    match report.closed_by {
+        None => println!("[conn {id}] Proxy shut down"),
+        Some(side) => println!("[conn {id}] Closed by the {side}"),
+    }
+    for attack in &report.attacks {
+        let actions: Vec<String> = attack.actions.iter().map(Action::to_string).collect();
+        let receiver = receiver(attack.direction);
+        let reactions = match attack.reactions.as_slice() {
+            [] => "did nothing".to_string(),
+            reactions => {
+                let reactions: Vec<String> = reactions.iter().map(Reaction::to_string).collect();
+                reactions.join(", ")
+            }
+        };
+        println!(
+            "[conn {id}]   {} #{}: {}, then the {receiver} {reactions}",
+            attack.direction,
+            attack.frame,
+            actions.join(", "),
+        );
+    }
+}
+
+/// A frame waiting to be delivered
+struct Outgoing {
+    /// When the frame may be delivered
+    due: Instant,
+    bytes: Vec<u8>,
+    /// Index of the attack on the frame, if it was tampered with
+    attack: Option<usize>,
+}
+
+/// Something read from one side of a connection
+enum Incoming {
+    /// A handshake message, relayed untouched
+    Handshake(Vec<u8>),
+    /// A sealed frame
+    Frame(Vec<u8>),
+}
+
+/// One side of a proxied connection, with the frames waiting to be delivered to it
+struct Peer {
+    side: Side,
+    stream: Box<dyn Transport>,
+    /// Sizes of the handshake messages this side has still to send, in order.
+    /// The rest are known once the ATM has chosen a protocol. Everything
+    /// after them is sealed frames
+    handshake: VecDeque<usize>,
+    /// Bytes read so far of the next message from this side
+    partial: Vec<u8>,
+    /// Number of frames read from this side
+    frames: usize,
+    /// Frames to deliver to this side, in order
+    outgoing: VecDeque<Outgoing>,
+    /// Frames held back until the next frame to this side is queued
+    held: Vec<Outgoing>,
+}
+
+impl Peer {
+    fn new(side: Side, stream: Box<dyn Transport>) -> Self {
+        let handshake = match side {
+            Side::Atm => vec![PROTOCOL_CHOICE_SIZE],
+            Side::Bank => vec![SERVER_HELLO_SIZE],
+        };
+        Self {
+            side,
+            stream,
+            handshake: handshake.into(),
+            partial: Vec::with_capacity(SEALED_FRAME_SIZE),
+            frames: 0,
+            outgoing: VecDeque::new(),
+            held: Vec::new(),
+        }
+    }
+
+    /// Reads whatever this side has sent. Returns a message once a whole one
+    /// has arrived, or an error once the side has closed the connection
+    fn read(&mut self) -> io::Result<Option<Incoming>> {
+        let size = self.handshake.front().copied().unwrap_or(SEALED_FRAME_SIZE);
+        // handshake messages may be longer than a frame
+        let mut buf = [0u8; NOISE_CLIENT_HELLO_SIZE];
+        let wanted = size - self.partial.len();
+        match self.stream.read(&mut buf[..wanted]) {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                self.partial.extend_from_slice(&buf[..read]);
+                if self.partial.len() < size {
+                    return Ok(None);
+                }
+                let message = self.partial.split_off(0);
+                if self.handshake.pop_front().is_some() {
+                    return Ok(Some(Incoming::Handshake(message)));
+                }
+                self.frames += 1;
+                Ok(Some(Incoming::Frame(message)))
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Queues a frame for delivery to this side
+    fn queue(&mut self, delivery: Delivery, attack: Option<usize>) {
+        let due = Instant::now() + delivery.delay;
+        let copies = (0..delivery.copies).map(|_| Outgoing {
+            due,
+            bytes: delivery.bytes.clone(),
+            attack,
+        });
+        if delivery.reorder {
+            self.held.extend(copies);
+            return;
+        }
+        let copies: Vec<Outgoing> = copies.collect();
+        for mut frame in copies.into_iter().chain(self.held.drain(..)) {
+            // held frames go out right behind the frame which released them
+            frame.due = due;
+            self.outgoing.push_back(frame);
+        }
+    }
+
+    /// Delivers the frames which are due. A delayed frame holds back every
+    /// frame queued behind it. Returns the attack on each frame delivered
+    fn flush(&mut self) -> io::Result<Vec<Option<usize>>> {
+        let now = Instant::now();
+        let mut delivered = Vec::new();
+        while let Some(frame) = self.outgoing.front() {
+            if frame.due > now {
+                break;
+            }
+            let frame = self.outgoing.pop_front().unwrap();
+            self.stream.write_all(&frame.bytes)?;
+            delivered.push(frame.attack);
+        }
+        Ok(delivered)
+    }
+
+    /// Delivers every queued frame without waiting for it to be due, for when
+    /// the other side has gone
+    fn flush_all(&mut self) {
+        for frame in self.outgoing.drain(..) {
+            if self.stream.write_all(&frame.bytes).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// State of one proxied connection
+struct Connection<'a> {
+    id: usize,
+    atm: Peer,
+    bank: Peer,
+    counter: RuleCounter<'a>,
+    attacks: Vec<Attack>,
+    /// Attacks whose frame was the last delivered to its side. What that side
+    /// does next is taken as its reaction
+    watching: Vec<usize>,
+}
+
+impl Connection<'_> {
+    /// Relays frames both ways until a side closes the connection. Returns that
+    /// side, or `None` if a shutdown was requested first
+    fn relay(&mut self, shutdown: &AtomicBool) -> Option<Side> {
+        while !shutdown.load(Ordering::SeqCst) {
+            for direction in [Direction::ToBank, Direction::ToAtm] {
+                let (from, _) = self.peers(direction);
+                let side = from.side;
+                match from.read() {
+                    Err(_) => return Some(self.closed(side)),
+                    Ok(None) => (),
+                    Ok(Some(Incoming::Handshake(message))) => {
+                        self.relay_handshake(direction, message)
+                    }
+                    Ok(Some(Incoming::Frame(frame))) => self.forward(direction, frame),
+                }
+            }
+            for direction in [Direction::ToBank, Direction::ToAtm] {
+                let (_, to) = self.peers(direction);
+                let side = to.side;
+                match to.flush() {
+                    Err(_) => return Some(self.closed(side)),
+                    Ok(delivered) => self.delivered(side, delivered),
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the sides a frame travelling the given way comes from and goes to
+    fn peers(&mut self, direction: Direction) -> (&mut Peer, &mut Peer) {
+        match direction {
+            Direction::ToBank => (&mut self.atm, &mut self.bank),
+            Direction::ToAtm => (&mut self.bank, &mut self.atm),
+        }
+    }
+
+    /// Queues a handshake message for the other side. The handshake is never
+    /// attacked: the rules only apply to the frames which follow it
+    fn relay_handshake(&mut self, direction: Direction, message: Vec<u8>) {
+        println!(
+            "[conn {}] {direction} handshake ({} bytes)",
+            self.id,
+            message.len()
+        );
+        if direction == Direction::ToBank && message.len() == PROTOCOL_CHOICE_SIZE {
+            // an unknown choice is left to the bank to refuse
+            if let Some(protocol) = Protocol::from_id(message[0]) {
+                println!("[conn {}]   chose {protocol:?}", self.id);
+                self.atm.handshake.push_back(protocol.client_hello_size());
+                self.bank.handshake.push_back(protocol.server_reply_size());
+            }
+        }
+        let (_, to) = self.peers(direction);
+        to.queue(Delivery::new(&message, &[]), None);
+    }
+
+    /// Carries out the rules on a frame and queues it for the other side
+    fn forward(&mut self, direction: Direction, frame: Vec<u8>) {
+        let id = self.id;
+        let actions = self.counter.actions(direction);
+        let attack = actions
+            .iter()
+            .any(|action| *action != Action::Log)
+            .then_some(self.attacks.len());
+        let (from, to) = self.peers(direction);
+        let (side, number) = (from.side, from.frames);
+        println!("[conn {id}] {direction} #{number}");
+        for action in &actions {
+            match action {
+                Action::Log => println!("[conn {id}]   bytes: {}", spaced_hex(&frame)),
+                action => println!("[conn {id}]   {action}"),
+            }
+        }
+        to.queue(Delivery::new(&frame, &actions), attack);
+
+        self.react(side, Reaction::Replied);
+        if attack.is_some() {
+            self.attacks.push(Attack {
+                direction,
+                frame: number,
+                actions,
+                reactions: Vec::new(),
+            });
+        }
+    }
+
+    /// Starts watching for reactions to the attacked frames just delivered to a
+    /// side, and stops watching for reactions to earlier ones
+    fn delivered(&mut self, side: Side, delivered: Vec<Option<usize>>) {
+        for attack in delivered {
+            let attacks = &self.attacks;
+            self.watching
+                .retain(|&i| receiver(attacks[i].direction) != side || Some(i) == attack);
+            if let Some(i) = attack.filter(|i| !self.watching.contains(i)) {
+                self.watching.push(i);
+            }
+        }
+    }
+
+    /// Records something a side did as a reaction to the attacks on frames
+    /// last delivered to it
+    fn react(&mut self, side: Side, reaction: Reaction) {
+        for &i in &self.watching {
+            let attack = &mut self.attacks[i];
+            if receiver(attack.direction) == side {
+                attack.reactions.push(reaction);
+            }
+        }
+    }
+
+    /// Records that a side closed the connection, and passes anything still
+    /// queued on to the other side
+    fn closed(&mut self, side: Side) -> Side {
+        self.react(side, Reaction::Closed);
+        match side {
+            Side::Atm => self.bank.flush_all(),
+            Side::Bank => self.atm.flush_all(),
+        }
+        side
+    }
+
+    fn finish(self, closed_by: Option<Side>) -> ConnectionReport {
+        ConnectionReport {
+            id: self.id,
+            attacks: self.attacks,
+            closed_by,
+        }
+    }
+}
+
+/// Returns the side frames travelling the given way are delivered to
+fn receiver(direction: Direction) -> Side {
+    match direction {
+        Direction::ToBank => Side::Bank,
+        Direction::ToAtm => Side::Atm,
+    }
+}
+
+/// Formats bytes as space separated hex
+fn spaced_hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.chunks(1).map(to_hex).collect();
+    hex.join(" ")
+}
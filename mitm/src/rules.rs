@@ -0,0 +1,274 @@
+use self::errors::RuleError;
+use common::{config::load_toml, crypto::SEALED_FRAME_SIZE};
+use serde::Deserialize;
+use std::{fmt, path::Path, time::Duration};
+
+/// Which way a frame is travelling through the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToBank,
+    ToAtm,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ToBank => write!(f, "atm -> bank"),
+            Self::ToAtm => write!(f, "bank -> atm"),
+        }
+    }
+}
+
+/// Directions a rule applies to, as written in a rule file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Directions {
+    ToBank,
+    ToAtm,
+    Both,
+}
+
+impl Directions {
+    /// Checks whether frames travelling the given way are covered
+    fn contains(&self, direction: Direction) -> bool {
+        match self {
+            Self::ToBank => direction == Direction::ToBank,
+            Self::ToAtm => direction == Direction::ToAtm,
+            Self::Both => true,
+        }
+    }
+}
+
+/// What to do to a frame a rule matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Action {
+    /// Print the frame's bytes
+    Log,
+    /// Never deliver the frame
+    Drop,
+    /// Deliver the frame, then deliver it again `times` more times
+    Replay {
+        #[serde(default = "default_replays")]
+        times: usize,
+    },
+    /// Hold the frame back until the next frame travelling the same way has
+    /// been delivered
+    Reorder,
+    /// Hold the frame, and every frame behind it, for a while before delivering it
+    Delay { millis: u64 },
+    /// Deliver only the first `length` bytes of the frame
+    Truncate { length: usize },
+    /// Invert one bit of the frame
+    Flip { byte: usize, bit: u8 },
+}
+
+fn default_replays() -> usize {
+    1
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Log => write!(f, "logged"),
+            Self::Drop => write!(f, "dropped"),
+            Self::Replay { times } => write!(f, "replayed {times} time(s)"),
+            Self::Reorder => write!(f, "held back behind the next frame"),
+            Self::Delay { millis } => write!(f, "delayed {millis}ms"),
+            Self::Truncate { length } => write!(f, "truncated to {length} bytes"),
+            Self::Flip { byte, bit } => write!(f, "flipped bit {bit} of byte {byte}"),
+        }
+    }
+}
+
+/// An attack on the frames which match it. Frames are encrypted, so they can
+/// only be told apart by the way they travel and their position
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    /// Directions the rule applies to
+    pub directions: Directions,
+    /// Which matching frame on a connection to attack, counting from 1.
+    /// Every matching frame if `None`
+    pub nth: Option<usize>,
+    pub action: Action,
+}
+
+/// Layout of a rule file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    rule: Vec<FileRule>,
+}
+
+/// A `[[rule]]` entry of a rule file
+#[derive(Debug, Deserialize)]
+struct FileRule {
+    #[serde(default = "default_directions")]
+    direction: Directions,
+    /// No longer supported, but rejected with an explanation rather than ignored
+    message: Option<String>,
+    nth: Option<usize>,
+    #[serde(flatten)]
+    action: Action,
+}
+
+fn default_directions() -> Directions {
+    Directions::Both
+}
+
+/// The rules applied to every proxied connection, in the order they are written
+#[derive(Debug, Clone, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Builds a rule set, rejecting rules which could never be carried out
+    pub fn new(rules: Vec<Rule>) -> Result<Self, RuleError> {
+        for (number, rule) in rules.iter().enumerate() {
+            let invalid = |reason: &str| RuleError::Invalid {
+                number: number + 1,
+                reason: reason.to_string(),
+            };
+            match rule.action {
+                Action::Truncate { length } if length >= SEALED_FRAME_SIZE => {
+                    return Err(invalid("truncated length must be shorter than a frame"))
+                }
+                Action::Flip { byte, .. } if byte >= SEALED_FRAME_SIZE => {
+                    return Err(invalid("flipped byte must be within a frame"))
+                }
+                Action::Flip { bit, .. } if bit >= 8 => {
+                    return Err(invalid("flipped bit must be between 0 and 7"))
+                }
+                _ if rule.nth == Some(0) => return Err(invalid("frames are counted from 1")),
+                _ => (),
+            }
+        }
+        Ok(Self { rules })
+    }
+
+    /// Reads a rule set from a TOML rule file
+    pub fn load(path: &Path) -> Result<Self, RuleError> {
+        let file: RuleFile = load_toml(path)?;
+        let rules = file
+            .rule
+            .into_iter()
+            .enumerate()
+            .map(|(number, rule)| {
+                if rule.message.is_some() {
+                    return Err(RuleError::Invalid {
+                        number: number + 1,
+                        reason: "frames are encrypted, so rules cannot pick out message types. Use `nth` instead".to_string(),
+                    });
+                }
+                Ok(Rule {
+                    directions: rule.direction,
+                    nth: rule.nth,
+                    action: rule.action,
+                })
+            })
+            .collect::<Result<_, RuleError>>()?;
+        Self::new(rules)
+    }
+
+    /// Returns the rules in order
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+    /// Checks whether there are no rules, in which case frames are only logged
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+/// Counts the frames each rule has matched on one connection
+#[derive(Debug)]
+pub struct RuleCounter<'a> {
+    rules: &'a RuleSet,
+    matched: Vec<usize>,
+}
+
+impl<'a> RuleCounter<'a> {
+    pub fn new(rules: &'a RuleSet) -> Self {
+        Self {
+            rules,
+            matched: vec![0; rules.rules.len()],
+        }
+    }
+
+    /// Returns the actions to carry out on the next frame travelling the
+    /// given way, in rule order
+    pub fn actions(&mut self, direction: Direction) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for (rule, matched) in self.rules.rules.iter().zip(self.matched.iter_mut()) {
+            if !rule.directions.contains(direction) {
+                continue;
+            }
+            *matched += 1;
+            if rule.nth.is_none_or(|nth| nth == *matched) {
+                actions.push(rule.action);
+            }
+        }
+        actions
+    }
+}
+
+/// How a frame is delivered once its rules have been carried out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    /// Bytes to deliver, which may have been tampered with
+    pub bytes: Vec<u8>,
+    /// Number of times to deliver the bytes. Zero if the frame was dropped
+    pub copies: usize,
+    /// How long to wait before delivering
+    pub delay: Duration,
+    /// Whether to deliver after the next frame travelling the same way
+    pub reorder: bool,
+}
+
+impl Delivery {
+    /// Carries out the given actions on a frame, in order
+    pub fn new(frame: &[u8], actions: &[Action]) -> Self {
+        let mut delivery = Self {
+            bytes: frame.to_vec(),
+            copies: 1,
+            delay: Duration::ZERO,
+            reorder: false,
+        };
+        for action in actions {
+            match *action {
+                Action::Log => (),
+                Action::Drop => delivery.copies = 0,
+                Action::Replay { times } if delivery.copies > 0 => delivery.copies += times,
+                Action::Replay { .. } => (),
+                Action::Reorder => delivery.reorder = true,
+                Action::Delay { millis } => delivery.delay += Duration::from_millis(millis),
+                Action::Truncate { length } => delivery.bytes.truncate(length),
+                Action::Flip { byte, bit } => {
+                    if let Some(byte) = delivery.bytes.get_mut(byte) {
+                        *byte ^= 1 << bit;
+                    }
+                }
+            }
+        }
+        delivery
+    }
+}
+
+/// Error types related to rule files
+pub mod errors {
+    use common::config::errors::ConfigError;
+    use thiserror::Error;
+
+    /// Errors loading or validating rules
+    #[derive(Debug, Error)]
+    pub enum RuleError {
+        /// Rule file could not be read or parsed
+        #[error(transparent)]
+        File(#[from] ConfigError),
+        /// A rule cannot be carried out
+        #[error("Invalid rule {number}: {reason}")]
+        Invalid { number: usize, reason: String },
+    }
+}
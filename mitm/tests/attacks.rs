@@ -0,0 +1,312 @@
+use bank::{
+    server::{ServerConfig, SessionTimeouts},
+    test_support::{dollars, TestBank},
+};
+use common::{
+    client::{errors::ClientError, BankClient},
+    crypto::{AtmCredentials, BankSecret},
+    io::{errors::ReceiveError, StreamManager, Timeouts},
+    message::{Amount, AuthRequest, BalanceRequest, Pin, Username},
+    transport::{Endpoint, Listener},
+};
+use mitm::{
+    proxy::{self, ConnectionReport, Reaction, Side},
+    rules::{Action, Directions, Rule, RuleSet},
+};
+use std::{
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How long clients wait for the bank, kept short so dropped frames are noticed quickly
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait for the proxy to report on a connection
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A bank holding an account for amy, reached through a proxy carrying out
+/// the given rules
+struct AttackRun {
+    test_bank: TestBank,
+    proxy: Endpoint,
+    reports: Receiver<ConnectionReport>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl AttackRun {
+    fn start(rules: Vec<Rule>) -> Self {
+        Self::start_with(rules, ServerConfig::default())
+    }
+    fn start_with(rules: Vec<Rule>, config: ServerConfig) -> Self {
+        let test_bank = TestBank::start_with(config);
+        test_bank
+            .bank()
+            .create_account("amy", 1234, dollars(100.0))
+            .unwrap();
+        let bank = test_bank.endpoint().unwrap().clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Endpoint::Tcp(listener.local_addr().unwrap());
+        let rules = Arc::new(RuleSet::new(rules).unwrap());
+        let (sender, reports) = mpsc::channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_clone = shutdown.clone();
+        thread::spawn(move || {
+            proxy::serve(
+                Listener::Tcp(listener),
+                bank,
+                rules,
+                Some(sender),
+                shutdown_clone,
+            )
+        });
+        Self {
+            test_bank,
+            proxy,
+            reports,
+            shutdown,
+        }
+    }
+
+    fn timeouts() -> Timeouts {
+        Timeouts {
+            read: Some(CLIENT_TIMEOUT),
+            write: Some(CLIENT_TIMEOUT),
+        }
+    }
+    /// Opens a client connection through the proxy
+    fn client(&self) -> BankClient {
+        self.client_as(&AtmCredentials::default())
+    }
+    /// Opens a client connection through the proxy with the given credentials
+    fn client_as(&self, credentials: &AtmCredentials) -> BankClient {
+        BankClient::connect(&self.proxy, Self::timeouts(), credentials).unwrap()
+    }
+    /// Opens a raw connection through the proxy
+    fn connect(&self) -> StreamManager {
+        let stream = self.proxy.connect().unwrap();
+        StreamManager::open(stream, Self::timeouts(), &AtmCredentials::default()).unwrap()
+    }
+    /// Waits for the proxy's report on the next connection to end
+    fn report(&self) -> ConnectionReport {
+        self.reports.recv_timeout(REPORT_TIMEOUT).unwrap()
+    }
+    fn balance(&self) -> Amount {
+        self.test_bank.bank().balance("amy").unwrap()
+    }
+}
+
+impl Drop for AttackRun {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Position among the frames the ATM sends of the first request after logging in
+const FIRST_REQUEST: usize = 2;
+
+/// A rule attacking one frame the ATM sends
+fn attack_request(nth: usize, action: Action) -> Rule {
+    Rule {
+        directions: Directions::ToBank,
+        nth: Some(nth),
+        action,
+    }
+}
+
+#[test]
+fn relays_sessions_it_does_not_attack() {
+    let run = AttackRun::start(Vec::new());
+    let mut client = run.client();
+
+    assert!(client.authenticate("amy", 1234).unwrap());
+    assert_eq!(client.withdraw(dollars(10.0)).unwrap(), dollars(90.0));
+    drop(client);
+
+    let report = run.report();
+    assert!(report.attacks.is_empty());
+    assert_eq!(report.closed_by, Some(Side::Atm));
+}
+
+#[test]
+fn relays_noise_handshakes() {
+    let noise_key = BankSecret::from([7; 32]);
+    let credentials = AtmCredentials {
+        bank_key: Some(noise_key.public()),
+        ..AtmCredentials::default()
+    };
+    let run = AttackRun::start_with(
+        vec![attack_request(FIRST_REQUEST, Action::Replay { times: 1 })],
+        ServerConfig {
+            noise_key: Some(noise_key),
+            ..ServerConfig::default()
+        },
+    );
+    let mut client = run.client_as(&credentials);
+    client.authenticate("amy", 1234).unwrap();
+
+    // frames are sealed the same way whichever handshake opened the channel
+    assert_eq!(client.withdraw(dollars(10.0)).unwrap(), dollars(90.0));
+    assert!(client.balance().is_err());
+    assert_eq!(run.balance(), dollars(90.0));
+    assert_eq!(run.report().closed_by, Some(Side::Bank));
+}
+
+#[test]
+fn replayed_request_is_refused() {
+    let run = AttackRun::start(vec![attack_request(
+        FIRST_REQUEST,
+        Action::Replay { times: 1 },
+    )]);
+    let mut client = run.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    // the original withdrawal goes through, the replayed copy does not
+    assert_eq!(client.withdraw(dollars(10.0)).unwrap(), dollars(90.0));
+    assert!(client.balance().is_err());
+    assert_eq!(run.balance(), dollars(90.0));
+
+    let report = run.report();
+    assert_eq!(report.closed_by, Some(Side::Bank));
+    assert_eq!(
+        report.attacks[0].reactions,
+        [Reaction::Replied, Reaction::Closed]
+    );
+}
+
+#[test]
+fn reordered_requests_are_refused() {
+    let run = AttackRun::start(vec![attack_request(1, Action::Reorder)]);
+    let mut manager = run.connect();
+    let mut comm_count = 0;
+
+    // send two requests without waiting, so the proxy can swap them
+    let username = Username::new("amy").unwrap();
+    let login = AuthRequest {
+        username: username.clone(),
+        pin: Pin::new(1234).unwrap(),
+    };
+    manager.send_message(login, &mut comm_count).unwrap();
+    manager
+        .send_message(BalanceRequest { username }, &mut comm_count)
+        .unwrap();
+
+    let mut comm_count = 1;
+    assert!(matches!(
+        manager.receive(&mut comm_count),
+        Err(ReceiveError::EndOfStream)
+    ));
+    let report = run.report();
+    assert_eq!(report.closed_by, Some(Side::Bank));
+    assert_eq!(report.attacks[0].reactions, [Reaction::Closed]);
+}
+
+/// Flips a bit of the withdrawal the ATM sends and checks the bank refuses it
+fn assert_tampered_withdrawal_is_refused(byte: usize) {
+    let run = AttackRun::start(vec![attack_request(
+        FIRST_REQUEST,
+        Action::Flip { byte, bit: 0 },
+    )]);
+    let mut client = run.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    assert!(client.withdraw(dollars(10.0)).is_err());
+    assert_eq!(run.balance(), dollars(100.0));
+
+    let report = run.report();
+    assert_eq!(report.closed_by, Some(Side::Bank));
+    assert_eq!(report.attacks[0].reactions, [Reaction::Closed]);
+}
+
+#[test]
+fn tampered_nonce_is_refused() {
+    assert_tampered_withdrawal_is_refused(0);
+}
+
+#[test]
+fn tampered_body_is_refused() {
+    // past the 24 byte nonce. Before frames were sealed, a flipped amount
+    // went unnoticed
+    assert_tampered_withdrawal_is_refused(34);
+}
+
+#[test]
+fn truncated_request_is_never_carried_out() {
+    let run = AttackRun::start_with(
+        vec![attack_request(
+            FIRST_REQUEST,
+            Action::Truncate { length: 10 },
+        )],
+        ServerConfig {
+            timeouts: SessionTimeouts {
+                write: CLIENT_TIMEOUT,
+                ..SessionTimeouts::default()
+            },
+            ..ServerConfig::default()
+        },
+    );
+    let mut client = run.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    // the bank gives up on the rest of the frame once its request timeout is up
+    assert!(client.withdraw(dollars(10.0)).is_err());
+    assert_eq!(run.balance(), dollars(100.0));
+
+    let report = run.report();
+    assert_eq!(report.closed_by, Some(Side::Bank));
+    assert_eq!(report.attacks[0].reactions, [Reaction::Closed]);
+}
+
+#[test]
+fn dropped_reply_times_out_without_harm() {
+    let run = AttackRun::start(vec![Rule {
+        directions: Directions::ToAtm,
+        // the reply to the withdrawal, after the one to logging in
+        nth: Some(2),
+        action: Action::Drop,
+    }]);
+    let mut client = run.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    // the withdrawal happened, but the ATM never hears about it
+    assert!(matches!(
+        client.withdraw(dollars(10.0)),
+        Err(ClientError::Receive(ReceiveError::TimedOut))
+    ));
+    assert_eq!(run.balance(), dollars(90.0));
+    drop(client);
+
+    let report = run.report();
+    assert_eq!(report.closed_by, Some(Side::Atm));
+    assert!(report.attacks[0].reactions.is_empty());
+}
+
+#[test]
+fn delayed_request_is_served_late() {
+    let run = AttackRun::start(vec![attack_request(
+        FIRST_REQUEST,
+        Action::Delay { millis: 200 },
+    )]);
+    let mut client = run.client();
+    client.authenticate("amy", 1234).unwrap();
+
+    assert_eq!(client.balance().unwrap(), dollars(100.0));
+    drop(client);
+
+    let report = run.report();
+    assert_eq!(report.attacks[0].reactions, [Reaction::Replied]);
+}
+
+#[test]
+fn example_rule_files_load() {
+    let examples = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("rules");
+    for name in ["replay.toml", "tamper.toml", "network.toml"] {
+        let rules = RuleSet::load(&examples.join(name)).unwrap();
+        assert!(!rules.is_empty(), "{name} has no rules");
+    }
+}